@@ -0,0 +1,367 @@
+use crate::auth::Identity;
+use crate::commands::Action;
+use crate::processing::SibylPID;
+use anyhow::{Context, Result};
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+
+/// one request the daemon received, as recorded in the audit log
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AuditRecord {
+    pub time: DateTime<Utc>,
+    pub client: AuditClient,
+    /// the action's type name, e.g. `CmdOnce`
+    pub action: String,
+    /// the action's fields
+    pub args: Value,
+    pub outcome: Outcome,
+    /// why the request failed or was denied
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// the process the request started or targeted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub spid: Option<SibylPID>,
+    /// the definition name or program of that process, while the daemon still knew it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub process: Option<String>,
+}
+
+/// the client that sent a request, as far as the daemon could tell
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AuditClient {
+    pub peer: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub uid: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    /// the name of the token the client authenticated with
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+}
+
+/// what became of a request
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    Executed,
+    Failed,
+    Denied,
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Outcome::Executed => write!(f, "executed"),
+            Outcome::Failed => write!(f, "failed"),
+            Outcome::Denied => write!(f, "denied"),
+        }
+    }
+}
+
+impl AuditRecord {
+    /// describes a request that was just handled
+    /// # Arguments
+    /// * `identity` - who sent the request
+    /// * `action` - the request's command
+    /// * `outcome` - what became of it
+    pub fn new(identity: &Identity, action: &dyn Action, outcome: Outcome) -> AuditRecord {
+        let args = action_args(action);
+        // commands aimed at an existing process name it in their `pid` field
+        let spid = args
+            .get("pid")
+            .and_then(Value::as_u64)
+            .map(|pid| pid as SibylPID);
+
+        AuditRecord {
+            time: Utc::now(),
+            client: AuditClient {
+                peer: identity.peer.to_string(),
+                uid: identity.uid,
+                user: identity.user.clone(),
+                token: identity.token.clone(),
+            },
+            action: action.typetag_name().to_string(),
+            args,
+            outcome,
+            error: None,
+            spid,
+            process: None,
+        }
+    }
+}
+
+impl fmt::Display for AuditRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} ",
+            self.time.to_rfc3339_opts(SecondsFormat::Secs, true)
+        )?;
+        match (&self.client.user, self.client.uid) {
+            (Some(user), Some(uid)) => write!(f, "{} (uid {})", user, uid)?,
+            (None, Some(uid)) => write!(f, "uid {}", uid)?,
+            _ => write!(f, "unknown user")?,
+        }
+        if let Some(token) = &self.client.token {
+            write!(f, " with token '{}'", token)?;
+        }
+        write!(f, " from {}: {}", self.client.peer, self.action)?;
+        if self.args.as_object().is_some_and(|args| !args.is_empty()) {
+            write!(f, " {}", self.args)?;
+        }
+        write!(f, " -> {}", self.outcome)?;
+        if let Some(spid) = self.spid {
+            write!(f, ", spid {}", spid)?;
+            if let Some(process) = &self.process {
+                write!(f, " ({})", process)?;
+            }
+        }
+        if let Some(error) = &self.error {
+            write!(f, ": {}", error)?;
+        }
+        Ok(())
+    }
+}
+
+/// selects records from the audit log
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct AuditFilter {
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+    /// a sibyl pid, or the definition name or program of the process
+    pub process: Option<String>,
+}
+
+impl AuditFilter {
+    pub fn matches(&self, record: &AuditRecord) -> bool {
+        if self.since.is_some_and(|since| record.time < since)
+            || self.until.is_some_and(|until| record.time > until)
+        {
+            return false;
+        }
+        match &self.process {
+            Some(process) => match process.parse::<SibylPID>() {
+                Ok(spid) => record.spid == Some(spid),
+                Err(_) => record.process.as_deref() == Some(process.as_str()),
+            },
+            None => true,
+        }
+    }
+}
+
+/// appends a record to the audit log as one line of JSON
+/// # Arguments
+/// * `path` - the audit log, created if it doesn't exist yet
+/// * `record` - the request to record
+pub fn record(path: &Path, record: &AuditRecord) -> Result<()> {
+    let mut line = serde_json::to_string(record).context("failed to encode audit record")?;
+    line.push('\n');
+    append(path, &line)
+}
+
+/// reads the records from the audit log that match a filter, oldest first
+///
+/// returns the records along with the number of lines that could not be read
+/// # Arguments
+/// * `path` - the audit log, which is treated as empty if it doesn't exist yet
+/// * `filter` - which records to return
+pub fn read(path: &Path, filter: &AuditFilter) -> Result<(Vec<AuditRecord>, usize)> {
+    let file = match fs::File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok((Vec::new(), 0)),
+        Err(e) => {
+            return Err(e).with_context(|| format!("failed to open audit log {}", path.display()))
+        }
+    };
+
+    let mut records = Vec::new();
+    let mut unreadable = 0;
+    for line in BufReader::new(file).lines() {
+        let line = line.with_context(|| format!("failed to read audit log {}", path.display()))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str::<AuditRecord>(&line) {
+            Ok(record) if filter.matches(&record) => records.push(record),
+            Ok(_) => {}
+            Err(_) => unreadable += 1,
+        }
+    }
+    Ok((records, unreadable))
+}
+
+/// the fields of an action, without the type tag and with its paths and
+/// arguments as strings rather than the bytes they serialize to
+fn action_args(action: &dyn Action) -> Value {
+    let mut args = match serde_json::to_value(action) {
+        Ok(Value::Object(mut fields)) => {
+            fields.remove("type");
+            readable(Value::Object(fields))
+        }
+        _ => Value::Object(Map::new()),
+    };
+    // input sent to processes may hold passwords and the like, so only its size is kept
+    if let Some(fields) = args.as_object_mut() {
+        for name in action.redacted_fields() {
+            if let Some(value) = fields.get_mut(*name) {
+                let size = value.as_array().map_or(0, Vec::len);
+                *value = Value::String(format!("<{} bytes>", size));
+            }
+        }
+    }
+    args
+}
+
+/// turns OsStrings, which serialize as `{"Unix": [bytes]}`, into strings
+fn readable(value: Value) -> Value {
+    match value {
+        Value::Object(fields) => {
+            if fields.len() == 1 {
+                if let Some(Value::Array(bytes)) = fields.get("Unix") {
+                    let bytes: Option<Vec<u8>> =
+                        bytes.iter().map(|b| b.as_u64().map(|b| b as u8)).collect();
+                    if let Some(bytes) = bytes {
+                        return Value::String(String::from_utf8_lossy(&bytes).into_owned());
+                    }
+                }
+            }
+            Value::Object(
+                fields
+                    .into_iter()
+                    .map(|(key, value)| (key, readable(value)))
+                    .collect(),
+            )
+        }
+        Value::Array(values) => Value::Array(values.into_iter().map(readable).collect()),
+        value => value,
+    }
+}
+
+fn append(path: &Path, line: &str) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)
+            .with_context(|| format!("failed to create directory {}", dir.display()))?;
+    }
+    let mut options = OpenOptions::new();
+    options.create(true).append(true);
+    // the log shows who did what and with which arguments, so only the daemon's user may read it
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options
+        .open(path)
+        .with_context(|| format!("failed to open audit log {}", path.display()))?;
+    file.write_all(line.as_bytes())
+        .with_context(|| format!("failed to write to audit log {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::TempDir;
+    use chrono::TimeZone;
+
+    fn record(spid: Option<SibylPID>, process: Option<&str>) -> AuditRecord {
+        AuditRecord {
+            time: Utc.timestamp(1_700_000_000, 0),
+            client: AuditClient {
+                peer: "127.0.0.1:4000".to_string(),
+                uid: Some(1000),
+                user: Some("alice".to_string()),
+                token: None,
+            },
+            action: "CmdStop".to_string(),
+            args: Value::Object(Map::new()),
+            outcome: Outcome::Executed,
+            error: None,
+            spid,
+            process: process.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn empty_filter_matches_everything() {
+        assert!(AuditFilter::default().matches(&record(None, None)));
+        assert!(AuditFilter::default().matches(&record(Some(3), Some("web"))));
+    }
+
+    #[test]
+    fn filter_by_time() {
+        let record = record(None, None);
+        let at = |secs| Some(Utc.timestamp(secs, 0));
+        let filter = |since, until| AuditFilter {
+            since,
+            until,
+            process: None,
+        };
+
+        // both bounds are inclusive
+        assert!(filter(at(1_700_000_000), at(1_700_000_000)).matches(&record));
+        assert!(filter(at(1_699_999_999), None).matches(&record));
+        assert!(!filter(at(1_700_000_001), None).matches(&record));
+        assert!(filter(None, at(1_700_000_001)).matches(&record));
+        assert!(!filter(None, at(1_699_999_999)).matches(&record));
+    }
+
+    #[test]
+    fn filter_by_process() {
+        let filter = |process: &str| AuditFilter {
+            process: Some(process.to_string()),
+            ..Default::default()
+        };
+
+        assert!(filter("3").matches(&record(Some(3), Some("web"))));
+        assert!(!filter("4").matches(&record(Some(3), Some("web"))));
+        assert!(filter("web").matches(&record(Some(3), Some("web"))));
+        assert!(!filter("db").matches(&record(Some(3), Some("web"))));
+        // requests that targeted no process never match
+        assert!(!filter("3").matches(&record(None, None)));
+        assert!(!filter("web").matches(&record(None, None)));
+    }
+
+    #[test]
+    fn read_skips_unreadable_lines() {
+        let dir = TempDir::new("audit-read");
+        let path = dir.path().join("audit.log");
+        super::record(&path, &record(Some(3), Some("web"))).unwrap();
+        append(&path, "not json\n\n").unwrap();
+        super::record(&path, &record(Some(4), Some("db"))).unwrap();
+
+        let (records, unreadable) = read(&path, &AuditFilter::default()).unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(unreadable, 1);
+
+        let filter = AuditFilter {
+            process: Some("db".to_string()),
+            ..Default::default()
+        };
+        let (records, _) = read(&path, &filter).unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].spid, Some(4));
+    }
+
+    #[test]
+    fn missing_log_is_empty() {
+        let dir = TempDir::new("audit-missing");
+        let (records, unreadable) =
+            read(&dir.path().join("audit.log"), &AuditFilter::default()).unwrap();
+        assert!(records.is_empty());
+        assert_eq!(unreadable, 0);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn log_is_private() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = TempDir::new("audit-mode");
+        let path = dir.path().join("audit.log");
+        super::record(&path, &record(None, None)).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+}
@@ -0,0 +1,621 @@
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::fmt::{self, Write};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write as _};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+/// how many random bytes a generated token is made of
+const TOKEN_BYTES: usize = 32;
+
+/// a client that may connect by presenting its token
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Token {
+    /// identifies the client in the daemon log
+    pub name: String,
+    pub token: String,
+}
+
+/// the tokens the daemon accepts, read from its `token_file`
+///
+/// the file holds secrets, so it is refused unless only its owner can access it
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct TokenStore {
+    #[serde(default, rename = "token")]
+    tokens: Vec<Token>,
+}
+
+impl TokenStore {
+    /// reads a token file, or returns an empty store if it doesn't exist
+    /// # Arguments
+    /// * `path` - the token file
+    pub fn load(path: &Path) -> Result<TokenStore> {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(TokenStore::default()),
+            Err(e) => {
+                return Err(e)
+                    .with_context(|| format!("failed to open token file {}", path.display()))
+            }
+        };
+        check_permissions(path, &file)?;
+
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("failed to read token file {}", path.display()))?;
+        let store: TokenStore = toml::from_str(&contents)
+            .with_context(|| format!("failed to parse token file {}", path.display()))?;
+        for (i, token) in store.tokens.iter().enumerate() {
+            if token.token.is_empty() {
+                bail!("token '{}' in {} is empty", token.name, path.display());
+            }
+            if store.tokens[..i].iter().any(|t| t.name == token.name) {
+                bail!(
+                    "token name '{}' is used twice in {}",
+                    token.name,
+                    path.display()
+                );
+            }
+        }
+
+        Ok(store)
+    }
+
+    /// writes the tokens back, creating the file with permissions only for its owner
+    pub fn save(&self, path: &Path) -> Result<()> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)
+                .with_context(|| format!("failed to create directory {}", dir.display()))?;
+        }
+
+        let contents = toml::to_string(self).context("failed to serialize tokens")?;
+        let mut options = OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut file = options
+            .open(path)
+            .with_context(|| format!("failed to open token file {}", path.display()))?;
+        check_permissions(path, &file)?;
+        file.write_all(contents.as_bytes())
+            .with_context(|| format!("failed to write token file {}", path.display()))?;
+
+        Ok(())
+    }
+
+    /// generates a token for a new client
+    ///
+    /// returns the token, which is only shown this once
+    /// # Arguments
+    /// * `name` - identifies the client, and must not be taken yet
+    pub fn add(&mut self, name: &str) -> Result<String> {
+        if self.tokens.iter().any(|t| t.name == name) {
+            bail!("there already is a token named '{}'", name);
+        }
+        let token = generate_token()?;
+        self.tokens.push(Token {
+            name: name.to_string(),
+            token: token.clone(),
+        });
+        Ok(token)
+    }
+
+    /// the names of every client with a token
+    pub fn names(&self) -> Vec<&str> {
+        self.tokens.iter().map(|t| t.name.as_str()).collect()
+    }
+
+    /// finds the client a token belongs to
+    ///
+    /// every token is compared in constant time, so that timing doesn't give tokens away
+    pub fn authenticate(&self, token: &str) -> Option<&str> {
+        let mut found = None;
+        for t in &self.tokens {
+            if constant_time_eq::constant_time_eq(t.token.as_bytes(), token.as_bytes()) {
+                found = Some(t.name.as_str());
+            }
+        }
+        found
+    }
+}
+
+/// refuses files that users other than the owner can read or write
+#[cfg(unix)]
+fn check_permissions(path: &Path, file: &File) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mode = file.metadata()?.permissions().mode();
+    if mode & 0o077 != 0 {
+        bail!(
+            "{} is accessible by other users (mode {:o}), fix it with `chmod 600 {}`",
+            path.display(),
+            mode & 0o777,
+            path.display()
+        );
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn check_permissions(_path: &Path, _file: &File) -> Result<()> {
+    Ok(())
+}
+
+/// generates a random token, hex-encoded
+pub fn generate_token() -> Result<String> {
+    let mut bytes = [0u8; TOKEN_BYTES];
+    getrandom::getrandom(&mut bytes).context("failed to generate a random token")?;
+
+    let mut token = String::with_capacity(TOKEN_BYTES * 2);
+    for byte in bytes {
+        write!(&mut token, "{:02x}", byte)?;
+    }
+    Ok(token)
+}
+
+/// the default location of the client's token, `$XDG_CONFIG_HOME/sibyl/token` on linux
+pub fn default_client_token_file() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("sibyl").join("token"))
+}
+
+/// finds the token the client authenticates with
+///
+/// the SIBYL_TOKEN environment variable takes precedence over the token file,
+/// which holds nothing but the token and, like the daemon's, must only be accessible by its owner
+///
+/// returns None if there is no token, which is fine for local connections
+/// # Arguments
+/// * `path` - the file given with `--token-file`, if any
+pub fn client_token(path: Option<&Path>) -> Result<Option<String>> {
+    if let Ok(token) = env::var("SIBYL_TOKEN") {
+        return Ok(Some(token));
+    }
+
+    let (path, explicit) = match path {
+        Some(path) => (PathBuf::from(path), true),
+        None => match default_client_token_file() {
+            Some(path) => (path, false),
+            None => return Ok(None),
+        },
+    };
+    let file = match File::open(&path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound && !explicit => return Ok(None),
+        Err(e) => {
+            return Err(e).with_context(|| format!("failed to open token file {}", path.display()))
+        }
+    };
+    check_permissions(&path, &file)?;
+
+    let token = fs::read_to_string(&path)
+        .with_context(|| format!("failed to read token file {}", path.display()))?;
+    Ok(Some(token.trim().to_string()))
+}
+
+/// what a request is allowed to do, declared by every action
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Permission {
+    /// looking at processes, logs and schedules
+    Read,
+    /// starting programs, which amounts to running arbitrary commands as the daemon's user
+    Run,
+    /// stopping, signalling and attaching to processes that are already running
+    Manage,
+    /// reloading, upgrading and shutting down the daemon itself
+    Admin,
+}
+
+impl fmt::Display for Permission {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self {
+            Permission::Read => write!(f, "read"),
+            Permission::Run => write!(f, "run"),
+            Permission::Manage => write!(f, "manage"),
+            Permission::Admin => write!(f, "admin"),
+        }
+    }
+}
+
+/// a set of permissions, and who they are granted to, from a `[[role]]` table
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Role {
+    pub name: String,
+    pub permissions: Vec<Permission>,
+    /// user names or uids, or `*` for every local user
+    #[serde(default)]
+    pub users: Vec<String>,
+    /// group names or gids, matching users that are members
+    #[serde(default)]
+    pub groups: Vec<String>,
+    /// names of tokens from the token file
+    #[serde(default)]
+    pub tokens: Vec<String>,
+}
+
+impl Role {
+    /// whether the role is granted to a client
+    pub fn applies_to(&self, identity: &Identity) -> bool {
+        if let Some(token) = &identity.token {
+            if self.tokens.contains(token) {
+                return true;
+            }
+        }
+        let uid = match identity.uid {
+            Some(uid) => uid,
+            None => return false,
+        };
+
+        let user_matches = |user: &String| {
+            user == "*" || user.parse() == Ok(uid) || identity.user.as_ref() == Some(user)
+        };
+        let group_matches = |group: &String| {
+            let gid = group.parse().ok().or_else(|| group_id(group));
+            gid.is_some_and(|gid| identity.gids.contains(&gid))
+        };
+        self.users.iter().any(user_matches) || self.groups.iter().any(group_matches)
+    }
+}
+
+/// who is on the other end of a connection, as far as the daemon can tell
+#[derive(Debug, Clone)]
+pub struct Identity {
+    pub peer: SocketAddr,
+    /// the name of the token the client authenticated with
+    pub token: Option<String>,
+    /// the local user that owns the client's socket, only known for loopback connections on linux
+    pub uid: Option<u32>,
+    pub user: Option<String>,
+    /// the user's primary and supplementary groups
+    pub gids: Vec<u32>,
+}
+
+impl Identity {
+    /// works out who connected from `peer` to the daemon's `local` address
+    /// # Arguments
+    /// * `peer` - the client's address
+    /// * `local` - the daemon's end of the connection
+    /// * `token` - the name of the token the client authenticated with, if any
+    pub fn of_peer(peer: SocketAddr, local: SocketAddr, token: Option<String>) -> Identity {
+        let uid = if peer.ip().to_canonical().is_loopback() {
+            socket_owner(peer, local)
+        } else {
+            None
+        };
+        let (user, gids) = match uid.and_then(user_entry) {
+            Some((user, gid)) => {
+                let gids = user_groups(&user, gid);
+                (Some(user), gids)
+            }
+            None => (None, Vec::new()),
+        };
+
+        Identity {
+            peer,
+            token,
+            uid,
+            user,
+            gids,
+        }
+    }
+
+    /// whether the client runs as the same user as the daemon, or as root
+    pub fn is_owner(&self) -> bool {
+        #[cfg(unix)]
+        // safety: geteuid(2) always succeeds
+        let euid = unsafe { libc::geteuid() };
+        #[cfg(not(unix))]
+        let euid = 0;
+
+        self.uid.is_some_and(|uid| uid == euid || uid == 0)
+    }
+}
+
+impl fmt::Display for Identity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (&self.user, self.uid) {
+            (Some(user), Some(uid)) => write!(f, "{} (uid {})", user, uid)?,
+            (None, Some(uid)) => write!(f, "uid {}", uid)?,
+            _ => write!(f, "unknown user")?,
+        }
+        if let Some(token) = &self.token {
+            write!(f, " with token '{}'", token)?;
+        }
+        write!(f, " from {}", self.peer)
+    }
+}
+
+/// finds the uid owning the client end of a loopback TCP connection, which
+/// linux lists in /proc/net/tcp with the client's address as the local one
+#[cfg(target_os = "linux")]
+fn socket_owner(peer: SocketAddr, local: SocketAddr) -> Option<u32> {
+    for table in ["/proc/net/tcp", "/proc/net/tcp6"] {
+        let contents = match fs::read_to_string(table) {
+            Ok(contents) => contents,
+            Err(_) => continue,
+        };
+        for line in contents.lines().skip(1) {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() < 8 {
+                continue;
+            }
+            let same = |field: &str, addr: SocketAddr| {
+                parse_proc_addr(field).is_some_and(|parsed| {
+                    parsed.port() == addr.port()
+                        && parsed.ip().to_canonical() == addr.ip().to_canonical()
+                })
+            };
+            if same(fields[1], peer) && same(fields[2], local) {
+                return fields[7].parse().ok();
+            }
+        }
+    }
+    None
+}
+
+#[cfg(not(target_os = "linux"))]
+fn socket_owner(_peer: SocketAddr, _local: SocketAddr) -> Option<u32> {
+    None
+}
+
+/// parses an address from /proc/net/tcp, such as `0100007F:CD60`
+///
+/// the address is printed as 32-bit words in the kernel's byte order, the port as a plain number
+#[cfg(target_os = "linux")]
+fn parse_proc_addr(field: &str) -> Option<SocketAddr> {
+    use std::convert::TryFrom;
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+    let (addr, port) = field.split_once(':')?;
+    let port = u16::from_str_radix(port, 16).ok()?;
+    let mut bytes = Vec::new();
+    for i in (0..addr.len()).step_by(8) {
+        let word = u32::from_str_radix(addr.get(i..i + 8)?, 16).ok()?;
+        bytes.extend(word.to_ne_bytes());
+    }
+    let ip = match bytes.len() {
+        4 => IpAddr::V4(Ipv4Addr::new(bytes[0], bytes[1], bytes[2], bytes[3])),
+        16 => IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(bytes).ok()?)),
+        _ => return None,
+    };
+    Some(SocketAddr::new(ip, port))
+}
+
+/// looks up a user's name and primary group
+#[cfg(unix)]
+fn user_entry(uid: u32) -> Option<(String, u32)> {
+    use std::ffi::CStr;
+
+    let mut buf = vec![0; 16 * 1024];
+    // safety: passwd is plain data, and getpwuid_r only writes within `buf`
+    unsafe {
+        let mut pwd: libc::passwd = std::mem::zeroed();
+        let mut result = std::ptr::null_mut();
+        libc::getpwuid_r(uid, &mut pwd, buf.as_mut_ptr(), buf.len(), &mut result);
+        if result.is_null() {
+            return None;
+        }
+        let name = CStr::from_ptr(pwd.pw_name).to_string_lossy().into_owned();
+        Some((name, pwd.pw_gid))
+    }
+}
+
+#[cfg(not(unix))]
+fn user_entry(_uid: u32) -> Option<(String, u32)> {
+    None
+}
+
+/// lists every group a user is a member of, including its primary group
+#[cfg(unix)]
+fn user_groups(user: &str, gid: u32) -> Vec<u32> {
+    let user = match std::ffi::CString::new(user) {
+        Ok(user) => user,
+        Err(_) => return vec![gid],
+    };
+
+    let mut size: libc::c_int = 64;
+    loop {
+        let mut groups: Vec<libc::gid_t> = vec![0; size as usize];
+        let mut count = size;
+        // safety: getgrouplist writes at most `count` groups, and sets `count` to how many there are
+        let found =
+            unsafe { libc::getgrouplist(user.as_ptr(), gid, groups.as_mut_ptr(), &mut count) };
+        if found != -1 {
+            groups.truncate(count as usize);
+            return groups;
+        }
+        size = count.max(size * 2);
+    }
+}
+
+#[cfg(not(unix))]
+fn user_groups(_user: &str, gid: u32) -> Vec<u32> {
+    vec![gid]
+}
+
+/// looks up a group by name
+#[cfg(unix)]
+fn group_id(name: &str) -> Option<u32> {
+    let name = std::ffi::CString::new(name).ok()?;
+    let mut buf = vec![0; 16 * 1024];
+    // safety: group is plain data, and getgrnam_r only writes within `buf`
+    unsafe {
+        let mut grp: libc::group = std::mem::zeroed();
+        let mut result = std::ptr::null_mut();
+        libc::getgrnam_r(
+            name.as_ptr(),
+            &mut grp,
+            buf.as_mut_ptr(),
+            buf.len(),
+            &mut result,
+        );
+        if result.is_null() {
+            return None;
+        }
+        Some(grp.gr_gid)
+    }
+}
+
+#[cfg(not(unix))]
+fn group_id(_name: &str) -> Option<u32> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::TempDir;
+
+    fn store(tokens: &[(&str, &str)]) -> TokenStore {
+        TokenStore {
+            tokens: tokens
+                .iter()
+                .map(|(name, token)| Token {
+                    name: name.to_string(),
+                    token: token.to_string(),
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn authenticate_finds_the_owner_of_a_token() {
+        let store = store(&[("ci", "abc123"), ("laptop", "def456")]);
+        assert_eq!(store.authenticate("abc123"), Some("ci"));
+        assert_eq!(store.authenticate("def456"), Some("laptop"));
+    }
+
+    #[test]
+    fn authenticate_refuses_near_misses() {
+        let store = store(&[("ci", "abc123")]);
+        assert_eq!(store.authenticate(""), None);
+        assert_eq!(store.authenticate("abc12"), None);
+        assert_eq!(store.authenticate("abc1234"), None);
+        assert_eq!(store.authenticate("abc124"), None);
+        assert_eq!(store.authenticate("ABC123"), None);
+        assert_eq!(TokenStore::default().authenticate(""), None);
+    }
+
+    #[test]
+    fn add_generates_unique_tokens() {
+        let mut store = TokenStore::default();
+        let first = store.add("ci").unwrap();
+        let second = store.add("laptop").unwrap();
+        assert_eq!(first.len(), TOKEN_BYTES * 2);
+        assert_ne!(first, second);
+        assert_eq!(store.names(), ["ci", "laptop"]);
+        assert!(store.add("ci").is_err());
+    }
+
+    #[test]
+    fn saved_tokens_load_again() {
+        let dir = TempDir::new("auth-save");
+        let path = dir.path().join("nested").join("tokens.toml");
+        let mut store = TokenStore::default();
+        let token = store.add("ci").unwrap();
+        store.save(&path).unwrap();
+
+        let loaded = TokenStore::load(&path).unwrap();
+        assert_eq!(loaded, store);
+        assert_eq!(loaded.authenticate(&token), Some("ci"));
+    }
+
+    #[test]
+    fn missing_token_file_is_empty() {
+        let dir = TempDir::new("auth-missing");
+        let store = TokenStore::load(&dir.path().join("tokens.toml")).unwrap();
+        assert!(store.names().is_empty());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn token_file_readable_by_others_is_refused() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = TempDir::new("auth-mode");
+        let path = dir.path().join("tokens.toml");
+        fs::write(&path, "[[token]]\nname = \"ci\"\ntoken = \"abc\"\n").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+        assert!(TokenStore::load(&path).is_err());
+
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o600)).unwrap();
+        assert_eq!(TokenStore::load(&path).unwrap().names(), ["ci"]);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn invalid_token_files_are_refused() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = TempDir::new("auth-invalid");
+        let path = dir.path().join("tokens.toml");
+        for contents in [
+            "[[token]]\nname = \"ci\"\ntoken = \"\"\n",
+            "[[token]]\nname = \"ci\"\ntoken = \"a\"\n[[token]]\nname = \"ci\"\ntoken = \"b\"\n",
+            "[[token]]\nname = \"ci\"\ntoken = \"a\"\nrole = \"admin\"\n",
+        ] {
+            fs::write(&path, contents).unwrap();
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o600)).unwrap();
+            assert!(TokenStore::load(&path).is_err(), "{}", contents);
+        }
+    }
+
+    fn identity(
+        uid: Option<u32>,
+        user: Option<&str>,
+        gids: &[u32],
+        token: Option<&str>,
+    ) -> Identity {
+        Identity {
+            peer: "127.0.0.1:40000".parse().unwrap(),
+            token: token.map(String::from),
+            uid,
+            user: user.map(String::from),
+            gids: gids.to_vec(),
+        }
+    }
+
+    fn role(users: &[&str], groups: &[&str], tokens: &[&str]) -> Role {
+        let list = |items: &[&str]| items.iter().map(|s| s.to_string()).collect();
+        Role {
+            name: String::from("ops"),
+            permissions: vec![Permission::Manage],
+            users: list(users),
+            groups: list(groups),
+            tokens: list(tokens),
+        }
+    }
+
+    #[test]
+    fn roles_apply_to_users_by_name_uid_or_star() {
+        let alice = identity(Some(4242), Some("alice"), &[4000], None);
+        assert!(role(&["alice"], &[], &[]).applies_to(&alice));
+        assert!(role(&["4242"], &[], &[]).applies_to(&alice));
+        assert!(role(&["*"], &[], &[]).applies_to(&alice));
+        assert!(!role(&["bob", "4243"], &[], &[]).applies_to(&alice));
+
+        // remote clients have no local user, not even for `*`
+        let remote = identity(None, None, &[], None);
+        assert!(!role(&["*"], &[], &[]).applies_to(&remote));
+    }
+
+    #[test]
+    fn roles_apply_to_group_members() {
+        let alice = identity(Some(4242), Some("alice"), &[4000, 4001], None);
+        assert!(role(&[], &["4001"], &[]).applies_to(&alice));
+        assert!(!role(&[], &["4002"], &[]).applies_to(&alice));
+        assert!(!role(&[], &["no-such-group-sibyl"], &[]).applies_to(&alice));
+    }
+
+    #[test]
+    fn roles_apply_to_tokens_by_name() {
+        let ci = identity(None, None, &[], Some("ci"));
+        assert!(role(&[], &[], &["ci"]).applies_to(&ci));
+        assert!(!role(&[], &[], &["laptop"]).applies_to(&ci));
+        assert!(!role(&["*"], &["4000"], &[]).applies_to(&ci));
+    }
+}
@@ -6,15 +6,32 @@ extern crate clap;
 use anyhow::{Context, Result};
 use chrono::Utc;
 use clap::{App, ArgMatches};
+use rustls::ClientConfig;
+use sibyl::auth;
 use sibyl::commands::*;
-use sibyl::{Client, Request};
+use sibyl::config::DEFAULT_LISTEN;
+use sibyl::tls::{self, TlsOptions};
+#[cfg(unix)]
+use sibyl::{auth::TokenStore, config::DaemonConfig, daemon, pty};
+use sibyl::{Client, Request, Response, StreamFrame};
 use std::convert::From;
+use std::env;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
 
 fn main() -> Result<()> {
     let yaml = load_yaml!("../cli.yml");
     let matches = App::from_yaml(yaml).get_matches();
 
-    let req = match build_request(&matches) {
+    if let Some(matches) = matches.subcommand_matches("daemon") {
+        return manage_daemon(matches);
+    }
+
+    let req = match build_request(&matches)? {
         Some(req) => req,
         None => {
             println!("no command specified");
@@ -22,16 +39,25 @@ fn main() -> Result<()> {
         }
     };
 
-    // this is a special case
-    // we shouldn't even consider this a failure
-    let mut client = match Client::connect() {
+    let endpoint = Endpoint::from_matches(&matches)?;
+    let autostart = matches.is_present("autostart")
+        || env::var("SIBYL_AUTOSTART").is_ok_and(|value| value == "1");
+    let mut client = match connect(&endpoint, autostart) {
         Ok(client) => client,
-        Err(_) => {
-            // fail gracefully if the daemon isn't running
-            println!("failed to establish link to sibyld");
-            return Ok(());
+        Err(e) => {
+            // not being able to reach the daemon is common enough
+            // that it deserves a short message instead of a backtrace
+            eprintln!("failed to establish link to sibyld: {:#}", e);
+            if !autostart && is_refused(&e) {
+                eprintln!("start it with `sibyl daemon start`, or pass --autostart");
+            }
+            process::exit(1);
         }
     };
+    if let Err(e) = client.authenticate(endpoint.token.as_deref()) {
+        eprintln!("{:#}", e);
+        process::exit(1);
+    }
 
     client
         .send_request(&req)
@@ -39,18 +65,490 @@ fn main() -> Result<()> {
     let res = client
         .receive_response()
         .context("failed to read response from daemon")?;
+
+    if let Some(matches) = matches.subcommand_matches("run") {
+        return run_foreground(client, res, matches.is_present("forward-sigint"), endpoint);
+    }
+    if matches.subcommand_matches("attach").is_some() {
+        return attach_terminal(client, res);
+    }
+    if matches.subcommand_matches("events").is_some() {
+        return follow_stream(client, res, "events");
+    }
+    if matches
+        .subcommand_matches("log")
+        .is_some_and(|matches| matches.is_present("follow"))
+    {
+        return follow_stream(client, res, "the log");
+    }
+    if matches.subcommand_matches("grep").is_some() {
+        return follow_stream(client, res, "the search");
+    }
+    if matches.subcommand_matches("top").is_some() {
+        return show_top(client, res, endpoint);
+    }
+    if ["stop", "down", "restart", "shutdown", "audit"]
+        .iter()
+        .any(|command| matches.subcommand_matches(command).is_some())
+    {
+        return finish_action(client, res);
+    }
+    if res.error {
+        eprintln!("{}", res.msg);
+        process::exit(1);
+    }
     println!("{}", res.msg);
 
     Ok(())
 }
 
-fn build_request(matches: &ArgMatches) -> Option<Request> {
+/// how to reach the daemon, from the global options and their environment variables
+#[derive(Clone)]
+struct Endpoint {
+    address: String,
+    token: Option<String>,
+    /// set when connecting over TLS, which `--tls-ca` turns on
+    tls: Option<Arc<ClientConfig>>,
+}
+
+impl Endpoint {
+    fn from_matches(matches: &ArgMatches) -> Result<Endpoint> {
+        // options take precedence over the environment
+        let option = |name: &str, var: &str| {
+            matches
+                .value_of(name)
+                .map(String::from)
+                .or_else(|| env::var(var).ok())
+        };
+
+        let tls = match option("tls-ca", "SIBYL_TLS_CA") {
+            Some(ca) => Some(tls::client_config(&TlsOptions {
+                ca: PathBuf::from(ca),
+                cert: option("tls-cert", "SIBYL_TLS_CERT").map(PathBuf::from),
+                key: option("tls-key", "SIBYL_TLS_KEY").map(PathBuf::from),
+            })?),
+            None => None,
+        };
+
+        Ok(Endpoint {
+            address: option("address", "SIBYL_ADDRESS")
+                .unwrap_or_else(|| String::from(DEFAULT_LISTEN)),
+            token: auth::client_token(matches.value_of("token-file").map(Path::new))?,
+            tls,
+        })
+    }
+
+    /// opens a connection, without authenticating yet
+    fn open(&self) -> Result<Client> {
+        match &self.tls {
+            Some(tls) => Client::connect_tls(&self.address, Arc::clone(tls)),
+            None => Client::connect_to(&self.address),
+        }
+    }
+}
+
+/// connects to the daemon, starting it first if it isn't running and `autostart` is set
+fn connect(endpoint: &Endpoint, autostart: bool) -> Result<Client> {
+    match endpoint.open() {
+        Ok(client) => Ok(client),
+        // only a refused connection means that nothing is listening
+        Err(e) if autostart && is_refused(&e) => {
+            start_daemon()?;
+            endpoint.open()
+        }
+        Err(e) => Err(e),
+    }
+}
+
+fn is_refused(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<io::Error>()
+        .is_some_and(|e| e.kind() == io::ErrorKind::ConnectionRefused)
+}
+
+#[cfg(unix)]
+fn start_daemon() -> Result<()> {
+    eprintln!("sibyld is not running, starting it...");
+    let pid = daemon::start(None)?;
+    eprintln!("sibyld started (pid {})", pid);
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn start_daemon() -> Result<()> {
+    anyhow::bail!("starting sibyld automatically is only supported on unix")
+}
+
+/// handles `sibyl daemon`, which works on the pidfile instead of talking to the daemon
+#[cfg(unix)]
+fn manage_daemon(matches: &ArgMatches) -> Result<()> {
+    let (command, sub_matches) = matches.subcommand();
+    // --config may be given before or after the subcommand
+    let config = sub_matches
+        .and_then(|matches| matches.value_of("config"))
+        .or_else(|| matches.value_of("config"))
+        .map(Path::new);
+
+    match command {
+        "start" => {
+            let pid = daemon::start(config)?;
+            println!("sibyld started (pid {})", pid);
+        }
+        "stop" => match daemon::stop(config)? {
+            Some(pid) => println!("sibyld stopped (pid {})", pid),
+            None => println!("sibyld is not running"),
+        },
+        "token" => {
+            let name = sub_matches
+                .and_then(|matches| matches.value_of("name"))
+                .unwrap();
+            let token_file = DaemonConfig::load(config)?.token_file;
+            let mut tokens = TokenStore::load(&token_file)?;
+            let token = tokens.add(name)?;
+            tokens.save(&token_file)?;
+            println!("{}", token);
+            eprintln!("added token '{}' to {}", name, token_file.display());
+            if let Some(pid) = daemon::signal_reload(config)? {
+                eprintln!("told sibyld (pid {}) to reload its tokens", pid);
+            }
+        }
+        "upgrade" => {
+            let binary = sub_matches.and_then(|matches| matches.value_of("binary"));
+            let pid = daemon::request_upgrade(config, binary.map(Path::new))?;
+            println!("sibyld upgraded (pid {})", pid);
+        }
+        "status" => {
+            let pid_file = DaemonConfig::load(config)?.pid_file;
+            match daemon::running_pid(&pid_file)? {
+                Some(pid) => println!("sibyld is running (pid {})", pid),
+                None => {
+                    println!("sibyld is not running");
+                    process::exit(1);
+                }
+            }
+        }
+        _ => unreachable!("clap requires a daemon subcommand"),
+    }
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn manage_daemon(_matches: &ArgMatches) -> Result<()> {
+    anyhow::bail!("managing sibyld is only supported on unix")
+}
+
+/// prints the output streamed by a `run` command, then exits with the exit code of the process
+/// # Arguments
+/// * `client` - the connection the `run` request was sent over
+/// * `res` - the response to the `run` request
+/// * `forward_sigint` - whether Ctrl-C should be forwarded to the process
+/// * `endpoint` - how to reach the daemon, for forwarding Ctrl-C
+fn run_foreground(
+    mut client: Client,
+    res: Response,
+    forward_sigint: bool,
+    endpoint: Endpoint,
+) -> Result<()> {
+    let spid = match res.spid {
+        Some(spid) => spid,
+        None => {
+            // the process never started, so the message is an error
+            eprintln!("{}", res.msg);
+            process::exit(1);
+        }
+    };
+
+    if forward_sigint {
+        ctrlc::set_handler(move || {
+            let req = Request {
+                command: Box::new(CmdSignal {
+                    pid: spid,
+                    signal: libc::SIGINT,
+                }),
+                time: Utc::now(),
+            };
+            // the streaming connection is busy, so the signal goes over a new one
+            if let Ok(mut client) = endpoint.open() {
+                if client.authenticate(endpoint.token.as_deref()).is_ok() {
+                    let _ = client.send_request(&req);
+                    let _ = client.receive_response();
+                }
+            }
+        })
+        .context("failed to install Ctrl-C handler")?;
+    }
+
+    let mut stdout = io::stdout();
+    loop {
+        match client
+            .receive_frame()
+            .context("lost connection to sibyld while streaming output")?
+        {
+            StreamFrame::Output(bytes) => {
+                stdout.write_all(&bytes)?;
+                stdout.flush()?;
+            }
+            StreamFrame::End(code) => process::exit(code.unwrap_or(1)),
+            // the other frames are never sent for `run`
+            _ => {}
+        }
+    }
+}
+
+/// prints what the daemon streams, e.g. events or a log, until the stream ends
+/// # Arguments
+/// * `client` - the connection the request was sent over
+/// * `res` - the response to the request
+/// * `what` - what is being followed, for the error message when the connection drops
+fn follow_stream(mut client: Client, res: Response, what: &str) -> Result<()> {
+    if res.error {
+        eprintln!("{}", res.msg);
+        process::exit(1);
+    }
+
+    let mut stdout = io::stdout();
+    loop {
+        match client
+            .receive_frame()
+            .with_context(|| format!("lost connection to sibyld while following {}", what))?
+        {
+            StreamFrame::Output(bytes) => {
+                stdout.write_all(&bytes)?;
+                stdout.flush()?;
+            }
+            StreamFrame::End(_) => return Ok(()),
+            _ => {}
+        }
+    }
+}
+
+/// prints the response to an action that goes on after it, e.g. stopping processes,
+/// and what the daemon reports until the action is done
+///
+/// exits with the code the daemon ends the stream with, if any
+fn finish_action(mut client: Client, res: Response) -> Result<()> {
+    if res.error {
+        eprintln!("{}", res.msg);
+        process::exit(1);
+    }
+    if !res.msg.is_empty() {
+        println!("{}", res.msg);
+    }
+
+    let mut stdout = io::stdout();
+    loop {
+        // the daemon closes the connection without ending the stream when it exits
+        let frame = match client.receive_frame() {
+            Ok(frame) => frame,
+            Err(_) => return Ok(()),
+        };
+        match frame {
+            StreamFrame::Output(bytes) => {
+                stdout.write_all(&bytes)?;
+                stdout.flush()?;
+            }
+            StreamFrame::End(Some(code)) if code != 0 => process::exit(code),
+            StreamFrame::End(_) => return Ok(()),
+            _ => {}
+        }
+    }
+}
+
+/// shows the `top` dashboard, opening further connections for its actions and log pane
+#[cfg(unix)]
+fn show_top(client: Client, res: Response, endpoint: Endpoint) -> Result<()> {
+    if res.error {
+        eprintln!("{}", res.msg);
+        process::exit(1);
+    }
+    sibyl::top::run(client, move || {
+        let mut client = endpoint.open()?;
+        client.authenticate(endpoint.token.as_deref())?;
+        Ok(client)
+    })
+}
+
+#[cfg(not(unix))]
+fn show_top(_client: Client, _res: Response, _endpoint: Endpoint) -> Result<()> {
+    anyhow::bail!("sibyl top is only supported on unix")
+}
+
+/// connects the terminal to an attached process until the user detaches or the process exits
+///
+/// typing `~.` at the start of a line detaches, and `~~` sends a literal `~`
+/// # Arguments
+/// * `client` - the connection the `attach` request was sent over
+/// * `res` - the response to the `attach` request
+fn attach_terminal(mut client: Client, res: Response) -> Result<()> {
+    if res.spid.is_none() {
+        eprintln!("{}", res.msg);
+        process::exit(1);
+    }
+    eprintln!("{}", res.msg);
+
+    // processes on a pty get the terminal in raw mode, so that
+    // keys like Ctrl-C and arrow keys reach the program itself
+    // input and resize frames are sent from different threads,
+    // so they share a lock to keep frames from interleaving
+    let sender = Arc::new(Mutex::new(client.try_clone()?));
+    if let StreamFrame::Attached { pty: true } = client.receive_frame()? {
+        enter_raw_mode(Arc::clone(&sender));
+    }
+
+    let mut output = client.try_clone()?;
+    thread::spawn(move || {
+        let mut stdout = io::stdout();
+        loop {
+            match output.receive_frame() {
+                Ok(StreamFrame::Output(bytes)) => {
+                    let _ = stdout.write_all(&bytes);
+                    let _ = stdout.flush();
+                }
+                Ok(StreamFrame::End(code)) => {
+                    restore_terminal();
+                    match code {
+                        Some(code) => eprintln!("\r\n[process exited with exit code {}]", code),
+                        None => eprintln!("\r\n[process exited]"),
+                    }
+                    process::exit(0);
+                }
+                _ => {
+                    restore_terminal();
+                    eprintln!("\r\n[lost connection to sibyld]");
+                    process::exit(1);
+                }
+            }
+        }
+    });
+
+    let mut stdin = io::stdin();
+    let mut buffer = [0; 1024];
+    let mut escape = EscapeDetector::default();
+    let result = loop {
+        let read = match stdin.read(&mut buffer) {
+            Ok(0) => break Ok(()),
+            Ok(read) => read,
+            Err(e) => break Err(e.into()),
+        };
+
+        let (bytes, detach) = escape.feed(&buffer[..read]);
+        if !bytes.is_empty() {
+            if let Err(e) = sender
+                .lock()
+                .unwrap()
+                .send_frame(&StreamFrame::Input(bytes))
+            {
+                break Err(e);
+            }
+        }
+        if detach {
+            break Ok(());
+        }
+    };
+
+    restore_terminal();
+    result?;
+    sender.lock().unwrap().send_frame(&StreamFrame::Detach)?;
+    eprintln!("\r\n[detached]");
+    Ok(())
+}
+
+/// terminal settings to go back to once the client is done attaching
+#[cfg(unix)]
+static ORIGINAL_TERMIOS: Mutex<Option<libc::termios>> = Mutex::new(None);
+
+/// puts the terminal into raw mode, and keeps the pty's window size
+/// in sync with it by polling the terminal's size
+#[cfg(unix)]
+fn enter_raw_mode(sender: Arc<Mutex<Client>>) {
+    let original = match pty::enter_raw_mode() {
+        Some(original) => original,
+        // stdin is not a terminal, so there is nothing to configure
+        None => return,
+    };
+    *ORIGINAL_TERMIOS.lock().unwrap() = Some(original);
+
+    thread::spawn(move || {
+        let mut last = None;
+        loop {
+            let size = pty::window_size(0);
+            if let Some((rows, cols)) = size.filter(|_| size != last) {
+                let frame = StreamFrame::Resize { rows, cols };
+                if sender.lock().unwrap().send_frame(&frame).is_err() {
+                    return;
+                }
+                last = size;
+            }
+            thread::sleep(Duration::from_millis(250));
+        }
+    });
+}
+
+#[cfg(not(unix))]
+fn enter_raw_mode(_sender: Arc<Mutex<Client>>) {}
+
+/// undoes `enter_raw_mode`, if it was called
+#[cfg(unix)]
+fn restore_terminal() {
+    if let Some(original) = ORIGINAL_TERMIOS.lock().unwrap().take() {
+        pty::restore_mode(&original);
+    }
+}
+
+#[cfg(not(unix))]
+fn restore_terminal() {}
+
+/// finds the `~.` detach sequence in typed input, ssh-style
+#[derive(Default)]
+struct EscapeDetector {
+    /// whether the last byte typed wasn't a newline, so that a `~` now would not
+    /// be at the start of a line (false before anything is typed)
+    mid_line: bool,
+    /// whether a `~` at the start of a line is being held back
+    pending: bool,
+}
+
+impl EscapeDetector {
+    /// returns the bytes that should be forwarded, and whether the user asked to detach
+    fn feed(&mut self, input: &[u8]) -> (Vec<u8>, bool) {
+        let mut out = Vec::with_capacity(input.len());
+
+        for &byte in input {
+            if self.pending {
+                self.pending = false;
+                match byte {
+                    b'.' => return (out, true),
+                    b'~' => {
+                        out.push(b'~');
+                        self.mid_line = true;
+                        continue;
+                    }
+                    _ => out.push(b'~'),
+                }
+            } else if byte == b'~' && !self.mid_line {
+                self.pending = true;
+                continue;
+            }
+
+            out.push(byte);
+            self.mid_line = !matches!(byte, b'\n' | b'\r');
+        }
+
+        (out, false)
+    }
+}
+
+fn build_request(matches: &ArgMatches) -> Result<Option<Request>> {
     let command: Box<dyn Action>;
 
     if let Some(matches) = matches.subcommand_matches("once") {
-        command = Box::new(CmdOnce::from(matches));
+        command = Box::new(CmdOnce::from_matches(matches)?);
     } else if matches.subcommand_matches("latest").is_some() {
         command = Box::new(CmdLatest);
+    } else if let Some(matches) = matches.subcommand_matches("shutdown") {
+        command = Box::new(CmdShutdown::from(matches));
+    } else if matches.subcommand_matches("reload").is_some() {
+        command = Box::new(CmdReload);
     } else if matches.subcommand_matches("ping").is_some() {
         command = Box::new(CmdPing);
     } else if let Some(matches) = matches.subcommand_matches("status") {
@@ -59,13 +557,47 @@ fn build_request(matches: &ArgMatches) -> Option<Request> {
         command = Box::new(CmdList);
     } else if let Some(matches) = matches.subcommand_matches("log") {
         command = Box::new(CmdLog::from(matches));
-    }
-    else {
-        return None;
+    } else if let Some(matches) = matches.subcommand_matches("grep") {
+        command = Box::new(CmdGrep::from_matches(matches)?);
+    } else if let Some(matches) = matches.subcommand_matches("up") {
+        let path = Path::new(matches.value_of("file").unwrap());
+        command = Box::new(CmdUp::from_file(path)?);
+    } else if let Some(matches) = matches.subcommand_matches("down") {
+        command = Box::new(CmdDown::from(matches));
+    } else if let Some(matches) = matches.subcommand_matches("schedule") {
+        command = Box::new(CmdSchedule::from_matches(matches)?);
+    } else if matches.subcommand_matches("schedules").is_some() {
+        command = Box::new(CmdSchedules);
+    } else if let Some(matches) = matches.subcommand_matches("unschedule") {
+        command = Box::new(CmdUnschedule::from_matches(matches)?);
+    } else if let Some(matches) = matches.subcommand_matches("run") {
+        command = Box::new(CmdRun::from_matches(matches)?);
+    } else if let Some(matches) = matches.subcommand_matches("signal") {
+        command = Box::new(CmdSignal::from_matches(matches)?);
+    } else if let Some(matches) = matches.subcommand_matches("stop") {
+        command = Box::new(CmdStop::from_matches(matches)?);
+    } else if let Some(matches) = matches.subcommand_matches("reset") {
+        command = Box::new(CmdReset::from(matches));
+    } else if let Some(matches) = matches.subcommand_matches("attach") {
+        command = Box::new(CmdAttach::from_matches(matches)?);
+    } else if let Some(matches) = matches.subcommand_matches("send") {
+        command = Box::new(CmdSend::from_matches(matches)?);
+    } else if let Some(matches) = matches.subcommand_matches("events") {
+        command = Box::new(CmdEvents::from_matches(matches)?);
+    } else if let Some(matches) = matches.subcommand_matches("restart") {
+        command = Box::new(CmdRestart::from_matches(matches)?);
+    } else if let Some(matches) = matches.subcommand_matches("top") {
+        command = Box::new(CmdTop::from_matches(matches)?);
+    } else if matches.subcommand_matches("metrics").is_some() {
+        command = Box::new(CmdMetrics);
+    } else if let Some(matches) = matches.subcommand_matches("audit") {
+        command = Box::new(CmdAudit::from_matches(matches)?);
+    } else {
+        return Ok(None);
     }
 
-    Some(Request {
+    Ok(Some(Request {
         command,
         time: Utc::now(),
-    })
+    }))
 }
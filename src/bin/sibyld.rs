@@ -1,77 +1,444 @@
 extern crate anyhow;
-extern crate dirs;
+#[macro_use]
+extern crate clap;
 #[macro_use]
 extern crate log;
 
 use anyhow::{Context, Result};
-use sibyl::commands::CommandContext;
+use clap::App;
+use rustls::ServerConfig;
+use sibyl::auth::{Identity, TokenStore};
+use sibyl::commands::{process_request, CommandContext, SharedContext};
+use sibyl::config::{AuthMode, DaemonConfig};
+#[cfg(unix)]
+use sibyl::daemon;
+use sibyl::events::EventBus;
+use sibyl::hooks::HookRunner;
+use sibyl::http;
 use sibyl::logging::LogHandler;
+use sibyl::metrics::Metrics;
 use sibyl::processing::ProcessHandler;
-use sibyl::{Client, Request, Response};
-use std::net::TcpListener;
+use sibyl::scheduling::ScheduleHandler;
+use sibyl::state::{DaemonFds, SavedState};
+use sibyl::supervisor;
+use sibyl::tls::{self, TlsStream};
+use sibyl::webhooks::Notifier;
+use sibyl::{Client, HandshakeReply, Response};
+use std::fs;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// how long a new connection has to send its handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
 
 fn main() -> Result<()> {
-    // use environment variable SIBYL_LOG for loglevel settings
-    env_logger::Builder::from_env("SIBYL_LOG").init();
+    let yaml = load_yaml!("../sibyld.yml");
+    let matches = App::from_yaml(yaml).get_matches();
+
+    let config = DaemonConfig::load(matches.value_of("config").map(Path::new))?;
+    // reloads must find the same file even if the working directory changes
+    let config_path = matches
+        .value_of("config")
+        .map(|path| fs::canonicalize(path).unwrap_or_else(|_| PathBuf::from(path)));
 
-    let listener =
-        TcpListener::bind("127.0.0.1:52352").context("failed to create TCP listener")?;
-    info!("bound to port 52352!");
+    // the environment variable SIBYL_LOG overrides the configured log level
+    env_logger::Builder::new()
+        .parse_filters(&config.log_level)
+        .parse_env("SIBYL_LOG")
+        .init();
 
-    // get (or create, if it does not exist) the log directory
-    let mut path = dirs::data_local_dir().unwrap();
-    path.push("sibyllogs");
+    // loaded up front, so that a bad certificate fails startup while errors can still reach the terminal
+    let tls_config = config.tls.as_ref().map(tls::server_config).transpose()?;
+
+    // an upgraded daemon carries on with the previous one's sockets and processes
+    let upgrade = match matches.value_of("upgrade-from") {
+        Some(path) => Some(
+            SavedState::take(Path::new(path))?
+                .with_context(|| format!("missing upgrade state {}", path))?,
+        ),
+        None => None,
+    };
+
+    #[cfg(unix)]
+    let (mut listeners, daemon_fds) = match &upgrade {
+        Some(state) => inherit_fds(&state.fds)?,
+        None => bind_listeners(&config, matches.is_present("daemonize"))?,
+    };
+    #[cfg(not(unix))]
+    let (mut listeners, daemon_fds) = match &upgrade {
+        Some(_) => anyhow::bail!("upgrades are only supported on unix"),
+        None => bind_listeners(&config, matches.is_present("daemonize"))?,
+    };
+    #[cfg(unix)]
+    install_signal_handlers();
+
+    let tokens = TokenStore::load(&config.token_file)?;
+    warn_unauthenticated(&config, &tokens);
 
     let mut ctx = CommandContext {
-        loghandler: LogHandler::new(&path),
+        config_path,
+        shutting_down: false,
+        daemon_fds,
+        tokens,
+        metrics: Metrics::default(),
+        events: EventBus::default(),
+        hooks: HookRunner::default(),
+        notifier: Notifier::default(),
+        loghandler: LogHandler::new(&config.log_directory),
         prochandler: ProcessHandler::new(),
+        schedhandler: ScheduleHandler::new(),
+        config,
+    };
+    match upgrade {
+        Some(state) => {
+            info!("upgraded sibyld (pid {})", std::process::id());
+            ctx.restore(state, true);
+        }
+        None => {
+            // processes a previous daemon left running when it shut down
+            if let Some(state) = SavedState::take(&ctx.config.state_file)? {
+                ctx.restore(state, false);
+            }
+        }
+    }
+    // definitions whose processes were carried over are already running or queued
+    let managed: Vec<String> = ctx
+        .prochandler
+        .all_processes()
+        .iter()
+        .filter_map(|proc| proc.name().map(String::from))
+        .chain(ctx.prochandler.pending().iter().map(|def| def.name.clone()))
+        .collect();
+    let definitions: Vec<_> = ctx
+        .config
+        .processes
+        .iter()
+        .filter(|def| !managed.contains(&def.name))
+        .cloned()
+        .collect();
+    ctx.prochandler.queue_definitions(definitions);
+
+    let ctx = Arc::new(Mutex::new(ctx));
+    supervisor::spawn(Arc::clone(&ctx));
+
+    if !listeners.tls.is_empty() && tls_config.is_none() {
+        // only possible after an upgrade to a config without tls
+        warn!("tls was removed from the config, closing its listeners");
+        listeners.tls.clear();
+    }
+    if !listeners.http.is_empty() && ctx.lock().unwrap().config.http.is_none() {
+        warn!("http was removed from the config, closing its listeners");
+        listeners.http.clear();
+    }
+    let mut threads = Vec::new();
+    for listener in listeners.plain {
+        let ctx = Arc::clone(&ctx);
+        threads.push(thread::spawn(move || {
+            accept_connections(listener, ctx, None)
+        }));
+    }
+    for listener in listeners.tls {
+        let ctx = Arc::clone(&ctx);
+        let tls_config = tls_config.clone();
+        threads.push(thread::spawn(move || {
+            accept_connections(listener, ctx, tls_config)
+        }));
+    }
+    for listener in listeners.http {
+        let ctx = Arc::clone(&ctx);
+        threads.push(thread::spawn(move || {
+            if let Err(e) = http::serve(listener, ctx) {
+                error!("http api stopped: {:#}", e);
+            }
+        }));
+    }
+    for thread in threads {
+        let _ = thread.join();
+    }
+
+    Ok(())
+}
+
+/// the sockets the daemon accepts connections on
+struct Listeners {
+    plain: Vec<TcpListener>,
+    /// connections to these start with a TLS handshake
+    tls: Vec<TcpListener>,
+    /// these serve the JSON API
+    http: Vec<TcpListener>,
+}
+
+/// locks the pidfile, binds every listen address and daemonizes if asked to
+fn bind_listeners(config: &DaemonConfig, daemonize: bool) -> Result<(Listeners, DaemonFds)> {
+    #[cfg(unix)]
+    let mut pidfile = daemon::PidFile::lock(&config.pid_file)?;
+
+    // bind everything up front, so that a bad address fails
+    // startup while errors can still reach the terminal
+    let bind = |addrs: &[String]| -> Result<Vec<TcpListener>> {
+        addrs
+            .iter()
+            .map(|addr| {
+                TcpListener::bind(addr)
+                    .with_context(|| format!("failed to create TCP listener on {}", addr))
+            })
+            .collect()
+    };
+    let tls_listen = config.tls.as_ref().map_or(&[][..], |tls| &tls.listen[..]);
+    let http_listen = config
+        .http
+        .as_ref()
+        .map_or(&[][..], |http| &http.listen[..]);
+    let listeners = Listeners {
+        plain: bind(&config.listen)?,
+        tls: bind(tls_listen)?,
+        http: bind(http_listen)?,
+    };
+
+    if daemonize {
+        #[cfg(unix)]
+        daemon::daemonize(&config.daemon_log)?;
+        #[cfg(not(unix))]
+        anyhow::bail!("--daemonize is only supported on unix");
+    }
+    // logged after daemonizing, so that it ends up in the daemon log
+    for addr in &config.listen {
+        info!("listening on {}", addr);
+    }
+    for addr in tls_listen {
+        info!("listening on {} (tls)", addr);
+    }
+    for addr in http_listen {
+        info!("listening on {} (http)", addr);
+    }
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::io::AsRawFd;
+
+        pidfile.write_pid()?;
+        let fds = DaemonFds {
+            listeners: listeners.plain.iter().map(|l| l.as_raw_fd()).collect(),
+            tls_listeners: listeners.tls.iter().map(|l| l.as_raw_fd()).collect(),
+            http_listeners: listeners.http.iter().map(|l| l.as_raw_fd()).collect(),
+            pid_file: Some(pidfile.as_raw_fd()),
+        };
+        // the lock lasts as long as the file stays open, which is until the daemon exits
+        std::mem::forget(pidfile);
+        Ok((listeners, fds))
+    }
+    #[cfg(not(unix))]
+    Ok((listeners, DaemonFds::default()))
+}
+
+/// takes back the pidfile and listeners that the previous daemon kept open across the upgrade
+#[cfg(unix)]
+fn inherit_fds(fds: &DaemonFds) -> Result<(Listeners, DaemonFds)> {
+    use std::os::unix::io::FromRawFd;
+
+    if let Some(fd) = fds.pid_file {
+        // safety: the fd was handed over by the previous daemon, and nothing else owns it
+        std::mem::forget(unsafe { daemon::PidFile::inherit(fd)? });
+    }
+    let inherit = |fds: &[i32], kind: &str| -> Result<Vec<TcpListener>> {
+        let mut listeners = Vec::new();
+        for &fd in fds {
+            daemon::set_cloexec(fd, true)?;
+            // safety: as above
+            let listener = unsafe { TcpListener::from_raw_fd(fd) };
+            if let Ok(addr) = listener.local_addr() {
+                info!("listening on {}{}", addr, kind);
+            }
+            listeners.push(listener);
+        }
+        Ok(listeners)
     };
+    let listeners = Listeners {
+        plain: inherit(&fds.listeners, "")?,
+        tls: inherit(&fds.tls_listeners, " (tls)")?,
+        http: inherit(&fds.http_listeners, " (http)")?,
+    };
+    Ok((listeners, fds.clone()))
+}
+
+/// warns when clients on other machines will not be able to connect, or will not need a token to
+fn warn_unauthenticated(config: &DaemonConfig, tokens: &TokenStore) {
+    let tls_listen = config.tls.iter().flat_map(|tls| &tls.listen);
+    let http_listen = config.http.iter().flat_map(|http| &http.listen);
+    let remote = config
+        .listen
+        .iter()
+        .chain(tls_listen)
+        .chain(http_listen)
+        .any(|addr| {
+            addr.parse::<SocketAddr>()
+                .is_ok_and(|addr| !addr.ip().is_loopback())
+        });
+    if !remote {
+        return;
+    }
+    match config.auth {
+        AuthMode::Off => warn!("auth is off, anyone who can reach sibyld can run commands"),
+        _ if tokens.names().is_empty() => warn!(
+            "there are no tokens in {}, remote clients will be refused",
+            config.token_file.display()
+        ),
+        _ => {}
+    }
+}
+
+/// makes SIGHUP reload the config, like `sibyl reload`, and
+/// SIGTERM and SIGINT shut the daemon down, like `sibyl shutdown`
+#[cfg(unix)]
+fn install_signal_handlers() {
+    extern "C" fn on_sighup(_: libc::c_int) {
+        supervisor::request_reload();
+    }
+    extern "C" fn on_shutdown_signal(_: libc::c_int) {
+        supervisor::request_shutdown();
+    }
+
+    // safety: the handlers only store to atomics, which is async-signal-safe
+    unsafe {
+        libc::signal(libc::SIGHUP, on_sighup as *const () as libc::sighandler_t);
+        libc::signal(
+            libc::SIGTERM,
+            on_shutdown_signal as *const () as libc::sighandler_t,
+        );
+        libc::signal(
+            libc::SIGINT,
+            on_shutdown_signal as *const () as libc::sighandler_t,
+        );
+    }
+}
 
+/// # Arguments
+/// * `listener` - the socket to accept connections on
+/// * `ctx` - the command context
+/// * `tls` - if set, connections start with a TLS handshake using these settings
+fn accept_connections(listener: TcpListener, ctx: SharedContext, tls: Option<Arc<ServerConfig>>) {
     for connection in listener.incoming() {
         match connection {
             Ok(stream) => {
-                // create a client for each incoming stream
-                let mut client = Client::from_stream(stream);
-
-                // match statement is here so we can handle failure gracefully
-                let req = match client.receive_request() {
-                    Ok(req) => {
-                        info!("got request");
-                        req
-                    }
-                    Err(e) => {
-                        error!("failed to receive request: {}", e);
-                        continue;
-                    }
-                };
-
-                let res = process_command(&req, &mut ctx);
-
-                match client.send_response(&res) {
-                    Ok(_) => {
-                        debug!("sent response: {:?}", res);
-                    }
-                    Err(e) => {
-                        error!("failed to send response: {}", e);
-                        continue;
-                    }
-                }
+                // each client gets its own thread, so that streaming
+                // commands don't hold up everyone else
+                let ctx = Arc::clone(&ctx);
+                let tls = tls.clone();
+                thread::spawn(move || {
+                    let client = match tls {
+                        Some(tls) => match accept_tls(stream, tls) {
+                            Ok(client) => client,
+                            Err(e) => {
+                                warn!("{:#}", e);
+                                return;
+                            }
+                        },
+                        None => Client::from_stream(stream),
+                    };
+                    handle_connection(client, ctx)
+                });
             }
             Err(e) => warn!("connection failed: {}", e),
         }
     }
+}
 
-    Ok(())
+/// performs the TLS handshake on a new connection, giving up if the client doesn't finish it in time
+fn accept_tls(stream: TcpStream, tls: Arc<ServerConfig>) -> Result<Client> {
+    let peer = stream.peer_addr()?;
+    stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+    let stream = TlsStream::accept(stream, tls)
+        .with_context(|| format!("refused connection from {}", peer))?;
+    stream.get_ref().set_read_timeout(None)?;
+    Ok(Client::from_tls_stream(stream))
 }
 
-fn process_command(req: &Request, ctx: &mut CommandContext) -> Response {
-    match req.command.execute(req, ctx) {
-        Ok(r) => r,
+fn handle_connection(mut client: Client, ctx: SharedContext) {
+    let peer = match client.peer_addr() {
+        Ok(peer) => peer,
         Err(e) => {
-            error!("failed to execute a command!");
-            Response {
-                msg: format!("an error occurred: {}", e),
-            }
+            error!("failed to get peer address: {}", e);
+            return;
+        }
+    };
+    let identity = match authenticate(&mut client, peer, &ctx) {
+        Ok(identity) => identity,
+        Err(e) => {
+            warn!("refused connection from {}: {:#}", peer, e);
+            return;
+        }
+    };
+
+    // match statement is here so we can handle failure gracefully
+    let req = match client.receive_request() {
+        Ok(req) => {
+            info!("got request");
+            req
+        }
+        Err(e) => {
+            error!("failed to receive request: {}", e);
+            return;
+        }
+    };
+
+    let res = match process_request(&req, &identity, &mut ctx.lock().unwrap()) {
+        Ok(res) => res,
+        Err(e) => Response {
+            msg: format!("an error occurred: {}", e),
+            spid: None,
+            error: true,
+        },
+    };
+
+    match client.send_response(&res) {
+        Ok(_) => {
+            debug!("sent response: {:?}", res);
+        }
+        Err(e) => {
+            error!("failed to send response: {}", e);
+            // actions such as shutdown and restart finish their work while streaming,
+            // which has to happen whether or not the client is still there
+        }
+    }
+
+    if !res.error {
+        if let Err(e) = req.command.stream(&req, &res, &ctx, &mut client) {
+            warn!("stream to client ended early: {}", e);
+        }
+    }
+}
+
+/// reads the client's handshake and checks its token, so that nothing
+/// from a client that isn't allowed in gets past this point
+///
+/// returns who the client is, for deciding what it may do
+fn authenticate(client: &mut Client, peer: SocketAddr, ctx: &SharedContext) -> Result<Identity> {
+    // a client that never sends its handshake shouldn't hold on to a thread
+    client.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+    let handshake = client.receive_handshake()?;
+    client.set_read_timeout(None)?;
+
+    let result = ctx
+        .lock()
+        .unwrap()
+        .authenticate(handshake.token.as_deref(), peer.ip());
+    match result {
+        Ok(token) => {
+            // looking up the client's user reads /proc, so it happens without the lock
+            let identity = Identity::of_peer(peer, client.local_addr()?, token);
+            debug!("connection from {}", identity);
+            client.send_handshake_reply(&HandshakeReply::Accepted)?;
+            Ok(identity)
+        }
+        Err(e) => {
+            // the reason is only logged, the client just learns that it was refused
+            let _ = client.send_handshake_reply(&HandshakeReply::Rejected(String::from(
+                "authentication failed",
+            )));
+            Err(e)
         }
     }
 }
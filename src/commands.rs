@@ -1,23 +1,725 @@
-use crate::logging::{LogHandler, LogName};
-use crate::processing::ProcessHandler;
-use crate::{Request, Response};
-use anyhow::{Context, Result};
-use chrono::{Local, Utc};
+use crate::audit::{self, AuditFilter, AuditRecord, Outcome};
+use crate::auth::{Identity, Permission, TokenStore};
+use crate::config::{DaemonConfig, ShutdownPolicy};
+use crate::definitions::{self, HookKind, ProcessDefinition};
+use crate::events::{Event, EventBus, EventFilter, EventKind};
+use crate::hooks::HookRunner;
+use crate::logging::{timestamped, LogHandler, LogName};
+use crate::metrics::{self, Metrics};
+use crate::processing::{
+    CrashState, HealthStatus, LaunchOptions, ProcessHandler, ProcessWaitStatus, SibylPID,
+    SibylProcess, StdinPipe,
+};
+#[cfg(unix)]
+use crate::pty::{self, Pty};
+use crate::scheduling::{JobID, OverlapPolicy, ScheduleHandler, Trigger};
+use crate::search;
+use crate::state::{DaemonFds, SavedState};
+use crate::top;
+use crate::util::{parse_duration, parse_signal, parse_timeout};
+use crate::webhooks::{AlertKind, Notifier};
+use crate::{Client, Request, Response, StreamFrame};
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Utc};
 use clap::ArgMatches;
+use log::{error, info, warn};
+use regex::{Regex, RegexBuilder};
 use serde::{Deserialize, Serialize};
-use std::fmt::Write;
+use std::collections::{HashMap, HashSet};
 use std::ffi::{OsStr, OsString};
-use std::fs::{metadata, read_dir, OpenOptions};
-use std::io::Read;
-use std::path::PathBuf;
+use std::fmt::{self, Write};
+use std::fs::{self, metadata, read_dir, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
-use std::time::SystemTime;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
 use typetag;
 
+/// how often streams that can go quiet send a heartbeat, so that clients
+/// that have gone away are noticed
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+
 /// structure containing all resources that commands may need to access
 pub struct CommandContext {
+    pub config: DaemonConfig,
+    /// the file given with `--config`, if any
+    pub config_path: Option<PathBuf>,
+    /// set once a shutdown has begun, after which no new processes are started
+    pub shutting_down: bool,
+    /// the daemon's own file descriptors, kept open when it is upgraded
+    pub daemon_fds: DaemonFds,
+    /// the tokens clients may authenticate with
+    pub tokens: TokenStore,
+    /// counters for the `metrics` command
+    pub metrics: Metrics,
+    /// lifecycle events, for clients following them with `events`
+    pub events: EventBus,
+    /// the definition hooks that are still running
+    pub hooks: HookRunner,
+    /// sends alerts to the configured webhooks
+    pub notifier: Notifier,
     pub loghandler: LogHandler,
     pub prochandler: ProcessHandler,
+    pub schedhandler: ScheduleHandler,
+}
+
+/// the command context as it is shared between the daemon's threads
+pub type SharedContext = Arc<Mutex<CommandContext>>;
+
+#[cfg(test)]
+impl CommandContext {
+    /// a context with the default config and nothing running, logging to `log_directory`
+    pub fn for_tests(log_directory: &Path) -> CommandContext {
+        CommandContext {
+            config: DaemonConfig::default(),
+            config_path: None,
+            shutting_down: false,
+            daemon_fds: DaemonFds::default(),
+            tokens: TokenStore::default(),
+            metrics: Metrics::default(),
+            events: EventBus::default(),
+            hooks: HookRunner::default(),
+            notifier: Notifier::default(),
+            loghandler: LogHandler::new(log_directory),
+            prochandler: ProcessHandler::new(),
+            schedhandler: ScheduleHandler::new(),
+        }
+    }
+}
+
+impl CommandContext {
+    /// whether `max_processes` processes are already running
+    pub fn at_capacity(&mut self) -> bool {
+        match self.config.max_processes {
+            Some(max) => self.prochandler.running_count() >= max,
+            None => false,
+        }
+    }
+
+    /// decides whether a new connection may send requests, before it gets to send any
+    ///
+    /// returns the name of the client's token, or None if it connected without one
+    /// # Arguments
+    /// * `token` - the token from the client's handshake
+    /// * `peer` - the address the client connected from
+    pub fn authenticate(&self, token: Option<&str>, peer: IpAddr) -> Result<Option<String>> {
+        match token {
+            // a wrong token is refused even where none is needed
+            Some(token) => match self.tokens.authenticate(token) {
+                Some(name) => Ok(Some(name.to_string())),
+                None => bail!("invalid token"),
+            },
+            None if self.config.auth.requires_token(peer) => bail!("a token is required"),
+            None => Ok(None),
+        }
+    }
+
+    /// whether a client may do what `permission` covers
+    ///
+    /// without any roles configured everyone may do everything, and
+    /// the daemon's own user always may
+    pub fn authorize(&self, identity: &Identity, permission: Permission) -> bool {
+        if self.config.roles.is_empty() || identity.is_owner() {
+            return true;
+        }
+        self.config
+            .roles
+            .iter()
+            .any(|role| role.permissions.contains(&permission) && role.applies_to(identity))
+    }
+
+    /// re-reads the config file and applies everything that can change at runtime
+    ///
+    /// settings that only take effect on restart are kept as they were, and
+    /// definitions are never stopped, only added or updated for their next start
+    ///
+    /// returns a description of what changed
+    pub fn reload_config(&mut self) -> Result<String> {
+        let mut new = DaemonConfig::load(self.config_path.as_deref())?;
+        let tokens = TokenStore::load(&new.token_file)?;
+        let old = &self.config;
+        let mut msg = String::new();
+
+        if tokens != self.tokens {
+            let (before, after) = (self.tokens.names().join(", "), tokens.names().join(", "));
+            if before == after {
+                writeln!(&mut msg, "  tokens: changed for [{}]", after)?;
+            } else {
+                writeln!(&mut msg, "  tokens: [{}] -> [{}]", before, after)?;
+            }
+        }
+
+        for (setting, before, after) in old.changed_settings(&new) {
+            writeln!(&mut msg, "  {}: {} -> {}", setting, before, after)?;
+        }
+        for setting in old.fixed_changes(&new) {
+            writeln!(
+                &mut msg,
+                "  warning: {} changed, but only takes effect when sibyld is restarted",
+                setting
+            )?;
+        }
+        new.listen = old.listen.clone();
+        new.log_level = old.log_level.clone();
+        new.pid_file = old.pid_file.clone();
+        new.daemon_log = old.daemon_log.clone();
+
+        for def in &new.processes {
+            match old.processes.iter().find(|d| d.name == def.name) {
+                Some(old_def) if old_def == def => {}
+                Some(_) => {
+                    self.prochandler.update_definition(def.clone());
+                    writeln!(
+                        &mut msg,
+                        "  process '{}': changed, applied when it is next started",
+                        def.name
+                    )?;
+                }
+                None => {
+                    let managed = self
+                        .prochandler
+                        .pending()
+                        .iter()
+                        .any(|d| d.name == def.name)
+                        || self.prochandler.get_process_by_name(&def.name).is_some();
+                    if managed {
+                        writeln!(
+                            &mut msg,
+                            "  warning: process '{}' added, but a process with that name is already managed",
+                            def.name
+                        )?;
+                    } else {
+                        self.prochandler.queue_definitions(vec![def.clone()]);
+                        writeln!(&mut msg, "  process '{}': added", def.name)?;
+                    }
+                }
+            }
+        }
+        for def in &old.processes {
+            if !new.processes.iter().any(|d| d.name == def.name) {
+                writeln!(
+                    &mut msg,
+                    "  process '{}': removed, left running (stop it with `sibyl down {}`)",
+                    def.name, def.name
+                )?;
+            }
+        }
+
+        self.loghandler.set_log_directory(&new.log_directory);
+        self.config = new;
+        self.tokens = tokens;
+        let changes = msg.lines().map(|line| line.trim().to_string()).collect();
+        self.emit(EventKind::ConfigReloaded { changes });
+
+        if msg.is_empty() {
+            Ok(String::from("reloaded config, nothing changed"))
+        } else {
+            Ok(format!("reloaded config:\n{}", msg.trim_end()))
+        }
+    }
+
+    /// the names of the definitions matching `wanted`, along with those of every
+    /// running or pending definition that depends on them, directly or not
+    pub fn with_dependents(&self, wanted: impl Fn(&str) -> bool) -> HashSet<String> {
+        let defs: Vec<&ProcessDefinition> = self
+            .prochandler
+            .all_processes()
+            .iter()
+            .filter_map(|proc| proc.definition.as_ref())
+            .chain(self.prochandler.pending())
+            .collect();
+
+        let mut names: HashSet<String> = defs
+            .iter()
+            .filter(|def| wanted(&def.name))
+            .map(|def| def.name.clone())
+            .collect();
+        loop {
+            let dependents: Vec<String> = defs
+                .iter()
+                .filter(|def| !names.contains(&def.name))
+                .filter(|def| def.depends_on.iter().any(|dep| names.contains(&dep.name)))
+                .map(|def| def.name.clone())
+                .collect();
+            if dependents.is_empty() {
+                return names;
+            }
+            names.extend(dependents);
+        }
+    }
+
+    /// finds the running latest instance of every definition whose name matches `wanted`,
+    /// dependents before the processes they depend on, for stopping them in that order
+    /// with `stop_process`
+    ///
+    /// they are marked as stopped right away, so that the supervisor doesn't restart
+    /// those that exit while the ones before them are being stopped
+    pub fn stop_order(&mut self, wanted: impl Fn(&str) -> bool) -> Result<Vec<(String, SibylPID)>> {
+        // only the latest instance of each name is considered,
+        // since older instances have already been replaced
+        let mut defs: Vec<ProcessDefinition> = Vec::new();
+        for proc in self.prochandler.all_processes().iter().rev() {
+            if let Some(def) = &proc.definition {
+                if wanted(&def.name) && !defs.iter().any(|d| d.name == def.name) {
+                    defs.push(def.clone());
+                }
+            }
+        }
+
+        let mut stopping = Vec::new();
+        let order = definitions::start_order(&defs)?;
+        for def in order.into_iter().rev() {
+            let proc = self.prochandler.get_process_by_name(&def.name).unwrap();
+            if let ProcessWaitStatus::Running(_) = proc.wait_status() {
+                proc.stopped = true;
+                stopping.push((def.name.clone(), proc.pid));
+            }
+        }
+
+        Ok(stopping)
+    }
+
+    /// collects the running processes, pending definitions and scheduled
+    /// jobs, for a later daemon to take over
+    pub fn save_state(&mut self) -> SavedState {
+        let mut state = SavedState::default();
+        for proc in self.prochandler.all_processes_mut() {
+            if matches!(proc.wait_status(), ProcessWaitStatus::Running(_)) {
+                state.processes.push(proc.to_saved());
+            }
+        }
+        state.pending = self.prochandler.pending().to_vec();
+        state.jobs = self
+            .schedhandler
+            .all_jobs()
+            .iter()
+            .map(From::from)
+            .collect();
+        state
+    }
+
+    /// takes over everything a previous daemon saved with `save_state`
+    /// # Arguments
+    /// * `state` - the saved state
+    /// * `inherited` - whether the daemon was upgraded in place, so the processes are still its children
+    pub fn restore(&mut self, mut state: SavedState, inherited: bool) {
+        // the parsed fields of definitions aren't saved, so they are filled in again
+        let saved_defs = state
+            .processes
+            .iter_mut()
+            .filter_map(|p| p.definition.as_mut());
+        for def in saved_defs.chain(state.pending.iter_mut()) {
+            if let Err(e) = definitions::validate(def) {
+                warn!("saved definition of '{}' is invalid: {:#}", def.name, e);
+            }
+        }
+
+        for saved in state.processes {
+            let (spid, os_pid) = (saved.spid, saved.os_pid);
+            let (stdin_fd, pty_fd) = (saved.stdin_fd, saved.pty_fd);
+            if !self.prochandler.adopt(saved, inherited) {
+                warn!("spid {} (pid {}) is no longer running", spid, os_pid);
+                continue;
+            }
+            info!("adopted spid {} (pid {})", spid, os_pid);
+
+            #[cfg(unix)]
+            if let Err(e) = self.restore_fds(spid, stdin_fd, pty_fd) {
+                warn!("failed to restore the stdin of spid {}: {}", spid, e);
+            }
+            #[cfg(not(unix))]
+            let _ = (stdin_fd, pty_fd);
+        }
+
+        self.prochandler.queue_definitions(state.pending);
+        for job in state.jobs {
+            self.schedhandler.restore_job(job.into());
+        }
+        if inherited {
+            self.hooks.inherit(state.hooks);
+        }
+    }
+
+    /// reattaches the stdin pipe or pty a process had before the daemon was upgraded
+    #[cfg(unix)]
+    fn restore_fds(
+        &mut self,
+        spid: SibylPID,
+        stdin_fd: Option<i32>,
+        pty_fd: Option<i32>,
+    ) -> Result<()> {
+        use std::os::unix::io::FromRawFd;
+
+        let proc = self.prochandler.get_process_by_pid_mut(spid).unwrap();
+        if let Some(fd) = pty_fd {
+            crate::daemon::set_cloexec(fd, true)?;
+            // safety: the fd was handed over by the previous daemon, and nothing else owns it
+            let master = unsafe { File::from_raw_fd(fd) };
+            let log = OpenOptions::new().append(true).open(&proc.log_file)?;
+            pty::copy_to_log(master.try_clone()?, log);
+            proc.stdin = Some(StdinPipe::new(master.try_clone()?));
+            proc.pty = Some(master);
+        } else if let Some(fd) = stdin_fd {
+            crate::daemon::set_cloexec(fd, true)?;
+            // safety: as above
+            let pipe = unsafe { File::from_raw_fd(fd) };
+            proc.stdin = Some(StdinPipe::with_fd(pipe)?);
+        }
+
+        Ok(())
+    }
+
+    /// gets the daemon ready to exit, either saving its processes to the state file
+    /// so the next daemon can adopt them, or keeping them from being restarted so
+    /// that `stop_for_shutdown` can stop them
+    ///
+    /// returns a description of what happened to the processes
+    pub fn shutdown(&mut self, policy: ShutdownPolicy) -> Result<String> {
+        let mut msg = String::new();
+
+        // saving is the only step that can fail before anything has been touched
+        if policy == ShutdownPolicy::Detach {
+            for proc in self.prochandler.all_processes() {
+                if proc.stdin.is_some() || proc.pty.is_some() {
+                    writeln!(
+                        &mut msg,
+                        "warning: spid {} loses its stdin once sibyld exits",
+                        proc.pid
+                    )?;
+                }
+            }
+
+            let state = self.save_state();
+            state.save(&self.config.state_file)?;
+            writeln!(
+                &mut msg,
+                "left {} processes running, saved to {}",
+                state.processes.len(),
+                self.config.state_file.display()
+            )?;
+        }
+
+        self.shutting_down = true;
+        for def in self.prochandler.take_pending(|_| true) {
+            writeln!(&mut msg, "cancelled pending process '{}'", def.name)?;
+        }
+        // no more restarts, whichever policy is used
+        for proc in self.prochandler.all_processes_mut() {
+            proc.stopped = true;
+        }
+
+        let msg = format!("sibyld is shutting down\n{}", msg);
+        Ok(msg.trim_end().to_string())
+    }
+
+    /// undoes `shutdown` after stopping the processes failed, so that the daemon
+    /// goes on as before with whatever is still running
+    ///
+    /// cancelled pending processes stay cancelled, and stopped processes stay stopped
+    pub fn abort_shutdown(&mut self) {
+        self.shutting_down = false;
+        for proc in self.prochandler.all_processes_mut() {
+            if matches!(proc.wait_status(), ProcessWaitStatus::Running(_)) {
+                proc.stopped = false;
+            }
+        }
+    }
+
+    /// the processes a shutdown with the stop policy stops, one at a time: definitions
+    /// with their dependents first, then everything else that is still running
+    fn shutdown_order(&mut self) -> Result<Vec<(String, SibylPID)>> {
+        let mut stopping: Vec<(String, SibylPID)> = self
+            .stop_order(|_| true)?
+            .into_iter()
+            .map(|(name, pid)| (format!("'{}' (spid {})", name, pid), pid))
+            .collect();
+        for proc in self.prochandler.all_processes_mut() {
+            let pid = proc.pid;
+            if matches!(proc.wait_status(), ProcessWaitStatus::Running(_))
+                && !stopping.iter().any(|(_, p)| *p == pid)
+            {
+                stopping.push((format!("spid {}", pid), pid));
+            }
+        }
+        Ok(stopping)
+    }
+
+    /// tells the clients following events about one, after running the hooks
+    /// and sending the webhook alerts it triggers
+    pub fn emit(&mut self, kind: EventKind) {
+        let rotated = self.log_rotation(&kind);
+        self.metrics.record_event(&kind);
+        self.run_hooks(&kind);
+        let event = Event::new(kind);
+        if let Some(alert) = self.alert_for(&event.kind) {
+            self.notifier.notify(&self.config.webhooks, alert, &event);
+        }
+        self.events.emit(event);
+        if let Some(rotated) = rotated {
+            self.emit(rotated);
+        }
+    }
+
+    /// the `log_rotated` event that follows a restart, since the new instance
+    /// writes to a log file of its own
+    fn log_rotation(&self, event: &EventKind) -> Option<EventKind> {
+        let (name, previous_spid, spid) = match event {
+            EventKind::Restarted {
+                name,
+                previous_spid,
+                spid,
+                ..
+            } => (name, *previous_spid, *spid),
+            _ => return None,
+        };
+        let from = &self.prochandler.get_process_by_pid(previous_spid)?.log_file;
+        let to = &self.prochandler.get_process_by_pid(spid)?.log_file;
+        if from == to {
+            return None;
+        }
+        Some(EventKind::LogRotated {
+            spid,
+            name: name.clone(),
+            from: from.clone(),
+            to: to.clone(),
+        })
+    }
+
+    /// the webhook alert an event raises, if any
+    fn alert_for(&mut self, event: &EventKind) -> Option<AlertKind> {
+        if self.shutting_down {
+            return None;
+        }
+        match *event {
+            EventKind::Exited { .. } if event.is_failure() => Some(AlertKind::Failed),
+            EventKind::CrashLooping { .. } | EventKind::Quarantined { .. } => {
+                Some(AlertKind::CrashLoop)
+            }
+            EventKind::HealthChanged {
+                spid,
+                to: HealthStatus::Unhealthy,
+                ..
+            } if self.is_running(spid) => Some(AlertKind::Unhealthy),
+            _ => None,
+        }
+    }
+
+    /// whether a process is still running, as opposed to exited or unknown
+    fn is_running(&mut self, spid: SibylPID) -> bool {
+        self.prochandler
+            .get_process_by_pid_mut(spid)
+            .is_some_and(|proc| matches!(proc.wait_status(), ProcessWaitStatus::Running(_)))
+    }
+
+    /// runs the hooks a definition declares for an event about one of its processes
+    fn run_hooks(&mut self, event: &EventKind) {
+        // the processes are being stopped on purpose, so nothing is failing
+        if self.shutting_down {
+            return;
+        }
+        let (spid, mut kinds, mut env) = match *event {
+            EventKind::Started { spid, .. } => (spid, vec![HookKind::OnStart], Vec::new()),
+            EventKind::Exited {
+                spid, code, signal, ..
+            } => {
+                let mut kinds = vec![HookKind::OnExit];
+                if event.is_failure() {
+                    kinds.push(HookKind::OnFailure);
+                }
+                let mut env = Vec::new();
+                if let Some(code) = code {
+                    env.push(("SIBYL_EXIT_CODE", code.to_string()));
+                }
+                if let Some(signal) = signal {
+                    env.push(("SIBYL_SIGNAL", signal.to_string()));
+                }
+                (spid, kinds, env)
+            }
+            EventKind::Restarted {
+                previous_spid,
+                spid,
+                restarts,
+                ..
+            } => (
+                spid,
+                vec![HookKind::OnRestart],
+                vec![
+                    ("SIBYL_PREVIOUS_SPID", previous_spid.to_string()),
+                    ("SIBYL_RESTARTS", restarts.to_string()),
+                ],
+            ),
+            EventKind::HealthChanged {
+                spid,
+                to: HealthStatus::Unhealthy,
+                ..
+            } => (spid, vec![HookKind::OnUnhealthy], Vec::new()),
+            _ => return,
+        };
+
+        // a process that exited is reported unhealthy too, which on_exit already covers
+        if kinds == [HookKind::OnUnhealthy] && !self.is_running(spid) {
+            return;
+        }
+        let proc = match self.prochandler.get_process_by_pid(spid) {
+            Some(proc) => proc,
+            None => return,
+        };
+        let def = match &proc.definition {
+            Some(def) => def,
+            None => return,
+        };
+        kinds.retain(|kind| def.hooks.get(*kind).is_some());
+        if kinds.is_empty() {
+            return;
+        }
+
+        let def = def.clone();
+        env.push(("SIBYL_SPID", spid.to_string()));
+        env.push(("SIBYL_NAME", def.name.clone()));
+        env.push((
+            "SIBYL_LOG_FILE",
+            proc.log_file.to_string_lossy().into_owned(),
+        ));
+        for kind in kinds {
+            let mut env = env.clone();
+            env.push(("SIBYL_HOOK", kind.to_string()));
+            let hook = def.hooks.get(kind).unwrap();
+            if let Err(e) = self
+                .hooks
+                .run(hook, &def.name, kind, &env, &mut self.loghandler)
+            {
+                error!("{:#}", e);
+            }
+        }
+    }
+
+    /// spawns a program with its output sent to a fresh log file
+    /// and registers it with the process handler
+    /// # Arguments
+    /// * `name` - used to name the log file
+    /// * `program` - the program to run
+    /// * `args` - the arguments passed to the program
+    /// * `definition` - the definition the process is started from, if any
+    /// * `options` - options controlling how the process is run
+    pub fn launch(
+        &mut self,
+        name: &impl LogName,
+        program: &OsStr,
+        args: &[OsString],
+        definition: Option<ProcessDefinition>,
+        options: &LaunchOptions,
+    ) -> Result<SibylPID> {
+        let pid = self.spawn(name, program, args, definition, options)?;
+        if let Some(proc) = self.prochandler.get_process_by_pid(pid) {
+            let event = EventKind::Started {
+                spid: pid,
+                name: proc.display_name().unwrap_or_default(),
+                cmdline: proc.cmdline.to_string_lossy().into_owned(),
+            };
+            self.emit(event);
+        }
+        Ok(pid)
+    }
+
+    /// `launch`, without announcing the new process
+    fn spawn(
+        &mut self,
+        name: &impl LogName,
+        program: &OsStr,
+        args: &[OsString],
+        definition: Option<ProcessDefinition>,
+        options: &LaunchOptions,
+    ) -> Result<SibylPID> {
+        if self.shutting_down {
+            bail!("sibyld is shutting down");
+        }
+        if self.at_capacity() {
+            bail!(
+                "the maximum of {} concurrent processes is already running",
+                self.config.max_processes.unwrap()
+            );
+        }
+
+        let logfile = self.loghandler.create_log(name)?;
+        let output_file = logfile.open()?;
+        let log_path = logfile.get_path().to_path_buf();
+
+        let mut cmd = Command::new(program);
+        cmd.args(args);
+        reset_signals(&mut cmd);
+
+        if options.pty {
+            return self.launch_pty(
+                program,
+                args,
+                cmd,
+                output_file,
+                &log_path,
+                definition,
+                options,
+            );
+        }
+
+        cmd.stdout(Stdio::from(output_file)).stderr(Stdio::null());
+        if options.stdin {
+            cmd.stdin(Stdio::piped());
+        }
+
+        self.prochandler
+            .create_process(program, args, &log_path, cmd, definition, options)
+            .context("failed to create process!")
+    }
+
+    /// spawns a program on a pty allocated by the daemon, with everything
+    /// it writes to the terminal copied into its log file
+    #[cfg(unix)]
+    #[allow(clippy::too_many_arguments)]
+    fn launch_pty(
+        &mut self,
+        program: &OsStr,
+        args: &[OsString],
+        mut cmd: Command,
+        output_file: File,
+        log_path: &Path,
+        definition: Option<ProcessDefinition>,
+        options: &LaunchOptions,
+    ) -> Result<SibylPID> {
+        let pty = Pty::open().context("failed to allocate a pty")?;
+        pty.attach_to(&mut cmd)?;
+
+        let pid = self
+            .prochandler
+            .create_process(program, args, log_path, cmd, definition, options)
+            .context("failed to create process!")?;
+
+        let master = pty.into_master();
+        pty::copy_to_log(master.try_clone()?, output_file);
+        let proc = self.prochandler.get_process_by_pid_mut(pid).unwrap();
+        proc.stdin = Some(StdinPipe::new(master.try_clone()?));
+        proc.pty = Some(master);
+
+        Ok(pid)
+    }
+
+    #[cfg(not(unix))]
+    #[allow(clippy::too_many_arguments)]
+    fn launch_pty(
+        &mut self,
+        _program: &OsStr,
+        _args: &[OsString],
+        _cmd: Command,
+        _output_file: File,
+        _log_path: &Path,
+        _definition: Option<ProcessDefinition>,
+        _options: &LaunchOptions,
+    ) -> Result<SibylPID> {
+        bail!("pty mode is only supported on unix")
+    }
 }
 
 /// trait that represents an action executable by the server
@@ -25,9 +727,238 @@ pub struct CommandContext {
 /// all command-structures implement this trait
 #[typetag::serde(tag = "type")]
 pub trait Action {
+    /// what the client needs to be allowed to do for the daemon to execute this action
+    fn permission(&self) -> Permission;
+
     fn execute(&self, req: &Request, ctx: &mut CommandContext) -> Result<Response>;
+
+    /// fields that are too sensitive to copy into the audit log, which records only their size
+    fn redacted_fields(&self) -> &'static [&'static str] {
+        &[]
+    }
+
+    /// whether the audit log records the action when it succeeds. denied and
+    /// failed requests are always recorded
+    fn audited(&self) -> bool {
+        true
+    }
+
+    /// called after the response from a successful `execute` has been sent,
+    /// for commands that keep streaming frames to the client
+    ///
+    /// the context is not locked while streaming, so implementations
+    /// should only lock it briefly
+    fn stream(
+        &self,
+        _req: &Request,
+        _res: &Response,
+        _ctx: &SharedContext,
+        _client: &mut Client,
+    ) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// why a request was not carried out
+#[derive(Debug)]
+pub enum RequestError {
+    /// the client lacks the permission the action needs
+    Denied(String),
+    /// the action was executed but failed
+    Failed(anyhow::Error),
+}
+
+impl fmt::Display for RequestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RequestError::Denied(msg) => write!(f, "{}", msg),
+            RequestError::Failed(e) => write!(f, "{:#}", e),
+        }
+    }
+}
+
+/// executes the command in a request if the client is allowed to, and records it in the audit log
+///
+/// this is shared by every way of reaching the daemon, so they all follow the same rules
+/// # Arguments
+/// * `req` - the request to carry out
+/// * `identity` - who sent it
+/// * `ctx` - the command context
+pub fn process_request(
+    req: &Request,
+    identity: &Identity,
+    ctx: &mut CommandContext,
+) -> std::result::Result<Response, RequestError> {
+    let started = Instant::now();
+    let permission = req.command.permission();
+    if !ctx.authorize(identity, permission) {
+        let action = action_name(req.command.as_ref());
+        warn!("denied {} to {}", action, identity);
+        let msg = format!(
+            "permission denied, {} needs the {} permission",
+            action, permission
+        );
+        let mut record = AuditRecord::new(identity, req.command.as_ref(), Outcome::Denied);
+        record.error = Some(msg.clone());
+        finish_request(ctx, &record, true, started);
+        return Err(RequestError::Denied(msg));
+    }
+
+    let result = req.command.execute(req, ctx);
+    let mut record = match &result {
+        Ok(res) => {
+            let mut record = AuditRecord::new(identity, req.command.as_ref(), Outcome::Executed);
+            record.spid = res.spid.or(record.spid);
+            record
+        }
+        Err(e) => {
+            let mut record = AuditRecord::new(identity, req.command.as_ref(), Outcome::Failed);
+            record.error = Some(format!("{:#}", e));
+            record
+        }
+    };
+    record.process = record
+        .spid
+        .and_then(|spid| ctx.prochandler.get_process_by_pid(spid))
+        .and_then(SibylProcess::display_name);
+    let audited = req.command.audited() || record.outcome != Outcome::Executed;
+    finish_request(ctx, &record, audited, started);
+
+    result.map_err(|e| {
+        error!("failed to execute a command!");
+        RequestError::Failed(e)
+    })
+}
+
+/// counts a handled request in the metrics and appends it to the audit log if `audited`,
+/// which failing to do doesn't fail the request
+fn finish_request(ctx: &mut CommandContext, record: &AuditRecord, audited: bool, started: Instant) {
+    ctx.metrics
+        .record_request(&record.action, record.outcome, started.elapsed());
+    if !audited {
+        return;
+    }
+    if let Err(e) = audit::record(&ctx.config.audit_log, record) {
+        error!("{:#}", e);
+    }
+}
+
+/// the name of an action as typed on the command line, e.g. `once` for CmdOnce
+pub fn action_name(action: &dyn Action) -> String {
+    action
+        .typetag_name()
+        .trim_start_matches("Cmd")
+        .to_lowercase()
+}
+
+/// stops a process, waiting for it outside of the context's lock
+///
+/// returns false if no process with the given pid exists or it has already exited
+/// # Arguments
+/// * `ctx` - the command context, which must not be locked by the caller
+/// * `pid` - the process to stop
+/// * `grace` - how long the process gets to exit after SIGTERM before it is killed
+pub fn stop_process(ctx: &SharedContext, pid: SibylPID, grace: Duration) -> Result<bool> {
+    if !ctx.lock().unwrap().prochandler.terminate_process(pid)? {
+        return Ok(false);
+    }
+    wait_for_stop(ctx, pid, grace)?;
+    Ok(true)
+}
+
+/// waits for a process that was sent SIGTERM to exit, killing it once `grace` has passed
+///
+/// the context is only locked to check on the process, so that the supervisor and
+/// other clients aren't held up while the process takes its time
+pub fn wait_for_stop(ctx: &SharedContext, pid: SibylPID, grace: Duration) -> Result<()> {
+    let deadline = Instant::now() + grace;
+    while Instant::now() < deadline {
+        if ctx.lock().unwrap().prochandler.has_exited(pid)? {
+            return Ok(());
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+
+    warn!("spid {} didn't exit within {:?}, killing it", pid, grace);
+    ctx.lock().unwrap().prochandler.kill_process(pid)?;
+    while !ctx.lock().unwrap().prochandler.has_exited(pid)? {
+        thread::sleep(Duration::from_millis(10));
+    }
+    Ok(())
+}
+
+/// stops every process once `CommandContext::shutdown` has prepared a shutdown
+/// with the stop policy, and removes the state file
+///
+/// the shutdown is aborted with `CommandContext::abort_shutdown` if this fails
+/// # Arguments
+/// * `ctx` - the command context, which must not be locked by the caller
+/// * `report` - called with a line describing each process that was stopped
+pub fn stop_for_shutdown(ctx: &SharedContext, mut report: impl FnMut(String)) -> Result<()> {
+    let result = stop_all(ctx, &mut report);
+    if result.is_err() {
+        ctx.lock().unwrap().abort_shutdown();
+    }
+    result
+}
+
+fn stop_all(ctx: &SharedContext, report: &mut impl FnMut(String)) -> Result<()> {
+    let (stopping, grace, state_file) = {
+        let mut ctx = ctx.lock().unwrap();
+        let stopping = ctx.shutdown_order()?;
+        (
+            stopping,
+            ctx.config.stop_timeout(),
+            ctx.config.state_file.clone(),
+        )
+    };
+    for (what, pid) in stopping {
+        stop_process(ctx, pid, grace)?;
+        report(format!("stopped {}", what));
+    }
+
+    // a stale state file would make the next daemon adopt the wrong processes
+    if state_file.exists() {
+        fs::remove_file(&state_file)?;
+    }
+    Ok(())
+}
+
+/// sends what is left of an action's reply once it has done the work it does after
+/// its response, and ends the stream
+///
+/// sending is best-effort, since the work is done whether or not the client stayed
+fn finish_stream(client: &mut Client, result: Result<String>) -> Result<()> {
+    let (msg, code) = match result {
+        Ok(msg) => (msg, None),
+        Err(e) => (format!("an error occurred: {:#}\n", e), Some(1)),
+    };
+    if !msg.is_empty() {
+        let _ = client.send_frame(&StreamFrame::Output(msg.into_bytes()));
+    }
+    let _ = client.send_frame(&StreamFrame::End(code));
+    Ok(())
+}
+
+/// makes the child start with the default disposition for the signals users forward,
+/// since a daemon started in the background has SIGINT and SIGQUIT ignored
+#[cfg(unix)]
+fn reset_signals(cmd: &mut Command) {
+    use std::os::unix::process::CommandExt;
+
+    // safety: signal(2) is async-signal-safe, so it may be called between fork and exec
+    unsafe {
+        cmd.pre_exec(|| {
+            libc::signal(libc::SIGINT, libc::SIG_DFL);
+            libc::signal(libc::SIGQUIT, libc::SIG_DFL);
+            Ok(())
+        });
+    }
 }
 
+#[cfg(not(unix))]
+fn reset_signals(_cmd: &mut Command) {}
+
 /// command-structure for the `once` command
 ///
 /// action that describes a program to be run once
@@ -36,19 +967,33 @@ pub trait Action {
 pub struct CmdOnce {
     pub program: OsString,
     pub args: Vec<OsString>,
+    pub options: LaunchOptions,
 }
 
 // implement the ability to create a CmdOnce from clap's ArgMatches
 // consider moving this to a utility file or in sibyl.rs
-impl From<&ArgMatches<'_>> for CmdOnce {
-    fn from(matches: &ArgMatches) -> Self {
+impl CmdOnce {
+    /// builds the command from clap's ArgMatches, parsing the timeout
+    pub fn from_matches(matches: &ArgMatches) -> Result<Self> {
         let cmdline = matches.values_of("cmd").unwrap().collect::<Vec<_>>();
 
         let (program, args) = cmdline.split_at(1);
         let program: OsString = OsString::from(program[0]);
         let args: Vec<OsString> = args.iter().map(OsString::from).collect();
+        let timeout = matches
+            .value_of("timeout")
+            .map(|timeout| parse_timeout(timeout).context("invalid timeout"))
+            .transpose()?;
 
-        CmdOnce { program, args }
+        Ok(CmdOnce {
+            program,
+            args,
+            options: LaunchOptions {
+                timeout,
+                stdin: matches.is_present("stdin"),
+                pty: matches.is_present("pty"),
+            },
+        })
     }
 }
 
@@ -63,42 +1008,24 @@ impl LogName for CmdOnce {
         if v.len() == 1 {
             filename.push(v[0]);
         } else {
-            for arg in v.iter().take(v.len() - 2) {
+            for arg in v.iter().take(v.len() - 1) {
                 filename.push(arg);
                 filename.push("_");
             }
-            filename.push(v[args.len() - 1]);
+            filename.push(v[v.len() - 1]);
         }
-        let timestamp = format!("_{}", Local::now());
-        let timestamp: String = timestamp
-            .chars()
-            .map(|c| match c {
-                ' ' => '-',
-                ':' => '-',
-                _ => c,
-            })
-            .collect();
-        filename.push(timestamp);
-
-        PathBuf::from(filename)
+        timestamped(filename)
     }
 }
 
 #[typetag::serde]
 impl Action for CmdOnce {
-    fn execute(&self, _req: &Request, ctx: &mut CommandContext) -> Result<Response> {
-        let logfile = ctx.loghandler.create_log(self)?;
-        let output_file = logfile.open()?;
-
-        let mut cmd = Command::new(&self.program);
-        cmd.args(&self.args)
-            .stdout(Stdio::from(output_file))
-            .stderr(Stdio::null());
+    fn permission(&self) -> Permission {
+        Permission::Run
+    }
 
-        let pid = ctx
-            .prochandler
-            .create_process(&self.program, &self.args, logfile.get_path(), cmd)
-            .context("failed to create process!")?;
+    fn execute(&self, _req: &Request, ctx: &mut CommandContext) -> Result<Response> {
+        let pid = ctx.launch(self, &self.program, &self.args, None, &self.options)?;
 
         Ok(Response {
             msg: format!(
@@ -106,6 +1033,8 @@ impl Action for CmdOnce {
                 &self.program.to_str().unwrap(),
                 pid
             ),
+            spid: Some(pid),
+            error: false,
         })
     }
 }
@@ -118,6 +1047,10 @@ pub struct CmdLatest;
 
 #[typetag::serde]
 impl Action for CmdLatest {
+    fn permission(&self) -> Permission {
+        Permission::Read
+    }
+
     fn execute(&self, _req: &Request, ctx: &mut CommandContext) -> Result<Response> {
         let path = ctx.loghandler.log_directory();
         let mut latest_file = PathBuf::new();
@@ -140,55 +1073,244 @@ impl Action for CmdLatest {
         let mut s = String::new();
         file.read_to_string(&mut s)?;
 
-        Ok(Response { msg: s })
+        Ok(Response {
+            msg: s,
+            spid: None,
+            error: false,
+        })
     }
 }
 
-/// command-structure for the `ping` command
+/// command-structure for the `reload` command
 ///
-/// for now, just responds with 'pong!'
-// we should change this to actually time the ping
+/// re-reads the daemon's config file, like sending sibyld SIGHUP
 #[derive(Serialize, Deserialize)]
-pub struct CmdPing;
+pub struct CmdReload;
 
 #[typetag::serde]
-impl Action for CmdPing {
-    fn execute(&self, req: &Request, _ctx: &mut CommandContext) -> Result<Response> {
-        let now = Utc::now();
-        let pingtime = now - req.time;
+impl Action for CmdReload {
+    fn permission(&self) -> Permission {
+        Permission::Admin
+    }
 
+    fn execute(&self, _req: &Request, ctx: &mut CommandContext) -> Result<Response> {
         Ok(Response {
-            msg: format!("pong! {}ms", pingtime.num_milliseconds()),
+            msg: ctx.reload_config()?,
+            spid: None,
+            error: false,
         })
     }
 }
 
+/// command-structure for the `shutdown` command
+///
+/// stops or detaches from every process, then makes the daemon exit
 #[derive(Serialize, Deserialize)]
-pub struct CmdStatus {
-    pub pid: u32,
+pub struct CmdShutdown {
+    /// overrides the `shutdown` setting of the daemon's config
+    pub policy: Option<ShutdownPolicy>,
 }
 
-impl From<&ArgMatches<'_>> for CmdStatus {
+impl From<&ArgMatches<'_>> for CmdShutdown {
     fn from(matches: &ArgMatches) -> Self {
-        let pid: u32 = matches
-            .value_of("pid")
-            .unwrap()
-            .parse()
-            .expect("failed to parse pid as integer!");
-        CmdStatus { pid }
+        let policy = if matches.is_present("detach") {
+            Some(ShutdownPolicy::Detach)
+        } else if matches.is_present("stop") {
+            Some(ShutdownPolicy::Stop)
+        } else {
+            None
+        };
+        CmdShutdown { policy }
     }
 }
 
 #[typetag::serde]
-impl Action for CmdStatus {
+impl Action for CmdShutdown {
+    fn permission(&self) -> Permission {
+        Permission::Admin
+    }
+
     fn execute(&self, _req: &Request, ctx: &mut CommandContext) -> Result<Response> {
-        Ok(match ctx.prochandler.get_process_status(self.pid) {
-            Some(status) => Response {
-                msg: format!("{}", status),
-            },
-            None => Response {
-                msg: format!("no process found with pid {}", self.pid),
-            },
+        let policy = self.policy.unwrap_or(ctx.config.shutdown);
+        Ok(Response {
+            msg: ctx.shutdown(policy)?,
+            spid: None,
+            error: false,
+        })
+    }
+
+    /// stops the processes if the policy says so, then exits
+    fn stream(
+        &self,
+        _req: &Request,
+        _res: &Response,
+        ctx: &SharedContext,
+        client: &mut Client,
+    ) -> Result<()> {
+        let policy = self.policy.unwrap_or(ctx.lock().unwrap().config.shutdown);
+        if policy == ShutdownPolicy::Stop {
+            let stopped = stop_for_shutdown(ctx, |line| {
+                let line = format!("{}\n", line);
+                let _ = client.send_frame(&StreamFrame::Output(line.into_bytes()));
+            });
+            if let Err(e) = stopped {
+                let e = e.context("failed to shut down, sibyld keeps running");
+                error!("{:#}", e);
+                return finish_stream(client, Err(e));
+            }
+        }
+        ctx.lock().unwrap().hooks.kill_all();
+        let _ = finish_stream(client, Ok(String::new()));
+        std::process::exit(0);
+    }
+}
+
+/// command-structure for `sibyl daemon upgrade`
+///
+/// replaces the running daemon with a new binary, which takes over
+/// its processes, schedules and listening sockets without stopping them
+#[derive(Serialize, Deserialize)]
+pub struct CmdUpgrade {
+    /// the binary to run, the daemon's own binary if unset
+    pub binary: Option<PathBuf>,
+}
+
+impl From<&ArgMatches<'_>> for CmdUpgrade {
+    fn from(matches: &ArgMatches) -> Self {
+        CmdUpgrade {
+            binary: matches.value_of("binary").map(PathBuf::from),
+        }
+    }
+}
+
+impl CmdUpgrade {
+    /// the binary the daemon is replaced with
+    fn binary(&self) -> Result<PathBuf> {
+        match &self.binary {
+            Some(binary) => Ok(binary.clone()),
+            None => {
+                let exe = std::env::current_exe()?;
+                // linux reports the old binary as deleted once it has been replaced on disk
+                let exe = exe.to_string_lossy();
+                Ok(PathBuf::from(exe.trim_end_matches(" (deleted)")))
+            }
+        }
+    }
+}
+
+#[typetag::serde]
+impl Action for CmdUpgrade {
+    fn permission(&self) -> Permission {
+        Permission::Admin
+    }
+
+    fn execute(&self, _req: &Request, ctx: &mut CommandContext) -> Result<Response> {
+        if ctx.shutting_down {
+            bail!("sibyld is shutting down");
+        }
+        let binary = self.binary()?;
+        if !binary.is_file() {
+            bail!("{} does not exist", binary.display());
+        }
+
+        Ok(Response {
+            msg: format!(
+                "upgrading sibyld (pid {}) to {}",
+                std::process::id(),
+                binary.display()
+            ),
+            spid: None,
+            error: false,
+        })
+    }
+
+    /// replaces the daemon once the response has reached the client
+    ///
+    /// on success this never returns, and the client sees its connection close.
+    /// otherwise the error is sent as output, followed by an End frame
+    fn stream(
+        &self,
+        _req: &Request,
+        _res: &Response,
+        ctx: &SharedContext,
+        client: &mut Client,
+    ) -> Result<()> {
+        #[cfg(unix)]
+        let err = match self.binary() {
+            Ok(binary) => crate::daemon::upgrade(&mut ctx.lock().unwrap(), &binary),
+            Err(e) => e,
+        };
+        #[cfg(not(unix))]
+        let err = {
+            let _ = ctx;
+            anyhow::anyhow!("upgrading sibyld is only supported on unix")
+        };
+
+        error!("failed to upgrade: {:#}", err);
+        client.send_frame(&StreamFrame::Output(format!("{:#}", err).into_bytes()))?;
+        client.send_frame(&StreamFrame::End(Some(1)))
+    }
+}
+
+/// command-structure for the `ping` command
+///
+/// for now, just responds with 'pong!'
+// we should change this to actually time the ping
+#[derive(Serialize, Deserialize)]
+pub struct CmdPing;
+
+#[typetag::serde]
+impl Action for CmdPing {
+    fn permission(&self) -> Permission {
+        Permission::Read
+    }
+
+    fn execute(&self, req: &Request, _ctx: &mut CommandContext) -> Result<Response> {
+        let now = Utc::now();
+        let pingtime = now - req.time;
+
+        Ok(Response {
+            msg: format!("pong! {}ms", pingtime.num_milliseconds()),
+            spid: None,
+            error: false,
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct CmdStatus {
+    pub pid: u32,
+}
+
+impl From<&ArgMatches<'_>> for CmdStatus {
+    fn from(matches: &ArgMatches) -> Self {
+        let pid: u32 = matches
+            .value_of("pid")
+            .unwrap()
+            .parse()
+            .expect("failed to parse pid as integer!");
+        CmdStatus { pid }
+    }
+}
+
+#[typetag::serde]
+impl Action for CmdStatus {
+    fn permission(&self) -> Permission {
+        Permission::Read
+    }
+
+    fn execute(&self, _req: &Request, ctx: &mut CommandContext) -> Result<Response> {
+        Ok(match ctx.prochandler.get_process_status(self.pid) {
+            Some(status) => Response {
+                msg: format!("{}", status),
+                spid: None,
+                error: false,
+            },
+            None => Response {
+                msg: format!("no process found with pid {}", self.pid),
+                spid: None,
+                error: false,
+            },
         })
     }
 }
@@ -198,15 +1320,43 @@ pub struct CmdList;
 
 #[typetag::serde]
 impl Action for CmdList {
+    fn permission(&self) -> Permission {
+        Permission::Read
+    }
+
     fn execute(&self, _req: &Request, ctx: &mut CommandContext) -> Result<Response> {
-        let mut msg = format!("list of processes:\n");
+        let mut msg = String::from("list of processes:\n");
         
         for proc in ctx.prochandler.all_processes() {
-            writeln!(&mut msg, "  SPID: {} - {}", proc.pid, proc.cmdline.to_str().unwrap())?;
+            match proc.name() {
+                Some(name) => writeln!(
+                    &mut msg,
+                    "  SPID: {} - [{}] {}",
+                    proc.pid,
+                    name,
+                    proc.cmdline.to_str().unwrap()
+                )?,
+                None => writeln!(
+                    &mut msg,
+                    "  SPID: {} - {}",
+                    proc.pid,
+                    proc.cmdline.to_str().unwrap()
+                )?,
+            }
+        }
+        for def in ctx.prochandler.pending() {
+            writeln!(
+                &mut msg,
+                "  pending  - [{}] {}",
+                def.name,
+                def.cmd.join(" ")
+            )?;
         }
 
         Ok(Response {
             msg,
+            spid: None,
+            error: false,
         })
     }
 }
@@ -214,6 +1364,9 @@ impl Action for CmdList {
 #[derive(Serialize, Deserialize)]
 pub struct CmdLog {
     pub pid: u32,
+    /// keep streaming what the process writes until it exits
+    #[serde(default)]
+    pub follow: bool,
 }
 
 impl From<&ArgMatches<'_>> for CmdLog {
@@ -222,19 +1375,34 @@ impl From<&ArgMatches<'_>> for CmdLog {
             .unwrap()
             .parse()
             .expect("failed to parse pid as integer!");
-        CmdLog { pid, } 
+        CmdLog { pid, follow: matches.is_present("follow"), } 
     }
 }
 
 #[typetag::serde]
 impl Action for CmdLog {
+    fn permission(&self) -> Permission {
+        Permission::Read
+    }
+
     fn execute(&self, _req: &Request, ctx: &mut CommandContext) -> Result<Response> {
         let proc = match ctx.prochandler.get_process_by_pid(self.pid) {
             Some(proc) => proc,
+            None if self.follow => bail!("no process with SPID {}", self.pid),
             None => return Ok(Response {
-                msg: format!("no process with SPID {}", self.pid)
+                msg: format!("no process with SPID {}", self.pid),
+                spid: None,
+                error: false,
             }),
         };
+        // the log is streamed instead
+        if self.follow {
+            return Ok(Response {
+                msg: String::new(),
+                spid: Some(self.pid),
+                error: false,
+            });
+        }
 
         let mut logfile = OpenOptions::new()
             .read(true)
@@ -248,7 +1416,1368 @@ impl Action for CmdLog {
 
         Ok(Response {
             msg: String::from_utf8(msg)?,
+            spid: None,
+            error: false,
+        })
+
+    }
+
+    fn stream(
+        &self,
+        _req: &Request,
+        _res: &Response,
+        ctx: &SharedContext,
+        client: &mut Client,
+    ) -> Result<()> {
+        if !self.follow {
+            return Ok(());
+        }
+        stream_log(self.pid, ctx, client, false, &AtomicBool::new(false))
+    }
+}
+
+/// command-structure for the `grep` command
+///
+/// searches the logs in the log directory, including rotated and compressed
+/// generations, and streams the matching lines to the client as they are found
+#[derive(Serialize, Deserialize)]
+pub struct CmdGrep {
+    /// a regular expression
+    pub pattern: String,
+    /// only the logs of processes with this definition name or program
+    pub process: Option<String>,
+    /// only logs written to after this time
+    pub since: Option<DateTime<Utc>>,
+    /// only logs created before this time
+    pub until: Option<DateTime<Utc>>,
+    /// how many lines to show before and after each match
+    pub context: usize,
+    pub ignore_case: bool,
+}
+
+impl CmdGrep {
+    /// builds the command from clap's ArgMatches, resolving relative times against the current one
+    pub fn from_matches(matches: &ArgMatches) -> Result<Self> {
+        let time = |name: &str| matches.value_of(name).map(parse_time).transpose();
+        let context = matches.value_of("context").unwrap();
+        Ok(CmdGrep {
+            pattern: matches.value_of("pattern").unwrap().to_string(),
+            process: matches.value_of("process").map(String::from),
+            since: time("since")?,
+            until: time("until")?,
+            context: context
+                .parse()
+                .with_context(|| format!("invalid number of context lines '{}'", context))?,
+            ignore_case: matches.is_present("ignore-case"),
+        })
+    }
+
+    fn regex(&self) -> Result<Regex> {
+        RegexBuilder::new(&self.pattern)
+            .case_insensitive(self.ignore_case)
+            .build()
+            .with_context(|| format!("invalid pattern '{}'", self.pattern))
+    }
+}
+
+#[typetag::serde]
+impl Action for CmdGrep {
+    fn permission(&self) -> Permission {
+        Permission::Read
+    }
+
+    fn execute(&self, _req: &Request, _ctx: &mut CommandContext) -> Result<Response> {
+        // checked here, so that a bad pattern is reported instead of an empty search
+        self.regex()?;
+        Ok(Response {
+            msg: String::from("searching logs"),
+            spid: None,
+            error: false,
+        })
+    }
+
+    fn stream(
+        &self,
+        _req: &Request,
+        _res: &Response,
+        ctx: &SharedContext,
+        client: &mut Client,
+    ) -> Result<()> {
+        let regex = self.regex()?;
+        // the search reads files for a while, so the lock is only held for finding them
+        let logs = {
+            let ctx = ctx.lock().unwrap();
+            let known: HashMap<PathBuf, String> = ctx
+                .prochandler
+                .all_processes()
+                .iter()
+                .filter_map(|proc| Some((proc.log_file.clone(), proc.display_name()?)))
+                .collect();
+            search::find_logs(ctx.loghandler.log_directory(), &known)?
+        };
+
+        let mut searched = 0;
+        let mut matched_logs = 0;
+        let mut matches = 0;
+        let mut output = String::new();
+        let mut disconnected = false;
+        for log in &logs {
+            if self
+                .process
+                .as_ref()
+                .is_some_and(|name| &log.process != name)
+                || !log.overlaps(self.since, self.until)
+            {
+                continue;
+            }
+            searched += 1;
+
+            // like grep, groups from different logs are separated when showing context
+            let mut separate = self.context > 0 && matched_logs > 0;
+            let mut out = |line: &str| -> Result<()> {
+                if std::mem::take(&mut separate) {
+                    output.push_str("--\n");
+                }
+                output.push_str(line);
+                output.push('\n');
+                // matches are sent in batches rather than line by line
+                if output.len() >= 8192 {
+                    let batch = std::mem::take(&mut output).into_bytes();
+                    if let Err(e) = client.send_frame(&StreamFrame::Output(batch)) {
+                        disconnected = true;
+                        return Err(e);
+                    }
+                }
+                Ok(())
+            };
+            match search::search_log(log, &regex, self.context, &mut out) {
+                Ok(0) => {}
+                Ok(found) => {
+                    matched_logs += 1;
+                    matches += found;
+                }
+                // the client went away, e.g. because its output was piped to `head`
+                Err(_) if disconnected => return Ok(()),
+                Err(e) => warn!("failed to search {}: {:#}", log.path.display(), e),
+            }
+        }
+
+        writeln!(
+            &mut output,
+            "{} matching lines in {} of {} searched logs",
+            matches, matched_logs, searched
+        )?;
+        client.send_frame(&StreamFrame::Output(output.into_bytes()))?;
+        client.send_frame(&StreamFrame::End(None))
+    }
+}
+
+/// command-structure for the `up` command
+///
+/// queues a set of process definitions, which the daemon
+/// starts in dependency order as their dependencies become ready
+#[derive(Serialize, Deserialize)]
+pub struct CmdUp {
+    pub definitions: Vec<ProcessDefinition>,
+}
+
+impl CmdUp {
+    /// reads the definitions for this command from a toml file
+    pub fn from_file(path: &Path) -> Result<Self> {
+        Ok(CmdUp {
+            definitions: definitions::load(path)?,
+        })
+    }
+}
+
+#[typetag::serde]
+impl Action for CmdUp {
+    fn permission(&self) -> Permission {
+        Permission::Run
+    }
+
+    fn execute(&self, _req: &Request, ctx: &mut CommandContext) -> Result<Response> {
+        let mut defs = self.definitions.clone();
+        for def in &mut defs {
+            definitions::validate(def)?;
+        }
+        let order = definitions::start_order(&defs)?;
+
+        for def in &order {
+            if ctx.prochandler.pending().iter().any(|p| p.name == def.name) {
+                bail!("process '{}' is already waiting to start", def.name);
+            }
+            if let Some(proc) = ctx.prochandler.get_process_by_name(&def.name) {
+                if let ProcessWaitStatus::Running(_) = proc.wait_status() {
+                    bail!(
+                        "process '{}' is already running (spid {})",
+                        def.name,
+                        proc.pid
+                    );
+                }
+            }
+            for dep in &def.depends_on {
+                let known = self.definitions.iter().any(|d| d.name == dep.name)
+                    || ctx.prochandler.pending().iter().any(|d| d.name == dep.name)
+                    || ctx.prochandler.get_process_by_name(&dep.name).is_some();
+                if !known {
+                    bail!(
+                        "process '{}' depends on unknown process '{}'",
+                        def.name,
+                        dep.name
+                    );
+                }
+            }
+        }
+
+        let names: Vec<&str> = order.iter().map(|def| def.name.as_str()).collect();
+        let msg = format!("queued processes in start order: {}", names.join(", "));
+        ctx.prochandler
+            .queue_definitions(order.into_iter().cloned());
+
+        Ok(Response {
+            msg,
+            spid: None,
+            error: false,
+        })
+    }
+}
+
+/// command-structure for the `down` command
+///
+/// stops named processes and the processes depending on them in reverse
+/// dependency order, or every named process if no names are given
+#[derive(Serialize, Deserialize)]
+pub struct CmdDown {
+    pub names: Vec<String>,
+}
+
+impl From<&ArgMatches<'_>> for CmdDown {
+    fn from(matches: &ArgMatches) -> Self {
+        let names = matches
+            .values_of("names")
+            .map(|names| names.map(String::from).collect())
+            .unwrap_or_default();
+        CmdDown { names }
+    }
+}
+
+#[typetag::serde]
+impl Action for CmdDown {
+    fn permission(&self) -> Permission {
+        Permission::Manage
+    }
+
+    fn execute(&self, _req: &Request, ctx: &mut CommandContext) -> Result<Response> {
+        let names = ctx.with_dependents(|name| self.wants(name));
+        let mut msg = String::new();
+
+        let cancelled = ctx
+            .prochandler
+            .take_pending(|def| names.contains(&def.name));
+        for def in cancelled {
+            writeln!(&mut msg, "cancelled pending process '{}'", def.name)?;
+        }
+        Ok(Response {
+            msg: msg.trim_end().to_string(),
+            spid: None,
+            error: false,
+        })
+    }
+
+    /// stops the processes one at a time, reporting each as it has stopped
+    fn stream(
+        &self,
+        _req: &Request,
+        res: &Response,
+        ctx: &SharedContext,
+        client: &mut Client,
+    ) -> Result<()> {
+        let result = (|| {
+            let (stopping, grace) = {
+                let mut ctx = ctx.lock().unwrap();
+                let names = ctx.with_dependents(|name| self.wants(name));
+                (
+                    ctx.stop_order(|name| names.contains(name))?,
+                    ctx.config.stop_timeout(),
+                )
+            };
+
+            let mut msg = String::new();
+            for (name, pid) in &stopping {
+                stop_process(ctx, *pid, grace)?;
+                let line = format!("stopped '{}' (spid {})\n", name, pid);
+                let _ = client.send_frame(&StreamFrame::Output(line.into_bytes()));
+            }
+            if stopping.is_empty() && res.msg.is_empty() {
+                msg.push_str("nothing to stop\n");
+            }
+            Ok(msg)
+        })();
+        finish_stream(client, result)
+    }
+}
+
+impl CmdDown {
+    fn wants(&self, name: &str) -> bool {
+        self.names.is_empty() || self.names.iter().any(|n| n == name)
+    }
+}
+
+/// command-structure for the `schedule` command
+///
+/// registers a job that the daemon runs on a cron expression or a fixed
+/// interval, logged the same way as the `once` command
+#[derive(Serialize, Deserialize)]
+pub struct CmdSchedule {
+    pub program: OsString,
+    pub args: Vec<OsString>,
+    pub trigger: Trigger,
+    pub overlap: OverlapPolicy,
+}
+
+impl CmdSchedule {
+    /// builds the command from clap's ArgMatches, validating the trigger and overlap policy
+    pub fn from_matches(matches: &ArgMatches) -> Result<Self> {
+        let once = CmdOnce::from_matches(matches)?;
+        let trigger = match (matches.value_of("cron"), matches.value_of("every")) {
+            (Some(expr), None) => Trigger::Cron(expr.to_string()),
+            (None, Some(every)) => {
+                let every = parse_duration(every)?;
+                if every.is_zero() {
+                    bail!("the interval must be longer than zero");
+                }
+                Trigger::Interval(every)
+            }
+            _ => bail!("exactly one of --cron or --every is required"),
+        };
+        let overlap = matches.value_of("overlap").unwrap_or("skip").parse()?;
+
+        Ok(CmdSchedule {
+            program: once.program,
+            args: once.args,
+            trigger,
+            overlap,
+        })
+    }
+}
+
+#[typetag::serde]
+impl Action for CmdSchedule {
+    fn permission(&self) -> Permission {
+        Permission::Run
+    }
+
+    fn execute(&self, _req: &Request, ctx: &mut CommandContext) -> Result<Response> {
+        let id = ctx.schedhandler.add_job(
+            self.program.clone(),
+            self.args.clone(),
+            self.trigger.clone(),
+            self.overlap,
+        )?;
+
+        Ok(Response {
+            msg: format!("scheduled job {} to run {}", id, self.trigger),
+            spid: None,
+            error: false,
+        })
+    }
+}
+
+/// command-structure for the `schedules` command
+///
+/// lists every scheduled job along with its run history
+#[derive(Serialize, Deserialize)]
+pub struct CmdSchedules;
+
+#[typetag::serde]
+impl Action for CmdSchedules {
+    fn permission(&self) -> Permission {
+        Permission::Read
+    }
+
+    fn execute(&self, _req: &Request, ctx: &mut CommandContext) -> Result<Response> {
+        let mut msg = String::from("list of schedules:\n");
+
+        for job in ctx.schedhandler.all_jobs() {
+            writeln!(&mut msg, "  job {} - {}", job.id, job.cmdline())?;
+            writeln!(
+                &mut msg,
+                "    trigger   : {} (overlap: {})",
+                job.trigger, job.overlap
+            )?;
+            match job.next_run {
+                Some(next) => writeln!(&mut msg, "    next run  : {}", next)?,
+                None => writeln!(&mut msg, "    next run  : never")?,
+            }
+            match job.last_run {
+                Some(last) => writeln!(&mut msg, "    last run  : {}", last)?,
+                None => writeln!(&mut msg, "    last run  : never")?,
+            }
+            if !job.running.is_empty() {
+                writeln!(&mut msg, "    running   : {:?}", job.running)?;
+            }
+            if let Some(exit) = &job.last_exit {
+                writeln!(&mut msg, "    last exit : {}", exit)?;
+            }
+        }
+
+        Ok(Response {
+            msg,
+            spid: None,
+            error: false,
+        })
+    }
+}
+
+/// command-structure for the `unschedule` command
+///
+/// removes a scheduled job without touching runs that are still going
+#[derive(Serialize, Deserialize)]
+pub struct CmdUnschedule {
+    pub id: JobID,
+}
+
+impl CmdUnschedule {
+    /// builds the command from clap's ArgMatches, parsing the job id
+    pub fn from_matches(matches: &ArgMatches) -> Result<Self> {
+        let id = matches
+            .value_of("id")
+            .unwrap()
+            .parse()
+            .context("failed to parse job id as integer")?;
+        Ok(CmdUnschedule { id })
+    }
+}
+
+#[typetag::serde]
+impl Action for CmdUnschedule {
+    fn permission(&self) -> Permission {
+        Permission::Manage
+    }
+
+    fn execute(&self, _req: &Request, ctx: &mut CommandContext) -> Result<Response> {
+        let msg = if ctx.schedhandler.remove_job(self.id) {
+            format!("removed job {}", self.id)
+        } else {
+            format!("no job found with id {}", self.id)
+        };
+
+        Ok(Response {
+            msg,
+            spid: None,
+            error: false,
+        })
+    }
+}
+
+/// command-structure for the `run` command
+///
+/// starts a program exactly like `once`, then streams its
+/// output to the client until it exits
+#[derive(Serialize, Deserialize)]
+pub struct CmdRun {
+    pub process: CmdOnce,
+}
+
+impl CmdRun {
+    /// builds the command from clap's ArgMatches, the same way as `CmdOnce`
+    pub fn from_matches(matches: &ArgMatches) -> Result<Self> {
+        Ok(CmdRun {
+            process: CmdOnce::from_matches(matches)?,
         })
+    }
+}
+
+#[typetag::serde]
+impl Action for CmdRun {
+    fn permission(&self) -> Permission {
+        Permission::Run
+    }
 
+    fn execute(&self, req: &Request, ctx: &mut CommandContext) -> Result<Response> {
+        self.process.execute(req, ctx)
     }
-}
\ No newline at end of file
+
+    fn stream(
+        &self,
+        _req: &Request,
+        res: &Response,
+        ctx: &SharedContext,
+        client: &mut Client,
+    ) -> Result<()> {
+        stream_log(
+            res.spid.unwrap(),
+            ctx,
+            client,
+            false,
+            &AtomicBool::new(false),
+        )
+    }
+}
+
+/// follows the log file of a process, sending everything written to it
+/// as Output frames, then sends an End frame once the process exits
+/// # Arguments
+/// * `pid` - the process to follow
+/// * `ctx` - the shared command context, locked briefly to poll the process
+/// * `client` - the client to stream to
+/// * `from_end` - skip whatever was already in the log before streaming started
+/// * `detached` - stops the stream without an End frame once set
+fn stream_log(
+    pid: SibylPID,
+    ctx: &SharedContext,
+    client: &mut Client,
+    from_end: bool,
+    detached: &AtomicBool,
+) -> Result<()> {
+    let log_path = ctx
+        .lock()
+        .unwrap()
+        .prochandler
+        .get_process_by_pid(pid)
+        .map(|proc| proc.log_file.clone())
+        .context("process disappeared before its log could be streamed")?;
+    let mut log = File::open(&log_path).context("failed to open logfile")?;
+    if from_end {
+        log.seek(SeekFrom::End(0))?;
+    }
+    let mut buffer = vec![0; 8192];
+
+    loop {
+        // poll before draining the log, so that nothing written
+        // between the two is lost once the process has exited
+        let status = ctx
+            .lock()
+            .unwrap()
+            .prochandler
+            .get_process_status(pid)
+            .map(|status| status.status);
+
+        loop {
+            let read = log.read(&mut buffer)?;
+            if read == 0 {
+                break;
+            }
+            client.send_frame(&StreamFrame::Output(buffer[..read].to_vec()))?;
+        }
+
+        if detached.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+        match status {
+            Some(ProcessWaitStatus::Running(_)) => thread::sleep(Duration::from_millis(50)),
+            Some(ProcessWaitStatus::Exited(code)) | Some(ProcessWaitStatus::TimedOut(code)) => {
+                // like a shell, report a process killed by a signal as 128 + the signal
+                let code = code.or_else(|| {
+                    let mut ctx = ctx.lock().unwrap();
+                    let proc = ctx.prochandler.get_process_by_pid_mut(pid)?;
+                    proc.child.exit_signal().map(|signal| 128 + signal)
+                });
+                return client.send_frame(&StreamFrame::End(code));
+            }
+            _ => return client.send_frame(&StreamFrame::End(None)),
+        }
+    }
+}
+
+/// command-structure for the `signal` command
+///
+/// sends a unix signal to a running process
+#[derive(Serialize, Deserialize)]
+pub struct CmdSignal {
+    pub pid: u32,
+    pub signal: i32,
+}
+
+impl CmdSignal {
+    /// builds the command from clap's ArgMatches, parsing the signal
+    pub fn from_matches(matches: &ArgMatches) -> Result<Self> {
+        let pid = matches
+            .value_of("pid")
+            .unwrap()
+            .parse()
+            .context("failed to parse pid as integer")?;
+        let signal = parse_signal(matches.value_of("signal").unwrap())?;
+        Ok(CmdSignal { pid, signal })
+    }
+}
+
+#[typetag::serde]
+impl Action for CmdSignal {
+    fn permission(&self) -> Permission {
+        Permission::Manage
+    }
+
+    fn execute(&self, _req: &Request, ctx: &mut CommandContext) -> Result<Response> {
+        let msg = if ctx.prochandler.signal_process(self.pid, self.signal)? {
+            format!("sent signal {} to SPID {}", self.signal, self.pid)
+        } else {
+            format!("no running process with SPID {}", self.pid)
+        };
+
+        Ok(Response {
+            msg,
+            spid: None,
+            error: false,
+        })
+    }
+}
+
+/// command-structure for the `stop` command
+///
+/// stops a single process the way `down` stops definitions, and keeps
+/// the supervisor from restarting it
+#[derive(Serialize, Deserialize)]
+pub struct CmdStop {
+    pub pid: u32,
+}
+
+impl CmdStop {
+    /// builds the command from clap's ArgMatches, parsing the pid
+    pub fn from_matches(matches: &ArgMatches) -> Result<Self> {
+        let pid = matches
+            .value_of("pid")
+            .unwrap()
+            .parse()
+            .context("failed to parse pid as integer")?;
+        Ok(CmdStop { pid })
+    }
+}
+
+#[typetag::serde]
+impl Action for CmdStop {
+    fn permission(&self) -> Permission {
+        Permission::Manage
+    }
+
+    /// sends the process SIGTERM, `stream` waits for it to exit
+    fn execute(&self, _req: &Request, ctx: &mut CommandContext) -> Result<Response> {
+        if ctx.prochandler.get_process_by_pid(self.pid).is_none() {
+            bail!("no process with SPID {}", self.pid);
+        }
+
+        let (msg, spid) = if ctx.prochandler.terminate_process(self.pid)? {
+            (String::new(), Some(self.pid))
+        } else {
+            (format!("SPID {} had already exited", self.pid), None)
+        };
+        Ok(Response {
+            msg,
+            spid,
+            error: false,
+        })
+    }
+
+    fn stream(
+        &self,
+        _req: &Request,
+        res: &Response,
+        ctx: &SharedContext,
+        client: &mut Client,
+    ) -> Result<()> {
+        if res.spid.is_none() {
+            return finish_stream(client, Ok(String::new()));
+        }
+        let grace = ctx.lock().unwrap().config.stop_timeout();
+        let result =
+            wait_for_stop(ctx, self.pid, grace).map(|_| format!("stopped SPID {}\n", self.pid));
+        finish_stream(client, result)
+    }
+}
+
+/// command-structure for the `reset` command
+///
+/// forgets the failures of a crash-looping definition, so that it is
+/// restarted normally again, including after it has been quarantined
+#[derive(Serialize, Deserialize)]
+pub struct CmdReset {
+    pub name: String,
+}
+
+impl From<&ArgMatches<'_>> for CmdReset {
+    fn from(matches: &ArgMatches) -> Self {
+        CmdReset {
+            name: matches.value_of("name").unwrap().to_string(),
+        }
+    }
+}
+
+#[typetag::serde]
+impl Action for CmdReset {
+    fn permission(&self) -> Permission {
+        Permission::Manage
+    }
+
+    fn execute(&self, _req: &Request, ctx: &mut CommandContext) -> Result<Response> {
+        let state = ctx
+            .prochandler
+            .crash_loop(&self.name)
+            .map(|crash| crash.state)
+            .unwrap_or_default();
+        let msg = match state {
+            CrashState::Stable => bail!("'{}' is not crash-looping", self.name),
+            CrashState::Backoff { .. } => {
+                format!("'{}' is no longer backing off its restarts", self.name)
+            }
+            CrashState::Quarantined { .. } => {
+                format!("'{}' is no longer quarantined", self.name)
+            }
+        };
+        ctx.prochandler.reset_crash_loop(&self.name);
+        info!("reset the crash loop of '{}'", self.name);
+
+        Ok(Response {
+            msg,
+            spid: ctx
+                .prochandler
+                .get_process_by_name(&self.name)
+                .map(|p| p.pid),
+            error: false,
+        })
+    }
+}
+
+/// command-structure for the `restart` command
+///
+/// stops a process the way `stop` does, then starts it again
+/// from its definition, or with the program and options it was started with
+#[derive(Serialize, Deserialize)]
+pub struct CmdRestart {
+    pub pid: u32,
+}
+
+impl CmdRestart {
+    /// builds the command from clap's ArgMatches, parsing the pid
+    pub fn from_matches(matches: &ArgMatches) -> Result<Self> {
+        let pid = matches
+            .value_of("pid")
+            .unwrap()
+            .parse()
+            .context("failed to parse pid as integer")?;
+        Ok(CmdRestart { pid })
+    }
+}
+
+#[typetag::serde]
+impl Action for CmdRestart {
+    fn permission(&self) -> Permission {
+        Permission::Manage
+    }
+
+    fn execute(&self, _req: &Request, ctx: &mut CommandContext) -> Result<Response> {
+        let proc = match ctx.prochandler.get_process_by_pid(self.pid) {
+            Some(proc) => proc,
+            None => bail!("no process with SPID {}", self.pid),
+        };
+        if proc.definition.is_none() && proc.command.is_none() {
+            bail!(
+                "SPID {} was adopted from a previous daemon without a definition, \
+                 so it can't be started again",
+                self.pid
+            );
+        }
+        // the supervisor must not start another instance in its place
+        ctx.prochandler
+            .get_process_by_pid_mut(self.pid)
+            .unwrap()
+            .replaced = true;
+        ctx.prochandler.terminate_process(self.pid)?;
+
+        Ok(Response {
+            msg: String::new(),
+            spid: Some(self.pid),
+            error: false,
+        })
+    }
+
+    /// waits for the process to exit, then starts it again
+    fn stream(
+        &self,
+        _req: &Request,
+        _res: &Response,
+        ctx: &SharedContext,
+        client: &mut Client,
+    ) -> Result<()> {
+        let grace = ctx.lock().unwrap().config.stop_timeout();
+        let result = wait_for_stop(ctx, self.pid, grace)
+            .and_then(|_| self.relaunch(&mut ctx.lock().unwrap()));
+        finish_stream(client, result.map(|msg| msg + "\n"))
+    }
+}
+
+impl CmdRestart {
+    /// starts a stopped process again, the way it was started before
+    fn relaunch(&self, ctx: &mut CommandContext) -> Result<String> {
+        let proc = match ctx.prochandler.get_process_by_pid(self.pid) {
+            Some(proc) => proc,
+            None => bail!("SPID {} disappeared while restarting it", self.pid),
+        };
+        let definition = proc.definition.clone();
+        let once = proc.command.as_ref().map(|command| CmdOnce {
+            program: command.get_program().to_os_string(),
+            args: command.get_args().map(OsStr::to_os_string).collect(),
+            options: proc.options.clone(),
+        });
+        let restarts = proc.restarts + 1;
+
+        let pid = match (&definition, &once) {
+            (Some(def), _) => ctx.launch(
+                def,
+                &def.program(),
+                &def.args(),
+                Some(def.clone()),
+                &def.launch_options(),
+            )?,
+            (None, Some(once)) => {
+                ctx.launch(once, &once.program, &once.args, None, &once.options)?
+            }
+            (None, None) => unreachable!(),
+        };
+        let proc = ctx.prochandler.get_process_by_pid_mut(pid).unwrap();
+        proc.restarts = restarts;
+        let name = proc.display_name().unwrap_or_default();
+        ctx.emit(EventKind::Restarted {
+            name,
+            previous_spid: self.pid,
+            spid: pid,
+            restarts,
+        });
+
+        Ok(format!("restarted SPID {} as SPID {}", self.pid, pid))
+    }
+}
+
+/// command-structure for the `attach` command
+///
+/// connects the client's terminal to the stdin and output of a process
+/// started with a stdin pipe, until the client detaches or the process exits
+#[derive(Serialize, Deserialize)]
+pub struct CmdAttach {
+    pub pid: u32,
+}
+
+impl CmdAttach {
+    /// builds the command from clap's ArgMatches, parsing the pid
+    pub fn from_matches(matches: &ArgMatches) -> Result<Self> {
+        let pid = matches
+            .value_of("pid")
+            .unwrap()
+            .parse()
+            .context("failed to parse pid as integer")?;
+        Ok(CmdAttach { pid })
+    }
+}
+
+#[typetag::serde]
+impl Action for CmdAttach {
+    fn permission(&self) -> Permission {
+        Permission::Manage
+    }
+
+    fn execute(&self, _req: &Request, ctx: &mut CommandContext) -> Result<Response> {
+        let proc = match ctx.prochandler.get_process_by_pid(self.pid) {
+            Some(proc) => proc,
+            None => bail!("no process with SPID {}", self.pid),
+        };
+        if proc.stdin.is_none() {
+            bail!(
+                "SPID {} has no stdin pipe, it must be started with --stdin or --pty",
+                self.pid
+            );
+        }
+        if let Some(status) = ctx.prochandler.get_process_status(self.pid) {
+            if !matches!(status.status, ProcessWaitStatus::Running(_)) {
+                bail!("SPID {} is not running", self.pid);
+            }
+        }
+
+        Ok(Response {
+            msg: format!(
+                "attached to SPID {}, type ~. at the start of a line to detach",
+                self.pid
+            ),
+            spid: Some(self.pid),
+            error: false,
+        })
+    }
+
+    fn stream(
+        &self,
+        _req: &Request,
+        _res: &Response,
+        ctx: &SharedContext,
+        client: &mut Client,
+    ) -> Result<()> {
+        let detached = Arc::new(AtomicBool::new(false));
+        let mut input = client.try_clone()?;
+        let (stdin, pty) = {
+            let ctx = ctx.lock().unwrap();
+            let proc = ctx
+                .prochandler
+                .get_process_by_pid(self.pid)
+                .context("process disappeared before it could be attached to")?;
+            let pty = match &proc.pty {
+                Some(master) => Some(master.try_clone()?),
+                None => None,
+            };
+            (
+                proc.stdin.clone().context("process lost its stdin pipe")?,
+                pty,
+            )
+        };
+        client.send_frame(&StreamFrame::Attached { pty: pty.is_some() })?;
+
+        // input is forwarded on its own thread, since the client can
+        // type at any time while output is being streamed
+        let input_detached = Arc::clone(&detached);
+        thread::spawn(move || {
+            loop {
+                match input.receive_client_frame() {
+                    Ok(StreamFrame::Input(bytes)) => {
+                        if stdin.write(bytes).is_err() {
+                            break;
+                        }
+                    }
+                    Ok(StreamFrame::Resize { rows, cols }) => {
+                        if let Some(master) = &pty {
+                            resize_pty(master, rows, cols);
+                        }
+                    }
+                    _ => break,
+                }
+            }
+            input_detached.store(true, Ordering::SeqCst);
+        });
+
+        stream_log(self.pid, ctx, client, true, &detached)
+    }
+}
+
+#[cfg(unix)]
+fn resize_pty(master: &File, rows: u16, cols: u16) {
+    if let Err(e) = pty::set_window_size(master, rows, cols) {
+        warn!("failed to resize pty: {}", e);
+    }
+}
+
+#[cfg(not(unix))]
+fn resize_pty(_master: &File, _rows: u16, _cols: u16) {}
+
+/// command-structure for the `send` command
+///
+/// writes bytes to the stdin pipe of a process started with --stdin
+#[derive(Serialize, Deserialize)]
+pub struct CmdSend {
+    pub pid: u32,
+    pub input: Vec<u8>,
+}
+
+impl CmdSend {
+    /// builds the command from clap's ArgMatches, reading the input file if one was given
+    pub fn from_matches(matches: &ArgMatches) -> Result<Self> {
+        let pid = matches.value_of("pid").unwrap().parse()?;
+        let input = match (matches.value_of("text"), matches.value_of("file")) {
+            (_, Some(path)) => {
+                fs::read(path).with_context(|| format!("failed to read input from {}", path))?
+            }
+            (Some(text), None) => {
+                let mut input = text.as_bytes().to_vec();
+                // a trailing newline is what submits a line to most console programs
+                if !matches.is_present("no-newline") && !input.ends_with(b"\n") {
+                    input.push(b'\n');
+                }
+                input
+            }
+            (None, None) => bail!("either text or --file is required"),
+        };
+
+        Ok(CmdSend { pid, input })
+    }
+}
+
+#[typetag::serde]
+impl Action for CmdSend {
+    fn permission(&self) -> Permission {
+        Permission::Manage
+    }
+
+    fn redacted_fields(&self) -> &'static [&'static str] {
+        &["input"]
+    }
+
+    fn execute(&self, _req: &Request, ctx: &mut CommandContext) -> Result<Response> {
+        let proc = match ctx.prochandler.get_process_by_pid(self.pid) {
+            Some(proc) => proc,
+            None => bail!("no process with SPID {}", self.pid),
+        };
+        let stdin = match &proc.stdin {
+            Some(stdin) => stdin,
+            None => bail!(
+                "SPID {} has no stdin pipe, it must be started with --stdin or --pty",
+                self.pid
+            ),
+        };
+        stdin.write(self.input.clone())?;
+
+        Ok(Response {
+            msg: format!("sent {} bytes to SPID {}", self.input.len(), self.pid),
+            spid: None,
+            error: false,
+        })
+    }
+}
+
+/// command-structure for the `audit` command
+///
+/// reads the requests recorded in the daemon's audit log
+#[derive(Serialize, Deserialize)]
+pub struct CmdAudit {
+    pub filter: AuditFilter,
+    /// return the records as the JSON lines they are stored as
+    pub json: bool,
+}
+
+impl CmdAudit {
+    /// builds the command from clap's ArgMatches, resolving relative times against the current one
+    pub fn from_matches(matches: &ArgMatches) -> Result<Self> {
+        let time = |name: &str| matches.value_of(name).map(parse_time).transpose();
+        Ok(CmdAudit {
+            filter: AuditFilter {
+                since: time("since")?,
+                until: time("until")?,
+                process: matches.value_of("process").map(String::from),
+            },
+            json: matches.is_present("json"),
+        })
+    }
+}
+
+#[typetag::serde]
+impl Action for CmdAudit {
+    fn permission(&self) -> Permission {
+        Permission::Admin
+    }
+
+    fn execute(&self, _req: &Request, _ctx: &mut CommandContext) -> Result<Response> {
+        Ok(Response {
+            msg: String::new(),
+            spid: None,
+            error: false,
+        })
+    }
+
+    /// sends the matching records, read without holding the lock since the log can be large
+    fn stream(
+        &self,
+        _req: &Request,
+        _res: &Response,
+        ctx: &SharedContext,
+        client: &mut Client,
+    ) -> Result<()> {
+        let path = ctx.lock().unwrap().config.audit_log.clone();
+        let result = (|| {
+            let (records, unreadable) = audit::read(&path, &self.filter)?;
+            if unreadable > 0 {
+                warn!(
+                    "skipped {} unreadable lines in audit log {}",
+                    unreadable,
+                    path.display()
+                );
+            }
+
+            let mut output = String::new();
+            for record in &records {
+                if self.json {
+                    writeln!(&mut output, "{}", serde_json::to_string(record)?)?;
+                } else {
+                    writeln!(&mut output, "{}", record)?;
+                }
+                // records are sent in batches rather than one by one
+                if output.len() >= 8192 {
+                    let batch = std::mem::take(&mut output).into_bytes();
+                    client.send_frame(&StreamFrame::Output(batch))?;
+                }
+            }
+            if records.is_empty() && !self.json {
+                output.push_str("no matching requests\n");
+            }
+            Ok(output)
+        })();
+        finish_stream(client, result)
+    }
+}
+
+/// parses a point in time given as RFC 3339, e.g. `2024-05-01T12:00:00Z`,
+/// or as a duration before now, e.g. `2h`
+fn parse_time(s: &str) -> Result<DateTime<Utc>> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(s) {
+        return Ok(time.with_timezone(&Utc));
+    }
+    let ago = parse_duration(s)
+        .with_context(|| format!("'{}' is neither an RFC 3339 time nor a duration", s))?;
+    chrono::Duration::from_std(ago)
+        .ok()
+        .and_then(|ago| Utc::now().checked_sub_signed(ago))
+        .with_context(|| format!("'{}' is too long ago", s))
+}
+
+/// command-structure for the `metrics` command
+///
+/// reports per-process and daemon-wide metrics in the Prometheus text format
+#[derive(Serialize, Deserialize)]
+pub struct CmdMetrics;
+
+#[typetag::serde]
+impl Action for CmdMetrics {
+    fn permission(&self) -> Permission {
+        Permission::Read
+    }
+
+    /// scrapers ask every few seconds, which would drown out everything else in the audit log
+    fn audited(&self) -> bool {
+        false
+    }
+
+    fn execute(&self, _req: &Request, ctx: &mut CommandContext) -> Result<Response> {
+        Ok(Response {
+            msg: metrics::render(ctx),
+            spid: None,
+            error: false,
+        })
+    }
+}
+
+/// command-structure for the `events` command
+///
+/// keeps the connection open and streams lifecycle events to the client as they happen
+#[derive(Serialize, Deserialize)]
+pub struct CmdEvents {
+    pub filter: EventFilter,
+    /// send each event as a line of JSON rather than text
+    pub json: bool,
+}
+
+impl CmdEvents {
+    /// builds the command from clap's ArgMatches, checking the event types
+    pub fn from_matches(matches: &ArgMatches) -> Result<Self> {
+        let values = |name: &str| -> Vec<String> {
+            matches
+                .values_of(name)
+                .map(|values| values.map(String::from).collect())
+                .unwrap_or_default()
+        };
+        Ok(CmdEvents {
+            filter: EventFilter::new(values("name"), values("type"))?,
+            json: matches.is_present("json"),
+        })
+    }
+}
+
+#[typetag::serde]
+impl Action for CmdEvents {
+    fn permission(&self) -> Permission {
+        Permission::Read
+    }
+
+    fn execute(&self, _req: &Request, _ctx: &mut CommandContext) -> Result<Response> {
+        Ok(Response {
+            msg: String::from("following events"),
+            spid: None,
+            error: false,
+        })
+    }
+
+    fn stream(
+        &self,
+        _req: &Request,
+        _res: &Response,
+        ctx: &SharedContext,
+        client: &mut Client,
+    ) -> Result<()> {
+        let events = ctx.lock().unwrap().events.subscribe();
+        loop {
+            let frame = match events.recv_timeout(HEARTBEAT_INTERVAL) {
+                Ok(event) if !self.filter.matches(&event) => continue,
+                Ok(event) => {
+                    let mut line = if self.json {
+                        serde_json::to_string(&event)?
+                    } else {
+                        event.to_string()
+                    };
+                    line.push('\n');
+                    StreamFrame::Output(line.into_bytes())
+                }
+                Err(RecvTimeoutError::Timeout) => StreamFrame::Heartbeat,
+                // the bus dropped the subscription because the client wasn't reading
+                Err(RecvTimeoutError::Disconnected) => {
+                    let e = anyhow::anyhow!("the client fell too far behind the events");
+                    return finish_stream(client, Err(e));
+                }
+            };
+            // the client stops following by disconnecting, which is not an error
+            if client.send_frame(&frame).is_err() {
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// command-structure for the `top` command
+///
+/// streams a snapshot of every process as a line of JSON, once per
+/// interval and whenever an event suggests that something changed
+#[derive(Serialize, Deserialize)]
+pub struct CmdTop {
+    /// the longest time between two snapshots
+    pub interval: Duration,
+}
+
+impl CmdTop {
+    /// builds the command from clap's ArgMatches, parsing the interval
+    pub fn from_matches(matches: &ArgMatches) -> Result<Self> {
+        let interval = parse_duration(matches.value_of("interval").unwrap())?;
+        if interval.is_zero() {
+            bail!("the interval must be longer than zero");
+        }
+        Ok(CmdTop { interval })
+    }
+}
+
+#[typetag::serde]
+impl Action for CmdTop {
+    fn permission(&self) -> Permission {
+        Permission::Read
+    }
+
+    fn execute(&self, _req: &Request, _ctx: &mut CommandContext) -> Result<Response> {
+        Ok(Response {
+            msg: String::from("following process status"),
+            spid: None,
+            error: false,
+        })
+    }
+
+    fn stream(
+        &self,
+        _req: &Request,
+        _res: &Response,
+        ctx: &SharedContext,
+        client: &mut Client,
+    ) -> Result<()> {
+        let mut events = ctx.lock().unwrap().events.subscribe();
+        let mut next_snapshot = Instant::now();
+        loop {
+            let frame = if Instant::now() >= next_snapshot {
+                next_snapshot = Instant::now() + self.interval;
+                let snapshot = top::snapshot(&mut ctx.lock().unwrap());
+                let mut line = serde_json::to_string(&snapshot)?;
+                line.push('\n');
+                StreamFrame::Output(line.into_bytes())
+            } else {
+                StreamFrame::Heartbeat
+            };
+            // the client stops following by disconnecting, which is not an error
+            if client.send_frame(&frame).is_err() {
+                return Ok(());
+            }
+
+            let wait = next_snapshot.saturating_duration_since(Instant::now());
+            match events.recv_timeout(wait.min(HEARTBEAT_INTERVAL)) {
+                // events tend to come in bursts, so they are all taken into one snapshot
+                Ok(_) => {
+                    while events.try_recv().is_ok() {}
+                    next_snapshot = Instant::now();
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                // events only suggest when to take a snapshot early, so a
+                // subscription dropped for falling behind is simply renewed
+                Err(RecvTimeoutError::Disconnected) => {
+                    events = ctx.lock().unwrap().events.subscribe();
+                    next_snapshot = Instant::now();
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::Role;
+    use crate::util::TempDir;
+
+    fn identity(uid: Option<u32>, token: Option<&str>) -> Identity {
+        Identity {
+            peer: "127.0.0.1:40000".parse().unwrap(),
+            token: token.map(String::from),
+            uid,
+            user: None,
+            gids: vec![4000],
+        }
+    }
+
+    fn role(permissions: &[Permission], users: &[&str], groups: &[&str], tokens: &[&str]) -> Role {
+        let list = |items: &[&str]| items.iter().map(|s| s.to_string()).collect();
+        Role {
+            name: String::from("ops"),
+            permissions: permissions.to_vec(),
+            users: list(users),
+            groups: list(groups),
+            tokens: list(tokens),
+        }
+    }
+
+    #[test]
+    fn everyone_is_authorized_without_roles() {
+        let dir = TempDir::new("commands-no-roles");
+        let ctx = CommandContext::for_tests(dir.path());
+        let stranger = identity(None, None);
+        assert!(ctx.authorize(&stranger, Permission::Admin));
+        assert!(ctx.authorize(&stranger, Permission::Run));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn the_owner_is_always_authorized() {
+        let dir = TempDir::new("commands-owner");
+        let mut ctx = CommandContext::for_tests(dir.path());
+        ctx.config.roles = vec![role(&[Permission::Read], &["*"], &[], &[])];
+        // safety: geteuid(2) always succeeds
+        let owner = identity(Some(unsafe { libc::geteuid() }), None);
+        assert!(ctx.authorize(&owner, Permission::Admin));
+    }
+
+    #[test]
+    fn roles_grant_only_their_permissions() {
+        let dir = TempDir::new("commands-roles");
+        let mut ctx = CommandContext::for_tests(dir.path());
+        ctx.config.roles = vec![
+            role(&[Permission::Read], &["*"], &[], &[]),
+            role(&[Permission::Manage], &[], &["4000"], &[]),
+            role(&[Permission::Run, Permission::Admin], &[], &[], &["ci"]),
+        ];
+
+        let local = identity(Some(4242), None);
+        assert!(ctx.authorize(&local, Permission::Read));
+        assert!(ctx.authorize(&local, Permission::Manage));
+        assert!(!ctx.authorize(&local, Permission::Run));
+        assert!(!ctx.authorize(&local, Permission::Admin));
+
+        let ci = identity(None, Some("ci"));
+        assert!(ctx.authorize(&ci, Permission::Run));
+        assert!(ctx.authorize(&ci, Permission::Admin));
+        assert!(!ctx.authorize(&ci, Permission::Read));
+
+        // a remote client without a known token matches nothing
+        let stranger = identity(None, Some("laptop"));
+        for permission in [
+            Permission::Read,
+            Permission::Run,
+            Permission::Manage,
+            Permission::Admin,
+        ] {
+            assert!(!ctx.authorize(&stranger, permission));
+        }
+    }
+}
@@ -0,0 +1,687 @@
+use crate::auth::Role;
+use crate::definitions::{self, ProcessDefinition, RestartPolicy};
+use crate::util::parse_duration;
+use crate::webhooks::AlertKind;
+use anyhow::{bail, Context, Result};
+use log::LevelFilter;
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::fmt;
+use std::fs;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// the address sibyld listens on, and sibyl connects to, by default
+pub const DEFAULT_LISTEN: &str = "127.0.0.1:52352";
+
+/// the longest restarts of a crash-looping definition are delayed
+const MAX_CRASH_BACKOFF: Duration = Duration::from_secs(600);
+
+/// daemon-wide settings, read from `sibyld.toml`
+///
+/// every setting is optional, and a missing config file is the same as an empty one
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct DaemonConfig {
+    /// addresses to accept connections on
+    pub listen: Vec<String>,
+    /// accepting connections over TLS, on addresses of their own
+    pub tls: Option<TlsConfig>,
+    /// serving a JSON API over HTTP, on addresses of its own
+    pub http: Option<HttpConfig>,
+    /// which connections must present a token
+    pub auth: AuthMode,
+    /// the tokens clients may authenticate with, which only the daemon's user may access
+    pub token_file: PathBuf,
+    /// what each client may do. without any roles, every client that gets past `auth` may do
+    /// anything, and with them the daemon's own user may still do anything
+    #[serde(rename = "role")]
+    pub roles: Vec<Role>,
+    /// where every request is recorded, one JSON object per line
+    pub audit_log: PathBuf,
+    /// endpoints alerted when processes fail or turn unhealthy
+    #[serde(rename = "webhook")]
+    pub webhooks: Vec<WebhookConfig>,
+    /// where process logs are created
+    pub log_directory: PathBuf,
+    /// log filter for the daemon itself, in env_logger syntax. the SIBYL_LOG
+    /// environment variable takes precedence over this
+    pub log_level: String,
+    /// when old log files are deleted
+    pub retention: Retention,
+    /// where the daemon saves the processes it manages, so they can be re-adopted
+    pub state_file: PathBuf,
+    /// the pidfile that keeps a second daemon from starting
+    pub pid_file: PathBuf,
+    /// where the daemon's own output goes when it runs with `--daemonize`
+    pub daemon_log: PathBuf,
+    /// restart policy for definitions that don't set their own
+    pub restart: RestartPolicy,
+    /// when definitions that keep failing have their restarts delayed, and then stopped
+    pub crash_loop: CrashLoopConfig,
+    /// the most processes that may be running at once, unlimited if unset
+    pub max_processes: Option<usize>,
+    /// what happens to running processes when the daemon shuts down
+    pub shutdown: ShutdownPolicy,
+    /// how long a stopped process gets to exit after SIGTERM before it is killed, e.g. `10s`
+    pub stop_timeout: String,
+    /// processes started when the daemon starts
+    #[serde(rename = "process")]
+    pub processes: Vec<ProcessDefinition>,
+}
+
+/// what the daemon does with its processes when it shuts down
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ShutdownPolicy {
+    /// stop every process, dependents before their dependencies
+    #[default]
+    Stop,
+    /// leave processes running and save them to the state file, for the next daemon to adopt
+    Detach,
+}
+
+impl fmt::Display for ShutdownPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self {
+            ShutdownPolicy::Stop => write!(f, "stop"),
+            ShutdownPolicy::Detach => write!(f, "detach"),
+        }
+    }
+}
+
+/// settings for accepting TLS connections, in the `[tls]` table
+///
+/// the plain `listen` addresses are unaffected, so local clients can keep using them
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    /// addresses to accept TLS connections on
+    pub listen: Vec<String>,
+    /// the server's certificate chain, PEM-encoded
+    pub cert: PathBuf,
+    /// the certificate's private key, PEM-encoded
+    pub key: PathBuf,
+    /// if set, clients must present a certificate signed by one of the CAs in this file.
+    /// they still have to authenticate according to `auth`
+    #[serde(default)]
+    pub client_ca: Option<PathBuf>,
+}
+
+/// settings for the JSON API, in the `[http]` table
+///
+/// every request must carry an `Authorization: Bearer <token>` header, whatever `auth`
+/// says, and is then authorized by `role` the same way as other clients. requests from
+/// browsers, which carry an `Origin` header, and requests whose `Host` is neither the
+/// address they were sent to nor `localhost` are refused. the API is plain HTTP, so remote
+/// access should go through a proxy that adds TLS
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct HttpConfig {
+    /// addresses to serve the API on
+    pub listen: Vec<String>,
+}
+
+/// an endpoint that alerts are POSTed to as JSON, in a `[[webhook]]` table
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct WebhookConfig {
+    /// an http or https URL
+    pub url: String,
+    /// which alerts to send, all of them if unset
+    #[serde(default = "AlertKind::all")]
+    pub alerts: Vec<AlertKind>,
+    /// how long alerts of the same kind about the same process are
+    /// collected before they are sent as one request, e.g. `10s`
+    #[serde(default = "WebhookConfig::default_group_window")]
+    pub group_window: String,
+    /// how many times sending a request is attempted before it is given up on
+    #[serde(default = "WebhookConfig::default_attempts")]
+    pub attempts: u32,
+    /// how long to wait before the first retry, doubled after every failed attempt
+    #[serde(default = "WebhookConfig::default_backoff")]
+    pub backoff: String,
+    /// `group_window`, parsed by `DaemonConfig::validate`
+    #[serde(skip)]
+    pub group_window_duration: Duration,
+    /// `backoff`, parsed by `DaemonConfig::validate`
+    #[serde(skip)]
+    pub backoff_duration: Duration,
+}
+
+impl WebhookConfig {
+    fn default_group_window() -> String {
+        String::from("10s")
+    }
+
+    fn default_attempts() -> u32 {
+        5
+    }
+
+    fn default_backoff() -> String {
+        String::from("1s")
+    }
+}
+
+/// which connections have to authenticate with a token
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum AuthMode {
+    /// nobody needs a token
+    Off,
+    /// connections from loopback addresses are trusted, everyone else needs a token
+    #[default]
+    Remote,
+    /// every connection needs a token
+    Always,
+}
+
+impl AuthMode {
+    /// whether a connection from `peer` has to present a token
+    pub fn requires_token(&self, peer: IpAddr) -> bool {
+        match self {
+            AuthMode::Off => false,
+            // ipv4 clients of an ipv6 listener show up as mapped addresses
+            AuthMode::Remote => !peer.to_canonical().is_loopback(),
+            AuthMode::Always => true,
+        }
+    }
+}
+
+impl fmt::Display for AuthMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self {
+            AuthMode::Off => write!(f, "off"),
+            AuthMode::Remote => write!(f, "remote"),
+            AuthMode::Always => write!(f, "always"),
+        }
+    }
+}
+
+/// limits on how many old log files are kept around
+///
+/// logs of running processes are never deleted
+#[derive(Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Retention {
+    /// delete logs last written to longer ago than this, e.g. `7d`
+    pub max_age: Option<String>,
+    /// keep at most this many logs, deleting the oldest first
+    pub max_files: Option<usize>,
+}
+
+impl Retention {
+    /// the parsed `max_age`, assuming the config has been validated
+    pub fn max_age(&self) -> Option<Duration> {
+        self.max_age
+            .as_ref()
+            .and_then(|age| parse_duration(age).ok())
+    }
+}
+
+/// settings for crash-looping definitions, in the `[crash_loop]` table
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct CrashLoopConfig {
+    /// a definition whose processes fail this many times within `window` is crash-looping
+    pub failures: usize,
+    pub window: String,
+    /// how long restarts of a crash-looping definition are delayed at first,
+    /// doubled after every further failure
+    pub backoff: String,
+    /// how many failures a crash-looping definition may have before it
+    /// stops being restarted, until `sibyl reset`
+    pub quarantine_after: u32,
+    /// `window`, parsed by `DaemonConfig::validate`
+    #[serde(skip)]
+    pub window_duration: Duration,
+    /// `backoff`, parsed by `DaemonConfig::validate`
+    #[serde(skip)]
+    pub backoff_duration: Duration,
+}
+
+impl Default for CrashLoopConfig {
+    fn default() -> Self {
+        CrashLoopConfig {
+            failures: 5,
+            window: String::from("1m"),
+            backoff: String::from("10s"),
+            quarantine_after: 5,
+            window_duration: Duration::from_secs(60),
+            backoff_duration: Duration::from_secs(10),
+        }
+    }
+}
+
+impl CrashLoopConfig {
+    /// how long to wait before restarting a crash-looping definition
+    /// # Arguments
+    /// * `failures` - how many times it failed since it started crash-looping
+    pub fn backoff(&self, failures: u32) -> Duration {
+        self.backoff_duration
+            .saturating_mul(1 << failures.min(16))
+            .min(MAX_CRASH_BACKOFF)
+    }
+}
+
+impl Default for DaemonConfig {
+    fn default() -> Self {
+        let data_dir = dirs::data_local_dir().unwrap_or_else(|| PathBuf::from("."));
+        let config_dir = dirs::config_dir().unwrap_or_else(|| PathBuf::from("."));
+
+        DaemonConfig {
+            listen: vec![DEFAULT_LISTEN.to_string()],
+            tls: None,
+            http: None,
+            auth: AuthMode::Remote,
+            token_file: config_dir.join("sibyl").join("tokens.toml"),
+            roles: Vec::new(),
+            audit_log: data_dir.join("sibyl").join("audit.log"),
+            webhooks: Vec::new(),
+            log_directory: data_dir.join("sibyllogs"),
+            log_level: String::from("info"),
+            retention: Retention::default(),
+            state_file: data_dir.join("sibyl").join("state.toml"),
+            pid_file: data_dir.join("sibyl").join("sibyld.pid"),
+            daemon_log: data_dir.join("sibyl").join("sibyld.log"),
+            restart: RestartPolicy::Never,
+            crash_loop: CrashLoopConfig::default(),
+            max_processes: None,
+            shutdown: ShutdownPolicy::Stop,
+            stop_timeout: String::from("5s"),
+            processes: Vec::new(),
+        }
+    }
+}
+
+impl DaemonConfig {
+    /// the parsed `stop_timeout`, assuming the config has been validated
+    pub fn stop_timeout(&self) -> Duration {
+        parse_duration(&self.stop_timeout).unwrap_or(Duration::from_secs(5))
+    }
+
+    /// the default location of the config file, `$XDG_CONFIG_HOME/sibyl/sibyld.toml` on linux
+    pub fn default_path() -> Option<PathBuf> {
+        dirs::config_dir().map(|dir| dir.join("sibyl").join("sibyld.toml"))
+    }
+
+    /// loads and validates the daemon config
+    ///
+    /// an explicitly given file must exist, while a missing
+    /// file at the default location just means default settings
+    /// # Arguments
+    /// * `path` - the file given with `--config`, if any
+    pub fn load(path: Option<&Path>) -> Result<DaemonConfig> {
+        let (path, explicit) = match path {
+            Some(path) => (PathBuf::from(path), true),
+            None => match Self::default_path() {
+                Some(path) => (path, false),
+                None => return Ok(DaemonConfig::default()),
+            },
+        };
+
+        if !explicit && !path.exists() {
+            return Ok(DaemonConfig::default());
+        }
+
+        let contents = fs::read_to_string(&path)
+            .with_context(|| format!("failed to read config file {}", path.display()))?;
+        let mut config: DaemonConfig = toml::from_str(&contents)
+            .with_context(|| format!("failed to parse config file {}", path.display()))?;
+        config.resolve_paths(&path)?;
+        config
+            .validate()
+            .with_context(|| format!("invalid config file {}", path.display()))?;
+
+        Ok(config)
+    }
+
+    /// lists the settings that differ from `new` and can be changed while the daemon runs,
+    /// as `(setting, old value, new value)`
+    pub fn changed_settings(&self, new: &DaemonConfig) -> Vec<(&'static str, String, String)> {
+        let mut changes = Vec::new();
+        let mut compare = |setting, old: String, new: String| {
+            if old != new {
+                changes.push((setting, old, new));
+            }
+        };
+
+        compare("auth", self.auth.to_string(), new.auth.to_string());
+        compare(
+            "token_file",
+            self.token_file.display().to_string(),
+            new.token_file.display().to_string(),
+        );
+        compare(
+            "audit_log",
+            self.audit_log.display().to_string(),
+            new.audit_log.display().to_string(),
+        );
+        compare(
+            "log_directory",
+            self.log_directory.display().to_string(),
+            new.log_directory.display().to_string(),
+        );
+        compare(
+            "retention.max_age",
+            describe(&self.retention.max_age, "none"),
+            describe(&new.retention.max_age, "none"),
+        );
+        compare(
+            "retention.max_files",
+            describe(&self.retention.max_files, "unlimited"),
+            describe(&new.retention.max_files, "unlimited"),
+        );
+        compare(
+            "state_file",
+            self.state_file.display().to_string(),
+            new.state_file.display().to_string(),
+        );
+        compare("restart", self.restart.to_string(), new.restart.to_string());
+        compare(
+            "crash_loop.failures",
+            self.crash_loop.failures.to_string(),
+            new.crash_loop.failures.to_string(),
+        );
+        compare(
+            "crash_loop.window",
+            self.crash_loop.window.clone(),
+            new.crash_loop.window.clone(),
+        );
+        compare(
+            "crash_loop.backoff",
+            self.crash_loop.backoff.clone(),
+            new.crash_loop.backoff.clone(),
+        );
+        compare(
+            "crash_loop.quarantine_after",
+            self.crash_loop.quarantine_after.to_string(),
+            new.crash_loop.quarantine_after.to_string(),
+        );
+        compare(
+            "max_processes",
+            describe(&self.max_processes, "unlimited"),
+            describe(&new.max_processes, "unlimited"),
+        );
+        compare(
+            "shutdown",
+            self.shutdown.to_string(),
+            new.shutdown.to_string(),
+        );
+        compare(
+            "stop_timeout",
+            self.stop_timeout.clone(),
+            new.stop_timeout.clone(),
+        );
+        if self.webhooks != new.webhooks {
+            let urls = |webhooks: &[WebhookConfig]| {
+                let urls: Vec<&str> = webhooks.iter().map(|w| w.url.as_str()).collect();
+                format!("[{}]", urls.join(", "))
+            };
+            changes.push(("webhooks", urls(&self.webhooks), urls(&new.webhooks)));
+        }
+        // roles can change without their names changing, so they are compared as a whole
+        if self.roles != new.roles {
+            let names = |roles: &[Role]| {
+                let names: Vec<&str> = roles.iter().map(|role| role.name.as_str()).collect();
+                format!("[{}]", names.join(", "))
+            };
+            changes.push(("roles", names(&self.roles), names(&new.roles)));
+        }
+
+        changes
+    }
+
+    /// lists the settings that differ from `new` but only take effect when the daemon restarts
+    pub fn fixed_changes(&self, new: &DaemonConfig) -> Vec<&'static str> {
+        let mut changes = Vec::new();
+        if self.listen != new.listen {
+            changes.push("listen");
+        }
+        if self.tls != new.tls {
+            changes.push("tls");
+        }
+        if self.http != new.http {
+            changes.push("http");
+        }
+        if self.log_level != new.log_level {
+            changes.push("log_level");
+        }
+        if self.pid_file != new.pid_file {
+            changes.push("pid_file");
+        }
+        if self.daemon_log != new.daemon_log {
+            changes.push("daemon_log");
+        }
+        changes
+    }
+
+    /// makes every relative path absolute, relative to the directory of the config file,
+    /// so that they point to the same place however the daemon's working directory changes
+    fn resolve_paths(&mut self, config_file: &Path) -> Result<()> {
+        let cwd = env::current_dir().context("failed to get the working directory")?;
+        let base = cwd.join(config_file.parent().unwrap_or(Path::new("")));
+        let mut paths = vec![
+            &mut self.log_directory,
+            &mut self.token_file,
+            &mut self.audit_log,
+            &mut self.state_file,
+            &mut self.pid_file,
+            &mut self.daemon_log,
+        ];
+        if let Some(tls) = &mut self.tls {
+            paths.push(&mut tls.cert);
+            paths.push(&mut tls.key);
+            paths.extend(tls.client_ca.as_mut());
+        }
+        for path in paths {
+            if path.is_relative() {
+                *path = base.join(&*path);
+            }
+        }
+        Ok(())
+    }
+
+    /// checks the config for values that would only fail later on,
+    /// and parses the durations that are read often
+    pub fn validate(&mut self) -> Result<()> {
+        if self.listen.is_empty() {
+            bail!("listen must contain at least one address");
+        }
+        for addr in &self.listen {
+            addr.parse::<SocketAddr>()
+                .with_context(|| format!("invalid listen address '{}'", addr))?;
+        }
+
+        if let Some(tls) = &self.tls {
+            if tls.listen.is_empty() {
+                bail!("tls.listen must contain at least one address");
+            }
+            for addr in &tls.listen {
+                addr.parse::<SocketAddr>()
+                    .with_context(|| format!("invalid tls.listen address '{}'", addr))?;
+            }
+        }
+        if let Some(http) = &self.http {
+            if http.listen.is_empty() {
+                bail!("http.listen must contain at least one address");
+            }
+            for addr in &http.listen {
+                addr.parse::<SocketAddr>()
+                    .with_context(|| format!("invalid http.listen address '{}'", addr))?;
+            }
+        }
+
+        for (i, role) in self.roles.iter().enumerate() {
+            if self.roles[..i].iter().any(|r| r.name == role.name) {
+                bail!("role name '{}' is used twice", role.name);
+            }
+            if role.permissions.is_empty() {
+                bail!("role '{}' has no permissions", role.name);
+            }
+        }
+
+        for webhook in &mut self.webhooks {
+            if !webhook.url.starts_with("http://") && !webhook.url.starts_with("https://") {
+                bail!("webhook url '{}' is not an http or https URL", webhook.url);
+            }
+            if webhook.alerts.is_empty() {
+                bail!("webhook '{}' has no alerts", webhook.url);
+            }
+            if webhook.attempts == 0 {
+                bail!("webhook '{}' must make at least 1 attempt", webhook.url);
+            }
+            webhook.group_window_duration = parse_duration(&webhook.group_window)
+                .with_context(|| format!("invalid group_window for webhook '{}'", webhook.url))?;
+            webhook.backoff_duration = parse_duration(&webhook.backoff)
+                .with_context(|| format!("invalid backoff for webhook '{}'", webhook.url))?;
+        }
+
+        validate_log_level(&self.log_level)?;
+
+        if let Some(age) = &self.retention.max_age {
+            parse_duration(age).context("invalid retention.max_age")?;
+        }
+        if self.retention.max_files == Some(0) {
+            bail!("retention.max_files must be at least 1");
+        }
+        parse_duration(&self.stop_timeout).context("invalid stop_timeout")?;
+        if self.crash_loop.failures == 0 {
+            bail!("crash_loop.failures must be at least 1");
+        }
+        self.crash_loop.window_duration =
+            parse_duration(&self.crash_loop.window).context("invalid crash_loop.window")?;
+        self.crash_loop.backoff_duration =
+            parse_duration(&self.crash_loop.backoff).context("invalid crash_loop.backoff")?;
+        if self.crash_loop.quarantine_after == 0 {
+            bail!("crash_loop.quarantine_after must be at least 1");
+        }
+        if self.max_processes == Some(0) {
+            bail!("max_processes must be at least 1");
+        }
+
+        for def in &mut self.processes {
+            definitions::validate(def)?;
+        }
+        definitions::start_order(&self.processes)?;
+        for def in &self.processes {
+            for dep in &def.depends_on {
+                if !self.processes.iter().any(|d| d.name == dep.name) {
+                    bail!(
+                        "process '{}' depends on unknown process '{}'",
+                        def.name,
+                        dep.name
+                    );
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// checks the levels in an env_logger filter such as `info,sibyl::supervisor=debug`
+///
+/// env_logger itself only prints a warning for invalid filters and carries on.
+/// it would also take a bare word such as `infoo` to be a module name, which is
+/// almost always a typo here, so modules must be given a level with `=`
+fn validate_log_level(filter: &str) -> Result<()> {
+    // anything after a `/` is a regex on the message, not part of the directives
+    let directives = filter.split('/').next().unwrap_or_default();
+
+    for directive in directives.split(',').map(str::trim) {
+        let valid = match directive.split_once('=') {
+            Some((module, level)) => !module.is_empty() && level.parse::<LevelFilter>().is_ok(),
+            None => directive.is_empty() || directive.parse::<LevelFilter>().is_ok(),
+        };
+        if !valid {
+            bail!("invalid log_level directive '{}'", directive);
+        }
+    }
+
+    Ok(())
+}
+
+/// formats an optional setting, using `unset` if it has no value
+fn describe<T: ToString>(value: &Option<T>, unset: &str) -> String {
+    match value {
+        Some(value) => value.to_string(),
+        None => unset.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn log_level_accepts_env_logger_filters() {
+        for filter in [
+            "info",
+            "DEBUG",
+            "off",
+            "info,sibyl::supervisor=debug",
+            "sibyl=trace, tiny_http=warn",
+            "warn/failed to .*",
+            "",
+        ] {
+            assert!(validate_log_level(filter).is_ok(), "{}", filter);
+        }
+    }
+
+    #[test]
+    fn log_level_rejects_invalid_levels() {
+        for filter in [
+            "infoo",
+            "info,debugg",
+            "sibyl=loud",
+            "=debug",
+            "sibyl",
+            "infoo/regex",
+        ] {
+            assert!(validate_log_level(filter).is_err(), "{}", filter);
+        }
+    }
+
+    #[test]
+    fn crash_loop_backoff_doubles_up_to_the_limit() {
+        let config = CrashLoopConfig::default();
+        assert_eq!(config.backoff(0), Duration::from_secs(10));
+        assert_eq!(config.backoff(1), Duration::from_secs(20));
+        assert_eq!(config.backoff(3), Duration::from_secs(80));
+        assert_eq!(config.backoff(6), MAX_CRASH_BACKOFF);
+        // large failure counts must not overflow the shift
+        assert_eq!(config.backoff(u32::MAX), MAX_CRASH_BACKOFF);
+    }
+
+    #[test]
+    fn validate_parses_durations() {
+        let mut config: DaemonConfig = toml::from_str(
+            r#"
+            [crash_loop]
+            window = "2m"
+            backoff = "500ms"
+
+            [[webhook]]
+            url = "http://127.0.0.1:9000/alerts"
+            group_window = "30s"
+            "#,
+        )
+        .unwrap();
+        config.validate().unwrap();
+
+        assert_eq!(config.crash_loop.window_duration, Duration::from_secs(120));
+        assert_eq!(config.crash_loop.backoff(1), Duration::from_secs(1));
+        let webhook = &config.webhooks[0];
+        assert_eq!(webhook.group_window_duration, Duration::from_secs(30));
+        assert_eq!(webhook.backoff_duration, Duration::from_secs(1));
+    }
+
+    #[test]
+    fn validate_rejects_invalid_durations() {
+        let mut config = DaemonConfig::default();
+        config.crash_loop.backoff = String::from("soon");
+        assert!(config.validate().is_err());
+    }
+}
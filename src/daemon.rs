@@ -0,0 +1,455 @@
+use crate::auth;
+use crate::commands::{CmdPing, CmdUpgrade, CommandContext};
+use crate::config::DaemonConfig;
+use crate::{Client, HandshakeReply, Request, StreamFrame};
+use anyhow::{bail, Context, Result};
+use chrono::Utc;
+use log::{info, warn};
+use std::convert::Infallible;
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// how long `start` waits for a new daemon to accept connections
+const START_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// how long `stop` waits for the daemon to exit, which includes
+/// stopping its processes one by one under the stop shutdown policy
+const STOP_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// an exclusively locked pidfile, which makes sure only one daemon runs per pidfile
+///
+/// the lock is held until the daemon exits, so a pidfile left behind
+/// by a crashed daemon doesn't stop a new one from starting
+pub struct PidFile {
+    file: File,
+}
+
+impl PidFile {
+    /// creates and locks the pidfile
+    ///
+    /// fails with "already running" if another daemon holds the lock
+    /// # Arguments
+    /// * `path` - where the pidfile is created
+    pub fn lock(path: &Path) -> Result<PidFile> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)
+                .with_context(|| format!("failed to create directory {}", dir.display()))?;
+        }
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .with_context(|| format!("failed to open pidfile {}", path.display()))?;
+
+        if !try_lock(&file)? {
+            match read_pid(path) {
+                Some(pid) => bail!("sibyld is already running (pid {})", pid),
+                None => bail!("sibyld is already running"),
+            }
+        }
+        // clear the pid of a previous daemon, so it isn't mistaken for ours
+        file.set_len(0)?;
+
+        Ok(PidFile { file })
+    }
+
+    /// takes back the pidfile handed over by the daemon before an upgrade
+    ///
+    /// the lock belongs to the open file, so it was never released
+    /// # Safety
+    /// `fd` must be the pidfile's descriptor from the handed over state, and not owned by anything else
+    pub unsafe fn inherit(fd: RawFd) -> Result<PidFile> {
+        set_cloexec(fd, true)?;
+        Ok(PidFile {
+            file: File::from_raw_fd(fd),
+        })
+    }
+
+    pub fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+
+    /// writes the pid of the current process into the pidfile
+    ///
+    /// must be called after `daemonize`, since forking changes the pid
+    pub fn write_pid(&mut self) -> Result<()> {
+        self.file.set_len(0)?;
+        self.file.seek(SeekFrom::Start(0))?;
+        writeln!(self.file, "{}", std::process::id())?;
+        self.file.flush()?;
+        Ok(())
+    }
+}
+
+/// tries to take an exclusive lock on a file without blocking
+///
+/// returns false if someone else holds the lock
+fn try_lock(file: &File) -> Result<bool> {
+    // safety: flock has no memory-safety requirements
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } == 0 {
+        return Ok(true);
+    }
+
+    let err = io::Error::last_os_error();
+    if err.raw_os_error() == Some(libc::EWOULDBLOCK) {
+        Ok(false)
+    } else {
+        Err(err).context("failed to lock pidfile")
+    }
+}
+
+/// reads the pid stored in a pidfile, without checking whether it is still locked
+fn read_pid(path: &Path) -> Option<u32> {
+    let mut contents = String::new();
+    File::open(path).ok()?.read_to_string(&mut contents).ok()?;
+    contents.trim().parse().ok()
+}
+
+/// returns the pid of the daemon that holds the lock on a pidfile, if any
+/// # Arguments
+/// * `path` - the pidfile to check
+pub fn running_pid(path: &Path) -> Result<Option<u32>> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => {
+            return Err(e).with_context(|| format!("failed to open pidfile {}", path.display()))
+        }
+    };
+
+    // the lock is released again when `file` is dropped
+    if try_lock(&file)? {
+        return Ok(None);
+    }
+
+    // a daemon that is just starting holds the lock before it has written its pid
+    let deadline = Instant::now() + Duration::from_secs(1);
+    loop {
+        if let Some(pid) = read_pid(path) {
+            return Ok(Some(pid));
+        }
+        if Instant::now() >= deadline {
+            bail!("pidfile {} is locked but has no pid in it", path.display());
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+/// detaches the current process from its terminal by double-forking
+///
+/// the original process exits, and the grandchild carries on in a new
+/// session with stdin from /dev/null and stdout and stderr appended to `log`.
+/// must be called before any threads are spawned
+/// # Arguments
+/// * `log` - the file the daemon's own output goes to
+pub fn daemonize(log: &Path) -> Result<()> {
+    if let Some(dir) = log.parent() {
+        fs::create_dir_all(dir)
+            .with_context(|| format!("failed to create directory {}", dir.display()))?;
+    }
+    let null = File::open("/dev/null")?;
+    let output = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(log)
+        .with_context(|| format!("failed to open daemon log {}", log.display()))?;
+
+    // safety: there are no other threads yet, so forking can't
+    // leave locks held by threads that don't exist in the child
+    unsafe {
+        fork_and_exit_parent()?;
+        if libc::setsid() == -1 {
+            return Err(io::Error::last_os_error()).context("failed to create a new session");
+        }
+        // the second fork makes sure the daemon can never reacquire a terminal
+        fork_and_exit_parent()?;
+
+        libc::umask(0o022);
+        if libc::dup2(null.as_raw_fd(), 0) == -1
+            || libc::dup2(output.as_raw_fd(), 1) == -1
+            || libc::dup2(output.as_raw_fd(), 2) == -1
+        {
+            return Err(io::Error::last_os_error()).context("failed to redirect stdio");
+        }
+    }
+
+    // relative paths have all been resolved by now, and the
+    // daemon shouldn't keep whatever directory it was started in busy
+    env::set_current_dir("/")?;
+    Ok(())
+}
+
+/// forks, exiting in the parent and returning in the child
+unsafe fn fork_and_exit_parent() -> Result<()> {
+    match libc::fork() {
+        -1 => Err(io::Error::last_os_error()).context("failed to fork"),
+        0 => Ok(()),
+        _ => libc::_exit(0),
+    }
+}
+
+/// sets or clears FD_CLOEXEC, which decides whether a file descriptor is closed when the process execs
+pub fn set_cloexec(fd: RawFd, cloexec: bool) -> Result<()> {
+    // safety: F_GETFD and F_SETFD only change the flags of the descriptor
+    unsafe {
+        let flags = libc::fcntl(fd, libc::F_GETFD);
+        if flags == -1 {
+            return Err(io::Error::last_os_error()).context("failed to get descriptor flags");
+        }
+        let flags = if cloexec {
+            flags | libc::FD_CLOEXEC
+        } else {
+            flags & !libc::FD_CLOEXEC
+        };
+        if libc::fcntl(fd, libc::F_SETFD, flags) == -1 {
+            return Err(io::Error::last_os_error()).context("failed to set descriptor flags");
+        }
+    }
+    Ok(())
+}
+
+/// replaces the daemon with a new binary, handing over its state and open descriptors
+///
+/// the process keeps its pid, so children stay children of the new daemon, and the
+/// listening sockets stay open the whole time, so no connection is refused.
+/// only returns if the upgrade failed, in which case the old daemon carries on
+/// # Arguments
+/// * `ctx` - the command context, which must stay locked until the exec
+/// * `binary` - the new sibyld binary
+pub fn upgrade(ctx: &mut CommandContext, binary: &Path) -> anyhow::Error {
+    match exec_upgrade(ctx, binary) {
+        Ok(never) => match never {},
+        Err(e) => e,
+    }
+}
+
+fn exec_upgrade(ctx: &mut CommandContext, binary: &Path) -> Result<Infallible> {
+    let mut state = ctx.save_state();
+    for saved in &mut state.processes {
+        let proc = ctx.prochandler.get_process_by_pid(saved.spid).unwrap();
+        saved.pty_fd = proc.pty.as_ref().map(|pty| pty.as_raw_fd());
+        if saved.pty_fd.is_none() {
+            saved.stdin_fd = proc
+                .stdin
+                .as_ref()
+                .and_then(|stdin| stdin.raw_fd())
+                .filter(|&fd| set_cloexec(fd, true).is_ok());
+        }
+    }
+    state.fds = ctx.daemon_fds.clone();
+    state.hooks = ctx.hooks.save();
+
+    let mut fds: Vec<RawFd> = state.fds.listeners.clone();
+    fds.extend(&state.fds.tls_listeners);
+    fds.extend(&state.fds.http_listeners);
+    fds.extend(state.fds.pid_file);
+    for saved in &state.processes {
+        fds.extend(saved.pty_fd);
+        fds.extend(saved.stdin_fd);
+    }
+
+    let path = ctx.config.state_file.with_extension("upgrade");
+    state.save(&path)?;
+
+    let mut cmd = Command::new(binary);
+    cmd.arg("--upgrade-from").arg(&path);
+    if let Some(config) = &ctx.config_path {
+        cmd.arg("--config").arg(config);
+    }
+
+    info!("upgrading to {}", binary.display());
+    let result = fds.iter().try_for_each(|&fd| set_cloexec(fd, false));
+    let err = match result {
+        // exec only returns if it failed
+        Ok(()) => anyhow::Error::from(cmd.exec()),
+        Err(e) => e,
+    };
+
+    for &fd in &fds {
+        if let Err(e) = set_cloexec(fd, true) {
+            warn!("failed to restore FD_CLOEXEC on {}: {}", fd, e);
+        }
+    }
+    let _ = fs::remove_file(&path);
+    Err(err.context(format!("failed to run {}", binary.display())))
+}
+
+/// finds the sibyld binary, preferring the one next to the running executable
+fn sibyld_path() -> PathBuf {
+    env::current_exe()
+        .ok()
+        .and_then(|exe| exe.parent().map(|dir| dir.join("sibyld")))
+        .filter(|path| path.exists())
+        .unwrap_or_else(|| PathBuf::from("sibyld"))
+}
+
+/// starts sibyld in the background and waits until it accepts connections
+///
+/// returns the pid of the new daemon
+/// # Arguments
+/// * `config_path` - the config file to pass to sibyld, if any
+pub fn start(config_path: Option<&Path>) -> Result<u32> {
+    let config = DaemonConfig::load(config_path)?;
+    if let Some(pid) = running_pid(&config.pid_file)? {
+        bail!("sibyld is already running (pid {})", pid);
+    }
+
+    let mut cmd = Command::new(sibyld_path());
+    cmd.arg("--daemonize").stdin(Stdio::null());
+    if let Some(path) = config_path {
+        cmd.arg("--config").arg(path);
+    }
+    // the first fork exits as soon as the daemon has detached,
+    // so this only waits for startup errors
+    let status = cmd.status().context("failed to run sibyld")?;
+    if !status.success() {
+        bail!("sibyld failed to start");
+    }
+
+    let deadline = Instant::now() + START_TIMEOUT;
+    while Instant::now() < deadline {
+        if let Some(pid) = running_pid(&config.pid_file)? {
+            if ping(&config.listen[0]).is_ok() {
+                return Ok(pid);
+            }
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+
+    bail!(
+        "sibyld did not start accepting connections within {}s, see {}",
+        START_TIMEOUT.as_secs(),
+        config.daemon_log.display()
+    )
+}
+
+/// sends a ping, so that checking the daemon is up doesn't look like a broken request
+///
+/// a daemon that refuses the connection is up all the same
+fn ping(addr: &str) -> Result<()> {
+    let mut client = Client::connect_to(addr)?;
+    if let HandshakeReply::Rejected(_) = client.handshake(auth::client_token(None)?.as_deref())? {
+        return Ok(());
+    }
+    client.send_request(&Request {
+        command: Box::new(CmdPing),
+        time: Utc::now(),
+    })?;
+    client.receive_response()?;
+    Ok(())
+}
+
+/// asks the running daemon to exit with SIGTERM, and waits until it has
+///
+/// returns the pid of the stopped daemon, or None if it wasn't running
+/// # Arguments
+/// * `config_path` - the config file the daemon was started with, if any
+pub fn stop(config_path: Option<&Path>) -> Result<Option<u32>> {
+    let config = DaemonConfig::load(config_path)?;
+    let pid = match running_pid(&config.pid_file)? {
+        Some(pid) => pid,
+        None => return Ok(None),
+    };
+
+    // safety: kill(2) has no memory-safety requirements
+    if unsafe { libc::kill(pid as libc::pid_t, libc::SIGTERM) } == -1 {
+        return Err(io::Error::last_os_error()).context("failed to signal sibyld");
+    }
+
+    let deadline = Instant::now() + STOP_TIMEOUT;
+    while Instant::now() < deadline {
+        if running_pid(&config.pid_file)?.is_none() {
+            return Ok(Some(pid));
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+
+    bail!(
+        "sibyld (pid {}) did not exit within {}s",
+        pid,
+        STOP_TIMEOUT.as_secs()
+    )
+}
+
+/// asks the running daemon to replace itself with a new binary, and waits until the new one answers
+///
+/// returns the pid of the daemon, which stays the same across the upgrade
+/// # Arguments
+/// * `config_path` - the config file the daemon was started with, if any
+/// * `binary` - the new sibyld binary, the daemon's current one if None
+pub fn request_upgrade(config_path: Option<&Path>, binary: Option<&Path>) -> Result<u32> {
+    let config = DaemonConfig::load(config_path)?;
+    let pid = match running_pid(&config.pid_file)? {
+        Some(pid) => pid,
+        None => bail!("sibyld is not running"),
+    };
+
+    let mut client = Client::connect_to(&config.listen[0])?;
+    client.authenticate(auth::client_token(None)?.as_deref())?;
+    client.send_request(&Request {
+        command: Box::new(CmdUpgrade {
+            binary: binary.map(Path::to_path_buf),
+        }),
+        time: Utc::now(),
+    })?;
+    let res = client.receive_response()?;
+    if res.error {
+        bail!("{}", res.msg);
+    }
+    println!("{}", res.msg);
+
+    // the connection closes when the daemon execs, and frames only arrive if it failed to
+    let mut output = Vec::new();
+    while let Ok(frame) = client.receive_frame() {
+        match frame {
+            StreamFrame::Output(bytes) => output.extend(bytes),
+            StreamFrame::End(_) => bail!("{}", String::from_utf8_lossy(&output)),
+            _ => {}
+        }
+    }
+
+    // the listening sockets stay open, so this waits for the new daemon to accept the connection
+    let deadline = Instant::now() + START_TIMEOUT;
+    while Instant::now() < deadline {
+        if ping(&config.listen[0]).is_ok() {
+            return Ok(pid);
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+
+    bail!(
+        "sibyld (pid {}) did not answer within {}s of upgrading, see {}",
+        pid,
+        START_TIMEOUT.as_secs(),
+        config.daemon_log.display()
+    )
+}
+
+/// makes the running daemon reload its config and tokens, like `sibyl reload` but through the pidfile,
+/// so that it works before the client has a token
+///
+/// returns the pid of the daemon, or None if it isn't running
+/// # Arguments
+/// * `config_path` - the config file the daemon was started with, if any
+pub fn signal_reload(config_path: Option<&Path>) -> Result<Option<u32>> {
+    let config = DaemonConfig::load(config_path)?;
+    let pid = match running_pid(&config.pid_file)? {
+        Some(pid) => pid,
+        None => return Ok(None),
+    };
+
+    // safety: kill(2) has no memory-safety requirements
+    if unsafe { libc::kill(pid as libc::pid_t, libc::SIGHUP) } == -1 {
+        return Err(io::Error::last_os_error()).context("failed to signal sibyld");
+    }
+    Ok(Some(pid))
+}
@@ -0,0 +1,493 @@
+use crate::logging::{timestamped, LogName};
+use crate::processing::LaunchOptions;
+use crate::util::parse_timeout;
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// condition a dependency has to reach before its dependents are started
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum DependencyCondition {
+    /// the dependency has been spawned
+    #[default]
+    Started,
+    /// the dependency is running and its healthcheck (if any) passes
+    Healthy,
+    /// the dependency has exited with exit code 0
+    ExitedSuccessfully,
+}
+
+/// what the supervisor does when a process started from a definition exits
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum RestartPolicy {
+    /// leave the process exited
+    #[default]
+    Never,
+    /// restart the process if it exited with a non-zero exit code or timed out
+    OnFailure,
+    /// restart the process whenever it exits
+    Always,
+}
+
+impl RestartPolicy {
+    /// whether a process that exited should be started again
+    /// # Arguments
+    /// * `failed` - whether the process exited unsuccessfully
+    pub fn should_restart(&self, failed: bool) -> bool {
+        match self {
+            RestartPolicy::Never => false,
+            RestartPolicy::OnFailure => failed,
+            RestartPolicy::Always => true,
+        }
+    }
+}
+
+impl fmt::Display for RestartPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self {
+            RestartPolicy::Never => write!(f, "never"),
+            RestartPolicy::OnFailure => write!(f, "on_failure"),
+            RestartPolicy::Always => write!(f, "always"),
+        }
+    }
+}
+
+/// the lifecycle transitions a definition can attach a hook to
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HookKind {
+    OnStart,
+    /// any exit, whether it failed or not
+    OnExit,
+    /// an exit with a non-zero exit code, by a signal or after timing out,
+    /// unless the process was stopped on purpose
+    OnFailure,
+    /// the supervisor started a new instance after the process exited
+    OnRestart,
+    /// the healthcheck started failing while the process is running
+    OnUnhealthy,
+}
+
+impl fmt::Display for HookKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self {
+            HookKind::OnStart => write!(f, "on_start"),
+            HookKind::OnExit => write!(f, "on_exit"),
+            HookKind::OnFailure => write!(f, "on_failure"),
+            HookKind::OnRestart => write!(f, "on_restart"),
+            HookKind::OnUnhealthy => write!(f, "on_unhealthy"),
+        }
+    }
+}
+
+/// a command the daemon runs when a process goes through a lifecycle transition
+///
+/// it is told about the transition through SIBYL_* environment variables
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Hook {
+    /// program followed by its arguments
+    pub cmd: Vec<String>,
+    /// how long the hook may run before it is killed, a minute if unset
+    #[serde(default)]
+    pub timeout: Option<String>,
+    /// `timeout` as parsed by `validate`
+    #[serde(skip)]
+    pub timeout_duration: Option<Duration>,
+}
+
+/// the hooks of a definition, in its `[process.hooks]` table
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Hooks {
+    pub on_start: Option<Hook>,
+    pub on_exit: Option<Hook>,
+    pub on_failure: Option<Hook>,
+    pub on_restart: Option<Hook>,
+    pub on_unhealthy: Option<Hook>,
+}
+
+impl Hooks {
+    pub fn get(&self, kind: HookKind) -> Option<&Hook> {
+        match kind {
+            HookKind::OnStart => self.on_start.as_ref(),
+            HookKind::OnExit => self.on_exit.as_ref(),
+            HookKind::OnFailure => self.on_failure.as_ref(),
+            HookKind::OnRestart => self.on_restart.as_ref(),
+            HookKind::OnUnhealthy => self.on_unhealthy.as_ref(),
+        }
+    }
+
+    pub fn get_mut(&mut self, kind: HookKind) -> Option<&mut Hook> {
+        match kind {
+            HookKind::OnStart => self.on_start.as_mut(),
+            HookKind::OnExit => self.on_exit.as_mut(),
+            HookKind::OnFailure => self.on_failure.as_mut(),
+            HookKind::OnRestart => self.on_restart.as_mut(),
+            HookKind::OnUnhealthy => self.on_unhealthy.as_mut(),
+        }
+    }
+}
+
+/// a single entry in a definition's `depends_on` list
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Dependency {
+    pub name: String,
+    #[serde(default)]
+    pub condition: DependencyCondition,
+}
+
+/// declarative description of a named process managed by sibyld
+///
+/// definitions are read from a toml file by the client (`sibyl up`)
+/// and sent to the daemon, which starts them in dependency order
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ProcessDefinition {
+    pub name: String,
+    /// program followed by its arguments
+    pub cmd: Vec<String>,
+    #[serde(default)]
+    pub depends_on: Vec<Dependency>,
+    /// optional command that exits with 0 while the process is healthy
+    #[serde(default)]
+    pub healthcheck: Option<Vec<String>>,
+    /// how long the process may run before it is terminated, e.g. `30s` or `10m`
+    #[serde(default)]
+    pub timeout: Option<String>,
+    /// keep a stdin pipe open so the process can be attached to
+    #[serde(default)]
+    pub stdin: bool,
+    /// run the process on a pty, for programs that behave differently without a terminal
+    #[serde(default)]
+    pub pty: bool,
+    /// what to do when the process exits, the daemon's default policy if unset
+    #[serde(default)]
+    pub restart: Option<RestartPolicy>,
+    /// commands run by the daemon when the process starts, exits and so on
+    #[serde(default)]
+    pub hooks: Hooks,
+}
+
+impl ProcessDefinition {
+    pub fn program(&self) -> OsString {
+        OsString::from(&self.cmd[0])
+    }
+
+    pub fn args(&self) -> Vec<OsString> {
+        self.cmd[1..].iter().map(OsString::from).collect()
+    }
+
+    /// the options this definition's processes are launched with
+    ///
+    /// assumes the definition has passed `validate`
+    pub fn launch_options(&self) -> LaunchOptions {
+        LaunchOptions {
+            timeout: self
+                .timeout
+                .as_ref()
+                .and_then(|timeout| parse_timeout(timeout).ok()),
+            stdin: self.stdin,
+            pty: self.pty,
+        }
+    }
+}
+
+impl LogName for ProcessDefinition {
+    fn log_name(&self) -> PathBuf {
+        timestamped(OsString::from(&self.name))
+    }
+}
+
+/// on-disk layout of a definitions file
+///
+/// each process is declared in its own `[[process]]` table
+#[derive(Deserialize)]
+struct DefinitionFile {
+    #[serde(default)]
+    process: Vec<ProcessDefinition>,
+}
+
+/// read and validate a list of definitions from a toml file
+/// # Arguments
+/// * `path` - the file to read from
+pub fn load(path: &Path) -> Result<Vec<ProcessDefinition>> {
+    let contents = fs::read_to_string(path)
+        .with_context(|| format!("failed to read definitions from {}", path.display()))?;
+    let mut file: DefinitionFile = toml::from_str(&contents)
+        .with_context(|| format!("failed to parse definitions in {}", path.display()))?;
+
+    for def in &mut file.process {
+        validate(def)?;
+    }
+    start_order(&file.process)?;
+
+    Ok(file.process)
+}
+
+/// checks a single definition for obviously invalid values
+/// and parses the timeouts of its hooks
+pub fn validate(def: &mut ProcessDefinition) -> Result<()> {
+    if def.name.is_empty() {
+        bail!("process definitions must have a name");
+    }
+    if def.cmd.is_empty() {
+        bail!("process '{}' has an empty cmd", def.name);
+    }
+    if let Some(check) = &def.healthcheck {
+        if check.is_empty() {
+            bail!("process '{}' has an empty healthcheck", def.name);
+        }
+    }
+    if let Some(timeout) = &def.timeout {
+        parse_timeout(timeout)
+            .with_context(|| format!("process '{}' has an invalid timeout", def.name))?;
+    }
+    let kinds = [
+        HookKind::OnStart,
+        HookKind::OnExit,
+        HookKind::OnFailure,
+        HookKind::OnRestart,
+        HookKind::OnUnhealthy,
+    ];
+    for kind in kinds {
+        let hook = match def.hooks.get_mut(kind) {
+            Some(hook) => hook,
+            None => continue,
+        };
+        if hook.cmd.is_empty() {
+            bail!("process '{}' has an empty {} hook", def.name, kind);
+        }
+        let name = &def.name;
+        hook.timeout_duration = match &hook.timeout {
+            Some(timeout) => Some(parse_timeout(timeout).with_context(|| {
+                format!(
+                    "process '{}' has an invalid timeout for its {} hook",
+                    name, kind
+                )
+            })?),
+            None => None,
+        };
+    }
+
+    Ok(())
+}
+
+/// sorts definitions so that every process comes after the processes it depends on
+///
+/// dependencies on names outside of `defs` are ignored here, since they
+/// may refer to processes that are already managed by the daemon
+/// # Arguments
+/// * `defs` - the definitions to sort
+pub fn start_order(defs: &[ProcessDefinition]) -> Result<Vec<&ProcessDefinition>> {
+    let mut index: HashMap<&str, usize> = HashMap::new();
+    for (i, def) in defs.iter().enumerate() {
+        if index.insert(def.name.as_str(), i).is_some() {
+            bail!("process '{}' is defined more than once", def.name);
+        }
+    }
+
+    // depth-first search, where `state` is 0 for unvisited,
+    // 1 for on the current path and 2 for already sorted
+    let mut state = vec![0u8; defs.len()];
+    let mut path: Vec<usize> = Vec::new();
+    let mut order = Vec::with_capacity(defs.len());
+
+    fn visit<'a>(
+        i: usize,
+        defs: &'a [ProcessDefinition],
+        index: &HashMap<&str, usize>,
+        state: &mut [u8],
+        path: &mut Vec<usize>,
+        order: &mut Vec<&'a ProcessDefinition>,
+    ) -> Result<()> {
+        match state[i] {
+            2 => return Ok(()),
+            1 => {
+                let start = path.iter().position(|&p| p == i).unwrap();
+                let mut cycle: Vec<&str> = path[start..]
+                    .iter()
+                    .map(|&p| defs[p].name.as_str())
+                    .collect();
+                cycle.push(&defs[i].name);
+                bail!("dependency cycle detected: {}", cycle.join(" -> "));
+            }
+            _ => {}
+        }
+
+        state[i] = 1;
+        path.push(i);
+        for dep in &defs[i].depends_on {
+            if let Some(&j) = index.get(dep.name.as_str()) {
+                visit(j, defs, index, state, path, order)?;
+            }
+        }
+        path.pop();
+        state[i] = 2;
+        order.push(&defs[i]);
+
+        Ok(())
+    }
+
+    for i in 0..defs.len() {
+        visit(i, defs, &index, &mut state, &mut path, &mut order)?;
+    }
+
+    Ok(order)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(toml: &str) -> Vec<ProcessDefinition> {
+        toml::from_str::<DefinitionFile>(toml).unwrap().process
+    }
+
+    fn names(order: Vec<&ProcessDefinition>) -> Vec<&str> {
+        order.into_iter().map(|def| def.name.as_str()).collect()
+    }
+
+    #[test]
+    fn validate_rejects_huge_timeouts() {
+        let mut defs = parse(
+            r#"
+            [[process]]
+            name = "web"
+            cmd = ["web"]
+            timeout = "18446744073709551615s"
+            "#,
+        );
+        let err = validate(&mut defs[0]).unwrap_err();
+        assert_eq!(
+            format!("{:#}", err),
+            "process 'web' has an invalid timeout: timeout \
+             '18446744073709551615s' is too long, the longest is 36500d"
+        );
+    }
+
+    #[test]
+    fn validate_parses_hook_timeouts() {
+        let mut defs = parse(
+            r#"
+            [[process]]
+            name = "web"
+            cmd = ["web"]
+            [process.hooks.on_exit]
+            cmd = ["notify"]
+            timeout = "30s"
+            [process.hooks.on_failure]
+            cmd = ["page"]
+            "#,
+        );
+        validate(&mut defs[0]).unwrap();
+        let hooks = &defs[0].hooks;
+        assert_eq!(
+            hooks.on_exit.as_ref().unwrap().timeout_duration,
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(hooks.on_failure.as_ref().unwrap().timeout_duration, None);
+
+        let mut defs = parse(
+            r#"
+            [[process]]
+            name = "web"
+            cmd = ["web"]
+            [process.hooks.on_exit]
+            cmd = ["notify"]
+            timeout = "18446744073709551615s"
+            "#,
+        );
+        assert!(validate(&mut defs[0]).is_err());
+    }
+
+    #[test]
+    fn start_order_puts_dependencies_first() {
+        let defs = parse(
+            r#"
+            [[process]]
+            name = "web"
+            cmd = ["web"]
+            depends_on = [{ name = "db" }, { name = "cache" }]
+
+            [[process]]
+            name = "cache"
+            cmd = ["cache"]
+            depends_on = [{ name = "db" }]
+
+            [[process]]
+            name = "db"
+            cmd = ["db"]
+            "#,
+        );
+        assert_eq!(names(start_order(&defs).unwrap()), ["db", "cache", "web"]);
+    }
+
+    #[test]
+    fn start_order_ignores_unknown_dependencies() {
+        let defs = parse(
+            r#"
+            [[process]]
+            name = "web"
+            cmd = ["web"]
+            depends_on = [{ name = "already-running" }]
+            "#,
+        );
+        assert_eq!(names(start_order(&defs).unwrap()), ["web"]);
+    }
+
+    #[test]
+    fn start_order_detects_cycles() {
+        let defs = parse(
+            r#"
+            [[process]]
+            name = "a"
+            cmd = ["a"]
+            depends_on = [{ name = "b" }]
+
+            [[process]]
+            name = "b"
+            cmd = ["b"]
+            depends_on = [{ name = "a" }]
+            "#,
+        );
+        let err = start_order(&defs).unwrap_err();
+        assert_eq!(err.to_string(), "dependency cycle detected: a -> b -> a");
+    }
+
+    #[test]
+    fn start_order_detects_self_dependencies() {
+        let defs = parse(
+            r#"
+            [[process]]
+            name = "a"
+            cmd = ["a"]
+            depends_on = [{ name = "a" }]
+            "#,
+        );
+        let err = start_order(&defs).unwrap_err();
+        assert_eq!(err.to_string(), "dependency cycle detected: a -> a");
+    }
+
+    #[test]
+    fn start_order_rejects_duplicate_names() {
+        let defs = parse(
+            r#"
+            [[process]]
+            name = "a"
+            cmd = ["a"]
+
+            [[process]]
+            name = "a"
+            cmd = ["b"]
+            "#,
+        );
+        let err = start_order(&defs).unwrap_err();
+        assert_eq!(err.to_string(), "process 'a' is defined more than once");
+    }
+}
@@ -0,0 +1,361 @@
+use crate::processing::{HealthStatus, SibylPID};
+use anyhow::{bail, Result};
+use chrono::{DateTime, Local, Utc};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver, SyncSender};
+
+/// the event types clients can filter on, as they appear in the `type` field
+pub const EVENT_TYPES: [&str; 9] = [
+    "started",
+    "exited",
+    "restarted",
+    "crash_looping",
+    "quarantined",
+    "health_changed",
+    "log_rotated",
+    "logs_pruned",
+    "config_reloaded",
+];
+
+/// something that happened to the daemon or one of its processes
+#[derive(Serialize, Debug, Clone)]
+pub struct Event {
+    pub time: DateTime<Utc>,
+    #[serde(flatten)]
+    pub kind: EventKind,
+}
+
+#[derive(Serialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum EventKind {
+    Started {
+        spid: SibylPID,
+        /// the definition name, or else the program
+        name: String,
+        cmdline: String,
+    },
+    /// carries the exit code, or the signal that killed the process
+    Exited {
+        spid: SibylPID,
+        name: String,
+        code: Option<i32>,
+        signal: Option<i32>,
+        timed_out: bool,
+        /// whether the process was stopped on purpose, e.g. by `sibyl stop`
+        stopped: bool,
+    },
+    /// the supervisor started a new instance of a definition in place of one that exited
+    Restarted {
+        name: String,
+        previous_spid: SibylPID,
+        spid: SibylPID,
+        restarts: u32,
+    },
+    /// a definition's processes keep failing soon after they are started
+    CrashLooping {
+        name: String,
+        /// how many times they failed within the window
+        failures: usize,
+        window_secs: u64,
+    },
+    /// a crash-looping definition stopped being restarted until `sibyl reset`
+    Quarantined {
+        name: String,
+        /// failures since it started crash-looping
+        failures: u32,
+    },
+    HealthChanged {
+        spid: SibylPID,
+        name: String,
+        from: HealthStatus,
+        to: HealthStatus,
+    },
+    /// a process started writing to a new log file, which happens when a
+    /// new instance is started in place of one that exited
+    LogRotated {
+        spid: SibylPID,
+        name: String,
+        /// the log file of the previous instance
+        from: PathBuf,
+        to: PathBuf,
+    },
+    /// old logs were deleted according to the retention settings
+    LogsPruned { deleted: usize },
+    ConfigReloaded {
+        /// one line per setting or definition that changed
+        changes: Vec<String>,
+    },
+}
+
+impl EventKind {
+    /// the name of the event's type, one of EVENT_TYPES
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            EventKind::Started { .. } => "started",
+            EventKind::Exited { .. } => "exited",
+            EventKind::Restarted { .. } => "restarted",
+            EventKind::CrashLooping { .. } => "crash_looping",
+            EventKind::Quarantined { .. } => "quarantined",
+            EventKind::HealthChanged { .. } => "health_changed",
+            EventKind::LogRotated { .. } => "log_rotated",
+            EventKind::LogsPruned { .. } => "logs_pruned",
+            EventKind::ConfigReloaded { .. } => "config_reloaded",
+        }
+    }
+
+    /// the process the event is about, None for daemon-wide events
+    pub fn process_name(&self) -> Option<&str> {
+        match self {
+            EventKind::Started { name, .. }
+            | EventKind::Exited { name, .. }
+            | EventKind::Restarted { name, .. }
+            | EventKind::CrashLooping { name, .. }
+            | EventKind::Quarantined { name, .. }
+            | EventKind::HealthChanged { name, .. }
+            | EventKind::LogRotated { name, .. } => Some(name),
+            EventKind::LogsPruned { .. } | EventKind::ConfigReloaded { .. } => None,
+        }
+    }
+
+    /// whether the event is a process exiting unsuccessfully without having been stopped
+    pub fn is_failure(&self) -> bool {
+        match *self {
+            EventKind::Exited {
+                code,
+                timed_out,
+                stopped,
+                ..
+            } => (code != Some(0) || timed_out) && !stopped,
+            _ => false,
+        }
+    }
+}
+
+impl Event {
+    /// an event that is happening now
+    pub fn new(kind: EventKind) -> Event {
+        Event {
+            time: Utc::now(),
+            kind,
+        }
+    }
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {:<15} ",
+            self.time.with_timezone(&Local).format("%Y-%m-%d %H:%M:%S"),
+            self.kind.type_name()
+        )?;
+        match &self.kind {
+            EventKind::Started {
+                spid,
+                name,
+                cmdline,
+            } => write!(f, "'{}' (spid {}): {}", name, spid, cmdline),
+            EventKind::Exited {
+                spid,
+                name,
+                code,
+                signal,
+                timed_out,
+                stopped,
+            } => {
+                write!(f, "'{}' (spid {})", name, spid)?;
+                match (code, signal) {
+                    (Some(code), _) => write!(f, " with exit code {}", code)?,
+                    (None, Some(signal)) => write!(f, " killed by signal {}", signal)?,
+                    (None, None) => write!(f, ", exit code unknown")?,
+                }
+                if *timed_out {
+                    write!(f, " after timing out")?;
+                }
+                if *stopped {
+                    write!(f, ", stopped")?;
+                }
+                Ok(())
+            }
+            EventKind::Restarted {
+                name,
+                previous_spid,
+                spid,
+                restarts,
+            } => write!(
+                f,
+                "'{}' (spid {} -> {}), restart {}",
+                name, previous_spid, spid, restarts
+            ),
+            EventKind::CrashLooping {
+                name,
+                failures,
+                window_secs,
+            } => write!(
+                f,
+                "'{}' failed {} times within {}s",
+                name, failures, window_secs
+            ),
+            EventKind::Quarantined { name, failures } => write!(
+                f,
+                "'{}' is no longer restarted after {} more failures",
+                name, failures
+            ),
+            EventKind::HealthChanged {
+                spid,
+                name,
+                from,
+                to,
+            } => write!(f, "'{}' (spid {}): {} -> {}", name, spid, from, to),
+            EventKind::LogRotated {
+                spid,
+                name,
+                from,
+                to,
+            } => write!(
+                f,
+                "'{}' (spid {}): {} -> {}",
+                name,
+                spid,
+                from.display(),
+                to.display()
+            ),
+            EventKind::LogsPruned { deleted } => write!(f, "deleted {} old log files", deleted),
+            EventKind::ConfigReloaded { changes } if changes.is_empty() => {
+                write!(f, "nothing changed")
+            }
+            EventKind::ConfigReloaded { changes } => write!(f, "{}", changes.join("; ")),
+        }
+    }
+}
+
+/// selects the events a subscriber is sent
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct EventFilter {
+    /// only events about processes with these names, all events if empty
+    pub names: Vec<String>,
+    /// only events of these types, all types if empty
+    pub types: Vec<String>,
+}
+
+impl EventFilter {
+    /// builds a filter, refusing event types that don't exist
+    pub fn new(names: Vec<String>, types: Vec<String>) -> Result<EventFilter> {
+        for kind in &types {
+            if !EVENT_TYPES.contains(&kind.as_str()) {
+                bail!(
+                    "unknown event type '{}', expected one of {}",
+                    kind,
+                    EVENT_TYPES.join(", ")
+                );
+            }
+        }
+        Ok(EventFilter { names, types })
+    }
+
+    pub fn matches(&self, event: &Event) -> bool {
+        if !self.types.is_empty() && !self.types.iter().any(|t| t == event.kind.type_name()) {
+            return false;
+        }
+        // daemon-wide events are not about any one process, so naming processes leaves them out
+        self.names.is_empty()
+            || event
+                .kind
+                .process_name()
+                .is_some_and(|name| self.names.iter().any(|n| n == name))
+    }
+}
+
+/// how many events a subscriber may fall behind before it is dropped
+const SUBSCRIBER_BACKLOG: usize = 1024;
+
+/// hands every event to the clients subscribed to them
+#[derive(Default)]
+pub struct EventBus {
+    subscribers: Vec<SyncSender<Event>>,
+}
+
+impl EventBus {
+    /// returns a receiver for all events from now on
+    ///
+    /// the receiver is disconnected once it has taken the events that were sent to it, if
+    /// it either goes away or falls more than SUBSCRIBER_BACKLOG events behind
+    pub fn subscribe(&mut self) -> Receiver<Event> {
+        let (sender, receiver) = mpsc::sync_channel(SUBSCRIBER_BACKLOG);
+        self.subscribers.push(sender);
+        receiver
+    }
+
+    /// sends an event to every subscriber, forgetting those that have gone away
+    /// or can't keep up, so that a stuck client can't make the daemon hoard events
+    pub fn emit(&mut self, event: Event) {
+        self.subscribers
+            .retain(|subscriber| subscriber.try_send(event.clone()).is_ok());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pruned() -> Event {
+        Event::new(EventKind::LogsPruned { deleted: 1 })
+    }
+
+    #[test]
+    fn slow_subscribers_are_dropped() {
+        let mut bus = EventBus::default();
+        let slow = bus.subscribe();
+        let fast = bus.subscribe();
+
+        for _ in 0..SUBSCRIBER_BACKLOG {
+            bus.emit(pruned());
+            assert!(fast.try_recv().is_ok());
+        }
+        assert_eq!(bus.subscribers.len(), 2);
+
+        // one event too many for the subscriber that never reads
+        bus.emit(pruned());
+        assert_eq!(bus.subscribers.len(), 1);
+        assert!(fast.try_recv().is_ok());
+
+        // it still gets what was sent before it was dropped
+        assert_eq!(slow.try_iter().count(), SUBSCRIBER_BACKLOG);
+        assert_eq!(
+            slow.try_recv().unwrap_err(),
+            mpsc::TryRecvError::Disconnected
+        );
+    }
+
+    #[test]
+    fn subscribers_that_went_away_are_dropped() {
+        let mut bus = EventBus::default();
+        drop(bus.subscribe());
+        bus.emit(pruned());
+        assert!(bus.subscribers.is_empty());
+    }
+
+    #[test]
+    fn log_rotations_are_filtered_by_process() {
+        let rotated = Event::new(EventKind::LogRotated {
+            spid: 2,
+            name: String::from("web"),
+            from: PathBuf::from("logs/web_1.slog"),
+            to: PathBuf::from("logs/web_2.slog"),
+        });
+        let filter = |names: &[&str], types: &[&str]| {
+            let names = names.iter().map(|s| s.to_string()).collect();
+            let types = types.iter().map(|s| s.to_string()).collect();
+            EventFilter::new(names, types).unwrap()
+        };
+
+        assert!(filter(&["web"], &["log_rotated"]).matches(&rotated));
+        assert!(!filter(&["db"], &[]).matches(&rotated));
+        assert!(!filter(&[], &["logs_pruned"]).matches(&rotated));
+        assert!(rotated
+            .to_string()
+            .ends_with("log_rotated     'web' (spid 2): logs/web_1.slog -> logs/web_2.slog"));
+    }
+}
@@ -0,0 +1,180 @@
+use crate::definitions::{Hook, HookKind};
+use crate::logging::{timestamped, LogHandler, LogName};
+use crate::processing::ChildHandle;
+use crate::state::SavedHook;
+use crate::util::MAX_TIMEOUT;
+use anyhow::{Context, Result};
+use log::{info, warn};
+use std::ffi::OsString;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+/// how long a hook may run if its definition doesn't say
+const DEFAULT_HOOK_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// the log file of one run of a hook, named after the definition and the hook
+struct HookLog<'a> {
+    name: &'a str,
+    kind: HookKind,
+}
+
+impl LogName for HookLog<'_> {
+    fn log_name(&self) -> PathBuf {
+        timestamped(OsString::from(format!("hook_{}_{}", self.name, self.kind)))
+    }
+}
+
+/// a hook that has been started and not yet reaped
+struct RunningHook {
+    /// the leader of the hook's process group
+    child: ChildHandle,
+    /// e.g. `on_failure hook of 'web'`, for the daemon's log
+    what: String,
+    /// none if the timeout is too long for a deadline to be represented
+    deadline: Option<Instant>,
+    /// whether it was killed for running past its deadline
+    killed: bool,
+}
+
+/// runs the hooks of definitions and keeps track of them until they exit
+#[derive(Default)]
+pub struct HookRunner {
+    running: Vec<RunningHook>,
+}
+
+impl HookRunner {
+    /// starts a hook in the background, with its output sent to a log file of its own
+    /// # Arguments
+    /// * `hook` - the hook to run
+    /// * `name` - the name of the definition the hook belongs to
+    /// * `kind` - the transition the hook is run for
+    /// * `env` - the SIBYL_* variables describing the transition
+    /// * `loghandler` - used to create the hook's log file
+    pub fn run(
+        &mut self,
+        hook: &Hook,
+        name: &str,
+        kind: HookKind,
+        env: &[(&str, String)],
+        loghandler: &mut LogHandler,
+    ) -> Result<()> {
+        let what = format!("{} hook of '{}'", kind, name);
+        let timeout = hook.timeout_duration.unwrap_or(DEFAULT_HOOK_TIMEOUT);
+
+        let logfile = loghandler.create_log(&HookLog { name, kind })?;
+        let output = logfile.open()?;
+        let mut cmd = Command::new(&hook.cmd[0]);
+        cmd.args(&hook.cmd[1..])
+            .envs(env.iter().map(|(key, value)| (key, value)))
+            .stdin(Stdio::null())
+            .stderr(output.try_clone()?)
+            .stdout(output);
+        // hooks are often shell scripts, whose children have to be killed along with them
+        #[cfg(unix)]
+        std::os::unix::process::CommandExt::process_group(&mut cmd, 0);
+        let child = cmd
+            .spawn()
+            .with_context(|| format!("failed to run the {}", what))?;
+
+        info!("started the {}", what);
+        self.running.push(RunningHook {
+            child: ChildHandle::Spawned(child),
+            what,
+            deadline: Instant::now().checked_add(timeout),
+            killed: false,
+        });
+        Ok(())
+    }
+
+    /// reaps the hooks that have exited and kills those that ran past their timeout
+    ///
+    /// killed hooks are reaped on a later tick, so that this never blocks
+    pub fn tick(&mut self) {
+        let now = Instant::now();
+        self.running.retain_mut(|hook| match hook.child.try_wait() {
+            Ok(Some(_)) if hook.killed => false,
+            Ok(Some(Some(0))) => {
+                info!("the {} finished", hook.what);
+                false
+            }
+            Ok(Some(Some(code))) => {
+                warn!("the {} failed with exit code {}", hook.what, code);
+                false
+            }
+            Ok(Some(None)) => {
+                warn!("the {} was killed by a signal", hook.what);
+                false
+            }
+            Ok(None) if hook.deadline.is_some_and(|d| now >= d) && !hook.killed => {
+                warn!("the {} timed out, killing it", hook.what);
+                kill_group(&mut hook.child);
+                hook.killed = true;
+                true
+            }
+            Ok(None) => true,
+            Err(e) => {
+                warn!("failed to wait for the {}: {}", hook.what, e);
+                false
+            }
+        });
+    }
+
+    /// kills every hook that is still running, for when the daemon exits
+    pub fn kill_all(&mut self) {
+        for mut hook in self.running.drain(..) {
+            if let Ok(None) = hook.child.try_wait() {
+                warn!("killing the {}, sibyld is exiting", hook.what);
+                kill_group(&mut hook.child);
+            }
+        }
+    }
+
+    /// describes the running hooks, for an upgraded daemon to take over with `inherit`
+    pub fn save(&self) -> Vec<SavedHook> {
+        let now = Instant::now();
+        self.running
+            .iter()
+            .map(|hook| SavedHook {
+                os_pid: hook.child.id(),
+                what: hook.what.clone(),
+                timeout_left: hook
+                    .deadline
+                    .map_or(MAX_TIMEOUT, |d| d.saturating_duration_since(now)),
+                killed: hook.killed,
+            })
+            .collect()
+    }
+
+    /// takes over the hooks the daemon started before it was upgraded in place,
+    /// which are still its children
+    pub fn inherit(&mut self, hooks: Vec<SavedHook>) {
+        let now = Instant::now();
+        for hook in hooks {
+            info!("took over the {} (pid {})", hook.what, hook.os_pid);
+            self.running.push(RunningHook {
+                child: ChildHandle::Inherited {
+                    pid: hook.os_pid,
+                    code: None,
+                },
+                what: hook.what,
+                deadline: now.checked_add(hook.timeout_left.min(MAX_TIMEOUT)),
+                killed: hook.killed,
+            });
+        }
+    }
+}
+
+/// kills a hook along with everything it started
+#[cfg(unix)]
+fn kill_group(child: &mut ChildHandle) {
+    // safety: killpg(2) has no memory-safety requirements
+    if unsafe { libc::killpg(child.id() as libc::pid_t, libc::SIGKILL) } != 0 {
+        let _ = child.kill();
+    }
+}
+
+#[cfg(not(unix))]
+fn kill_group(child: &mut ChildHandle) {
+    let _ = child.kill();
+}
@@ -0,0 +1,611 @@
+use crate::auth::Identity;
+use crate::commands::{
+    process_request, wait_for_stop, Action, CmdList, CmdLog, CmdMetrics, CmdOnce, CmdStatus,
+    CmdStop, CommandContext, RequestError, SharedContext,
+};
+use crate::processing::{CrashState, LaunchOptions, ProcessStatus, ProcessWaitStatus, SibylPID};
+use crate::util::parse_timeout;
+use crate::Request;
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Local, Utc};
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::ffi::OsString;
+use std::io::Read;
+use std::net::{IpAddr, SocketAddr, TcpListener};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::thread;
+use tiny_http::{Header, Method, Server};
+
+/// the largest request body the API accepts
+const MAX_BODY: u64 = 64 * 1024;
+
+/// serves the JSON API on a listener until the daemon exits
+///
+/// every request is authenticated, authorized and audited the same way
+/// as the requests of the binary protocol
+/// # Arguments
+/// * `listener` - the socket to accept connections on
+/// * `ctx` - the command context
+pub fn serve(listener: TcpListener, ctx: SharedContext) -> Result<()> {
+    let local = listener.local_addr()?;
+    let server = Server::from_listener(listener, None).map_err(|e| anyhow!(e))?;
+
+    for request in server.incoming_requests() {
+        // each request gets its own thread, since stopping a process can take a while
+        let ctx = Arc::clone(&ctx);
+        thread::spawn(move || handle_request(request, local, &ctx));
+    }
+    Ok(())
+}
+
+/// what the API sends back, as a status code and a body that is JSON unless it says otherwise
+#[derive(Debug)]
+struct Reply {
+    status: u16,
+    body: String,
+    content_type: &'static str,
+}
+
+impl Reply {
+    fn json(status: u16, body: Value) -> Reply {
+        Reply {
+            status,
+            body: body.to_string(),
+            content_type: "application/json",
+        }
+    }
+
+    fn ok(body: Value) -> Reply {
+        Reply::json(200, body)
+    }
+
+    fn error(status: u16, msg: impl ToString) -> Reply {
+        Reply::json(status, json!({ "error": msg.to_string() }))
+    }
+}
+
+/// the endpoints of the API
+enum Route {
+    /// `GET /processes`
+    List,
+    /// `POST /processes`
+    Launch,
+    /// `GET /processes/{id}`
+    Status(SibylPID),
+    /// `POST /processes/{id}/stop`
+    Stop(SibylPID),
+    /// `GET /processes/{id}/log`
+    Log(SibylPID),
+    /// `GET /metrics`, in the Prometheus text format rather than JSON
+    Metrics,
+}
+
+impl Route {
+    /// the process a route is about, if any
+    fn spid(&self) -> Option<SibylPID> {
+        match *self {
+            Route::Status(spid) | Route::Stop(spid) | Route::Log(spid) => Some(spid),
+            Route::List | Route::Launch | Route::Metrics => None,
+        }
+    }
+}
+
+/// the body of `POST /processes`, which mirrors `sibyl once`
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LaunchBody {
+    program: String,
+    #[serde(default)]
+    args: Vec<String>,
+    /// e.g. `30s` or `5m`
+    #[serde(default)]
+    timeout: Option<String>,
+    #[serde(default)]
+    stdin: bool,
+    #[serde(default)]
+    pty: bool,
+}
+
+/// a process as the API describes it
+#[derive(Serialize)]
+struct ProcessView {
+    spid: SibylPID,
+    name: Option<String>,
+    cmdline: String,
+    started: DateTime<Local>,
+    os_pid: u32,
+    /// `running`, `exited`, `timed_out` or `unknown`
+    state: &'static str,
+    exit_code: Option<i32>,
+    health: Option<String>,
+    restarts: u32,
+    /// `backoff` or `quarantined` for a crash-looping definition's latest process
+    crash_loop: Option<&'static str>,
+    log_file: PathBuf,
+}
+
+impl From<ProcessStatus> for ProcessView {
+    fn from(status: ProcessStatus) -> Self {
+        let (state, exit_code) = match status.status {
+            ProcessWaitStatus::Running(_) => ("running", None),
+            ProcessWaitStatus::Exited(code) => ("exited", code),
+            ProcessWaitStatus::TimedOut(code) => ("timed_out", code),
+            ProcessWaitStatus::Unknown => ("unknown", None),
+        };
+        ProcessView {
+            spid: status.internal_pid,
+            name: status.name,
+            cmdline: status.cmdline.to_string_lossy().into_owned(),
+            started: status.started,
+            os_pid: status.os_pid,
+            state,
+            exit_code,
+            health: status.health.map(|health| health.to_string()),
+            restarts: status.restarts,
+            crash_loop: match status.crash_state {
+                Some(CrashState::Backoff { .. }) => Some("backoff"),
+                Some(CrashState::Quarantined { .. }) => Some("quarantined"),
+                Some(CrashState::Stable) | None => None,
+            },
+            log_file: status.log_path,
+        }
+    }
+}
+
+fn handle_request(mut request: tiny_http::Request, local: SocketAddr, ctx: &SharedContext) {
+    let method = request.method().clone();
+    let url = request.url().to_string();
+    let reply = respond(&mut request, local, ctx);
+    debug!("{} {} -> {}", method, url, reply.status);
+
+    let mut response = tiny_http::Response::from_string(reply.body)
+        .with_status_code(reply.status)
+        .with_header(Header::from_bytes("Content-Type", reply.content_type).unwrap());
+    if reply.status == 401 {
+        response.add_header(Header::from_bytes("WWW-Authenticate", "Bearer").unwrap());
+    }
+    if let Err(e) = request.respond(response) {
+        warn!("failed to send http response: {}", e);
+    }
+}
+
+fn respond(request: &mut tiny_http::Request, local: SocketAddr, shared: &SharedContext) -> Reply {
+    let peer = match request.remote_addr() {
+        Some(peer) => *peer,
+        None => return Reply::error(400, "unknown client address"),
+    };
+    // browsers send an Origin header along with cross-site requests, which scripts on other
+    // sites could otherwise use to drive the API, and a Host that isn't the listener's own
+    // means the name it was reached by was pointed at it, e.g. by DNS rebinding
+    if header(request, "Origin").is_some() {
+        warn!("refused cross-origin http request from {}", peer);
+        return Reply::error(403, "cross-origin requests are not allowed");
+    }
+    if let Err(e) = check_host(request, local) {
+        warn!("refused http request from {}: {:#}", peer, e);
+        return Reply::error(400, e);
+    }
+    let identity = match authenticate(request, peer, local, shared) {
+        Ok(identity) => identity,
+        Err(e) => {
+            warn!("refused http request from {}: {:#}", peer, e);
+            return Reply::error(401, "authentication failed");
+        }
+    };
+
+    let (route, action) = match route(request) {
+        Ok(route) => route,
+        Err(reply) => return reply,
+    };
+    let req = Request {
+        command: action,
+        time: Utc::now(),
+    };
+
+    let mut ctx = shared.lock().unwrap();
+    // clients that may not perform the action don't get to learn whether the process exists
+    if let Some(spid) = route.spid() {
+        if ctx.prochandler.get_process_by_pid(spid).is_none()
+            && ctx.authorize(&identity, req.command.permission())
+        {
+            return Reply::error(404, format!("no process with SPID {}", spid));
+        }
+    }
+
+    let res = match process_request(&req, &identity, &mut ctx) {
+        Ok(res) => res,
+        Err(e @ RequestError::Denied(_)) => return Reply::error(403, e),
+        Err(e) => return Reply::error(500, e),
+    };
+    match route {
+        Route::List => Reply::ok(list_processes(&mut ctx)),
+        Route::Status(spid) => match ctx.prochandler.get_process_status(spid) {
+            Some(status) => Reply::ok(json!(ProcessView::from(status))),
+            None => Reply::error(404, format!("no process with SPID {}", spid)),
+        },
+        Route::Launch => Reply::json(201, json!({ "spid": res.spid, "message": res.msg })),
+        Route::Stop(spid) => {
+            // the process is only sent SIGTERM, waiting for it must not hold up the daemon
+            let grace = ctx.config.stop_timeout();
+            drop(ctx);
+            if res.spid.is_some() {
+                if let Err(e) = wait_for_stop(shared, spid, grace) {
+                    return Reply::error(500, e);
+                }
+            }
+            let message = res
+                .spid
+                .map_or(res.msg, |_| format!("stopped SPID {}", spid));
+            Reply::ok(json!({ "spid": spid, "message": message }))
+        }
+        Route::Log(spid) => Reply::ok(json!({ "spid": spid, "log": res.msg })),
+        Route::Metrics => Reply {
+            status: 200,
+            body: res.msg,
+            content_type: "text/plain; version=0.0.4",
+        },
+    }
+}
+
+/// the value of one of the request's headers
+fn header<'a>(request: &'a tiny_http::Request, name: &'static str) -> Option<&'a str> {
+    request
+        .headers()
+        .iter()
+        .find(|header| header.field.equiv(name))
+        .map(|header| header.value.as_str().trim())
+}
+
+/// checks that the request's Host is the address it was sent to, or `localhost`
+///
+/// other names are refused, since a name that leads to the API can be made to by
+/// whoever controls it. remote access by name should go through a proxy
+fn check_host(request: &tiny_http::Request, local: SocketAddr) -> Result<()> {
+    let host = header(request, "Host").ok_or_else(|| anyhow!("the Host header is missing"))?;
+    let (name, port) = match host.rsplit_once(':') {
+        // a bare IPv6 address has colons of its own
+        Some((name, port)) if name.ends_with(']') || !name.contains(':') => {
+            (name, port.parse::<u16>().ok())
+        }
+        // a client leaves out the port if it's the default one
+        _ => (host, Some(80)),
+    };
+    let name = name.trim_start_matches('[').trim_end_matches(']');
+    let same_address = name.eq_ignore_ascii_case("localhost")
+        || name
+            .parse::<IpAddr>()
+            .is_ok_and(|ip| local.ip().is_unspecified() || ip == local.ip());
+    if same_address && port == Some(local.port()) {
+        Ok(())
+    } else {
+        Err(anyhow!("'{}' is not an address the api is served on", host))
+    }
+}
+
+/// checks the request's bearer token the way the daemon checks handshakes
+///
+/// unlike other clients, a token is required no matter what `auth` says, since any
+/// program that can make HTTP requests could otherwise use the API
+fn authenticate(
+    request: &tiny_http::Request,
+    peer: SocketAddr,
+    mut local: SocketAddr,
+    ctx: &SharedContext,
+) -> Result<Identity> {
+    let token = match header(request, "Authorization") {
+        Some(value) => match value.strip_prefix("Bearer ") {
+            Some(token) => token.trim(),
+            None => return Err(anyhow!("only bearer tokens are supported")),
+        },
+        None => return Err(anyhow!("a token is required")),
+    };
+
+    let name = ctx.lock().unwrap().authenticate(Some(token), peer.ip())?;
+    // a listener on a wildcard address sees loopback clients connect to the loopback address,
+    // which is what finding the client's user needs
+    if local.ip().is_unspecified() && peer.ip().is_loopback() {
+        local.set_ip(peer.ip());
+    }
+    Ok(Identity::of_peer(peer, local, name))
+}
+
+/// works out the endpoint a request is for and the action it stands for
+fn route(request: &mut tiny_http::Request) -> Result<(Route, Box<dyn Action>), Reply> {
+    let path = request.url().split('?').next().unwrap_or("").to_string();
+    let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+    let method = request.method().clone();
+
+    let spid = |id: &str| {
+        id.parse::<SibylPID>()
+            .map_err(|_| Reply::error(404, format!("no process with SPID {}", id)))
+    };
+    let not_allowed = || Reply::error(405, format!("{} is not allowed on {}", method, path));
+
+    match segments.as_slice() {
+        ["processes"] => match method {
+            Method::Get => Ok((Route::List, Box::new(CmdList))),
+            Method::Post => Ok((Route::Launch, Box::new(launch_action(request)?))),
+            _ => Err(not_allowed()),
+        },
+        ["processes", id] => match method {
+            Method::Get => {
+                let pid = spid(id)?;
+                Ok((Route::Status(pid), Box::new(CmdStatus { pid })))
+            }
+            _ => Err(not_allowed()),
+        },
+        ["processes", id, "stop"] => match method {
+            Method::Post => {
+                let pid = spid(id)?;
+                Ok((Route::Stop(pid), Box::new(CmdStop { pid })))
+            }
+            _ => Err(not_allowed()),
+        },
+        ["processes", id, "log"] => match method {
+            Method::Get => {
+                let pid = spid(id)?;
+                Ok((Route::Log(pid), Box::new(CmdLog { pid, follow: false })))
+            }
+            _ => Err(not_allowed()),
+        },
+        ["metrics"] => match method {
+            Method::Get => Ok((Route::Metrics, Box::new(CmdMetrics))),
+            _ => Err(not_allowed()),
+        },
+        _ => Err(Reply::error(404, format!("no such endpoint {}", path))),
+    }
+}
+
+/// builds the `once` action described by the body of `POST /processes`
+fn launch_action(request: &mut tiny_http::Request) -> Result<CmdOnce, Reply> {
+    // other types are what an HTML form can send without the browser asking first
+    let is_json = header(request, "Content-Type").is_some_and(|value| {
+        let media_type = value.split(';').next().unwrap_or("").trim();
+        media_type.eq_ignore_ascii_case("application/json")
+    });
+    if !is_json {
+        return Err(Reply::error(
+            415,
+            "the request body must be application/json",
+        ));
+    }
+
+    let mut body = Vec::new();
+    request
+        .as_reader()
+        .take(MAX_BODY + 1)
+        .read_to_end(&mut body)
+        .map_err(|e| Reply::error(400, format!("failed to read request body: {}", e)))?;
+    if body.len() as u64 > MAX_BODY {
+        return Err(Reply::error(413, "request body is too large"));
+    }
+
+    let body: LaunchBody = serde_json::from_slice(&body)
+        .map_err(|e| Reply::error(400, format!("invalid request body: {}", e)))?;
+    let timeout = body
+        .timeout
+        .as_deref()
+        .map(parse_timeout)
+        .transpose()
+        .map_err(|e| Reply::error(400, format!("{:#}", e)))?;
+
+    Ok(CmdOnce {
+        program: OsString::from(body.program),
+        args: body.args.into_iter().map(OsString::from).collect(),
+        options: LaunchOptions {
+            timeout,
+            stdin: body.stdin,
+            pty: body.pty,
+        },
+    })
+}
+
+/// every process the daemon knows, along with the definitions waiting to be started
+fn list_processes(ctx: &mut CommandContext) -> Value {
+    let spids: Vec<SibylPID> = ctx
+        .prochandler
+        .all_processes()
+        .iter()
+        .map(|proc| proc.pid)
+        .collect();
+    let processes: Vec<ProcessView> = spids
+        .into_iter()
+        .filter_map(|spid| ctx.prochandler.get_process_status(spid))
+        .map(ProcessView::from)
+        .collect();
+    let pending: Vec<Value> = ctx
+        .prochandler
+        .pending()
+        .iter()
+        .map(|def| json!({ "name": def.name, "cmd": def.cmd }))
+        .collect();
+
+    json!({ "processes": processes, "pending": pending })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::TempDir;
+    use std::sync::Mutex;
+    use std::time::Duration;
+    use tiny_http::TestRequest;
+
+    fn request(
+        method: Method,
+        path: &str,
+        headers: &[(&str, &str)],
+        body: &'static str,
+    ) -> tiny_http::Request {
+        let mut request = TestRequest::new()
+            .with_method(method)
+            .with_path(path)
+            .with_body(body);
+        for (field, value) in headers {
+            request = request.with_header(Header::from_bytes(*field, *value).unwrap());
+        }
+        request.into()
+    }
+
+    fn get(path: &str, headers: &[(&str, &str)]) -> tiny_http::Request {
+        request(Method::Get, path, headers, "")
+    }
+
+    /// a context with a single token named `ci`, returned along with the token
+    fn context(dir: &TempDir) -> (SharedContext, String) {
+        let mut ctx = CommandContext::for_tests(dir.path());
+        let token = ctx.tokens.add("ci").unwrap();
+        (Arc::new(Mutex::new(ctx)), token)
+    }
+
+    fn status<T>(result: Result<T, Reply>) -> u16 {
+        match result {
+            Ok(_) => 200,
+            Err(reply) => reply.status,
+        }
+    }
+
+    #[test]
+    fn host_must_be_the_listener_address() {
+        let local: SocketAddr = "127.0.0.1:8080".parse().unwrap();
+        let ok = |host| check_host(&get("/", &[("Host", host)]), local).is_ok();
+        assert!(ok("127.0.0.1:8080"));
+        assert!(ok("localhost:8080"));
+        assert!(ok("LocalHost:8080"));
+        assert!(!ok("127.0.0.1:8081"));
+        assert!(!ok("127.0.0.2:8080"));
+        assert!(!ok("sibyl.example:8080"));
+        // without a port, the client means port 80
+        assert!(!ok("127.0.0.1"));
+        assert!(check_host(&get("/", &[]), local).is_err());
+
+        // a listener on a wildcard address is reached by any of the host's addresses
+        let any: SocketAddr = "0.0.0.0:8080".parse().unwrap();
+        assert!(check_host(&get("/", &[("Host", "10.1.2.3:8080")]), any).is_ok());
+        assert!(check_host(&get("/", &[("Host", "sibyl.example:8080")]), any).is_err());
+    }
+
+    #[test]
+    fn host_may_be_an_ipv6_address() {
+        let local: SocketAddr = "[::1]:8080".parse().unwrap();
+        let ok = |host| check_host(&get("/", &[("Host", host)]), local).is_ok();
+        assert!(ok("[::1]:8080"));
+        assert!(ok("localhost:8080"));
+        assert!(!ok("[::1]:8081"));
+        assert!(!ok("[::2]:8080"));
+        assert!(!ok("[::1]"));
+        assert!(!ok("::1"));
+
+        let default_port: SocketAddr = "[::1]:80".parse().unwrap();
+        assert!(check_host(&get("/", &[("Host", "[::1]")]), default_port).is_ok());
+    }
+
+    #[test]
+    fn a_bearer_token_is_always_required() {
+        let dir = TempDir::new("http-authenticate");
+        let (ctx, token) = context(&dir);
+        let peer: SocketAddr = "127.0.0.1:40000".parse().unwrap();
+        let local: SocketAddr = "127.0.0.1:8080".parse().unwrap();
+        let auth = |headers: &[(&str, &str)]| authenticate(&get("/", headers), peer, local, &ctx);
+
+        let bearer = format!("Bearer {}", token);
+        let identity = auth(&[("Authorization", &bearer)]).unwrap();
+        assert_eq!(identity.token.as_deref(), Some("ci"));
+
+        // loopback clients need no token for the binary protocol, but do here
+        assert!(auth(&[]).is_err());
+        assert!(auth(&[("Authorization", "Bearer wrong")]).is_err());
+        assert!(auth(&[("Authorization", "Bearer ")]).is_err());
+        let basic = format!("Basic {}", token);
+        assert!(auth(&[("Authorization", &basic)]).is_err());
+    }
+
+    #[test]
+    fn requests_are_refused_before_authorization() {
+        let dir = TempDir::new("http-respond");
+        let (ctx, token) = context(&dir);
+        let local: SocketAddr = "127.0.0.1:8080".parse().unwrap();
+        let bearer = format!("Bearer {}", token);
+        let host = ("Host", "127.0.0.1:8080");
+        let auth = ("Authorization", bearer.as_str());
+
+        let mut cross_origin = get("/metrics", &[host, auth, ("Origin", "http://evil.example")]);
+        assert_eq!(respond(&mut cross_origin, local, &ctx).status, 403);
+        let mut rebound = get("/metrics", &[("Host", "evil.example:8080"), auth]);
+        assert_eq!(respond(&mut rebound, local, &ctx).status, 400);
+        let mut anonymous = get("/metrics", &[host]);
+        let reply = respond(&mut anonymous, local, &ctx);
+        assert_eq!(reply.status, 401);
+        assert!(reply.body.contains("authentication failed"));
+    }
+
+    #[test]
+    fn routes_map_to_actions() {
+        let route_of = |method, path: &str| route(&mut request(method, path, &[], ""));
+        assert!(matches!(
+            route_of(Method::Get, "/processes"),
+            Ok((Route::List, _))
+        ));
+        assert!(matches!(
+            route_of(Method::Get, "/processes/3?verbose"),
+            Ok((Route::Status(3), _))
+        ));
+        assert!(matches!(
+            route_of(Method::Post, "/processes/3/stop"),
+            Ok((Route::Stop(3), _))
+        ));
+        assert!(matches!(
+            route_of(Method::Get, "/processes/3/log"),
+            Ok((Route::Log(3), _))
+        ));
+        assert!(matches!(
+            route_of(Method::Get, "/metrics"),
+            Ok((Route::Metrics, _))
+        ));
+
+        assert_eq!(status(route_of(Method::Get, "/processes/web")), 404);
+        assert_eq!(status(route_of(Method::Get, "/nothing")), 404);
+        assert_eq!(status(route_of(Method::Delete, "/processes")), 405);
+        assert_eq!(status(route_of(Method::Get, "/processes/3/stop")), 405);
+        assert_eq!(status(route_of(Method::Post, "/metrics")), 405);
+    }
+
+    #[test]
+    fn launches_need_a_json_body() {
+        let body = r#"{"program": "true", "args": ["-v"], "timeout": "5s"}"#;
+        let launch = |content_type: Option<&str>, body| {
+            let headers: Vec<(&str, &str)> = content_type
+                .map(|t| ("Content-Type", t))
+                .into_iter()
+                .collect();
+            launch_action(&mut request(Method::Post, "/processes", &headers, body))
+        };
+
+        let once = launch(Some("application/json; charset=utf-8"), body).unwrap();
+        assert_eq!(once.program, "true");
+        assert_eq!(once.args, ["-v"]);
+        assert_eq!(once.options.timeout, Some(Duration::from_secs(5)));
+
+        // anything an HTML form could send is refused
+        assert_eq!(status(launch(None, body)), 415);
+        assert_eq!(status(launch(Some("text/plain"), body)), 415);
+        assert_eq!(
+            status(launch(Some("application/x-www-form-urlencoded"), body)),
+            415
+        );
+
+        let json = Some("application/json");
+        assert_eq!(status(launch(json, "{")), 400);
+        assert_eq!(
+            status(launch(json, r#"{"program": "true", "user": "root"}"#)),
+            400
+        );
+        let huge = r#"{"program": "true", "timeout": "18446744073709551615s"}"#;
+        assert_eq!(status(launch(json, huge)), 400);
+        let large = Box::leak(" ".repeat(MAX_BODY as usize + 1).into_boxed_str());
+        assert_eq!(status(launch(json, large)), 413);
+    }
+}
@@ -4,16 +4,49 @@ extern crate log;
 extern crate serde;
 extern crate typetag;
 
+pub mod audit;
+pub mod auth;
 pub mod commands;
+pub mod config;
+#[cfg(unix)]
+pub mod daemon;
+pub mod definitions;
+pub mod events;
+pub mod hooks;
+pub mod http;
 pub mod logging;
+pub mod metrics;
 pub mod processing;
+#[cfg(unix)]
+pub mod pty;
+pub mod scheduling;
+pub mod search;
+pub mod state;
+pub mod supervisor;
+pub mod tls;
+pub mod top;
+pub mod util;
+pub mod webhooks;
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 use chrono::{DateTime, Utc};
 use commands::*;
+use processing::SibylPID;
+use rustls::ClientConfig;
 use serde::{Deserialize, Serialize};
-use std::io::{Read, Write};
-use std::net::TcpStream;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::sync::Arc;
+use std::time::Duration;
+use tls::TlsStream;
+
+/// the largest handshake the daemon reads, since it arrives before the client is authenticated
+const MAX_HANDSHAKE_SIZE: usize = 64 * 1024;
+
+/// the largest request, or frame sent by a client, the daemon reads. it leaves plenty
+/// of room for definitions and `sibyl send` input, while keeping a client from making
+/// the daemon allocate whatever size it claims
+const MAX_REQUEST_SIZE: usize = 16 * 1024 * 1024;
 
 /// structure containing all information that *might* be required by the server to fufill a command
 ///
@@ -27,37 +60,231 @@ pub struct Request {
 
 /// structure containing any information the client might report to the user
 ///
-/// currently only contains a response message
+/// contains a response message and, for commands that start a process, its sibyl pid
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Response {
     pub msg: String,
+    pub spid: Option<SibylPID>,
+    /// set if the request failed, in which case `msg` says why
+    pub error: bool,
+}
+
+/// a chunk of a streamed reply
+///
+/// streaming commands such as `run` send any number of frames
+/// after their Response, followed by a single End frame
+#[derive(Serialize, Deserialize, Debug)]
+pub enum StreamFrame {
+    /// raw output of the process being streamed
+    Output(Vec<u8>),
+    /// the stream is over, carrying the exit code of the process if it had one,
+    /// or 128 plus the signal that killed it, the way shells report it
+    End(Option<i32>),
+    /// bytes typed by an attached client, to be written to the process' stdin
+    Input(Vec<u8>),
+    /// the attached client is going away, the process is left running
+    Detach,
+    /// first frame of an attach stream, telling the client whether the process runs on a pty
+    Attached { pty: bool },
+    /// the attached client's terminal changed size
+    Resize { rows: u16, cols: u16 },
+    /// sent by streams that can go quiet for a long time, so that the daemon
+    /// notices clients that have gone away. clients ignore it
+    Heartbeat,
+}
+
+/// first message on every connection, sent by the client before its request
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Handshake {
+    /// the client's token, if it has one
+    pub token: Option<String>,
+}
+
+/// the daemon's answer to a handshake
+#[derive(Serialize, Deserialize, Debug)]
+pub enum HandshakeReply {
+    Accepted,
+    /// the connection is closed after this, carrying the reason
+    Rejected(String),
 }
 
-/// helper structure that represents a connection over a TcpStream (windows IPC not supported yet)
+/// helper structure that represents a connection over a TcpStream, or TLS over one (windows IPC not supported yet)
 ///
 /// has convenience methods for sending and receiving requests and responses
 pub struct Client {
-    connection: TcpStream,
+    connection: Stream,
+}
+
+/// the transport under a Client
+enum Stream {
+    Plain(TcpStream),
+    Tls(TlsStream),
+}
+
+impl Stream {
+    fn tcp(&self) -> &TcpStream {
+        match self {
+            Stream::Plain(tcp) => tcp,
+            Stream::Tls(tls) => tls.get_ref(),
+        }
+    }
+
+    fn try_clone(&self) -> io::Result<Stream> {
+        match self {
+            Stream::Plain(tcp) => Ok(Stream::Plain(tcp.try_clone()?)),
+            Stream::Tls(tls) => Ok(Stream::Tls(tls.try_clone()?)),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(tcp) => tcp.read(buf),
+            Stream::Tls(tls) => tls.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(tcp) => tcp.write(buf),
+            Stream::Tls(tls) => tls.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Plain(tcp) => tcp.flush(),
+            Stream::Tls(tls) => tls.flush(),
+        }
+    }
 }
 
 impl Client {
-    /// connect to a unix socket in the hard-coded position as of right now
+    /// connect to the daemon on its default listen address
     ///
     /// returns a Result<Client>
     pub fn connect() -> Result<Client> {
-        let connection = TcpStream::connect("127.0.0.1:52352")?;
+        Client::connect_to(config::DEFAULT_LISTEN)
+    }
+
+    /// connect to the daemon on a specific address
+    pub fn connect_to(addr: &str) -> Result<Client> {
+        let connection = TcpStream::connect(addr)?;
+
+        Ok(Client {
+            connection: Stream::Plain(connection),
+        })
+    }
+
+    /// connect to the daemon on one of its `tls.listen` addresses
+    /// # Arguments
+    /// * `addr` - the address to connect to, whose host the daemon's certificate must be valid for
+    /// * `config` - built with `tls::client_config`
+    pub fn connect_tls(addr: &str, config: Arc<ClientConfig>) -> Result<Client> {
+        let connection = TcpStream::connect(addr)?;
+        let connection = TlsStream::connect(connection, config, addr)?;
+
+        Ok(Client {
+            connection: Stream::Tls(connection),
+        })
+    }
+
+    /// sends the handshake every connection starts with, and waits for the daemon to accept it
+    /// # Arguments
+    /// * `token` - the token to authenticate with, if any
+    pub fn authenticate(&mut self, token: Option<&str>) -> Result<()> {
+        match self.handshake(token)? {
+            HandshakeReply::Accepted => Ok(()),
+            HandshakeReply::Rejected(reason) => {
+                anyhow::bail!("sibyld refused the connection: {}", reason)
+            }
+        }
+    }
+
+    /// like `authenticate`, but returns the daemon's answer instead of turning a refusal into an error
+    pub fn handshake(&mut self, token: Option<&str>) -> Result<HandshakeReply> {
+        let handshake = Handshake {
+            token: token.map(String::from),
+        };
+        let serialized: Vec<u8> = bincode::serialize(&handshake)?;
+        // a daemon that rejects a TLS client certificate only closes the connection at this point
+        send_reqres(&mut self.connection, &serialized)
+            .context("lost connection to sibyld during the handshake")?;
+
+        let received = read_reqres(&mut self.connection)
+            .context("lost connection to sibyld during the handshake")?;
+        Ok(bincode::deserialize(&received)?)
+    }
+
+    /// block and wait for the client's handshake, which is limited in size
+    pub fn receive_handshake(&mut self) -> Result<Handshake> {
+        let received = read_limited(&mut self.connection, MAX_HANDSHAKE_SIZE)?;
+        Ok(bincode::deserialize(&received)?)
+    }
+
+    /// serialize and send the answer to a handshake
+    pub fn send_handshake_reply(&mut self, reply: &HandshakeReply) -> Result<()> {
+        let serialized: Vec<u8> = bincode::serialize(reply)?;
+        send_reqres(&mut self.connection, &serialized)
+    }
+
+    /// the address of the other end of the connection
+    pub fn peer_addr(&self) -> Result<SocketAddr> {
+        Ok(self.connection.tcp().peer_addr()?)
+    }
 
-        Ok(Client { connection })
+    /// the address of this end of the connection
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.connection.tcp().local_addr()?)
+    }
+
+    /// limits how long reads block, or lifts the limit with None
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<()> {
+        Ok(self.connection.tcp().set_read_timeout(timeout)?)
+    }
+
+    /// closes the connection in both directions, which also
+    /// wakes up any other handle blocked reading from it
+    pub fn shutdown(&self) -> Result<()> {
+        Ok(self.connection.tcp().shutdown(Shutdown::Both)?)
+    }
+
+    /// creates a second handle to the same connection, so that
+    /// one thread can send while another one receives
+    pub fn try_clone(&self) -> Result<Client> {
+        Ok(Client {
+            connection: self.connection.try_clone()?,
+        })
     }
 
     /// creates a client by taking ownership of an already-existing TcpStream struct
     pub fn from_stream(connection: TcpStream) -> Client {
-        Client { connection }
+        Client {
+            connection: Stream::Plain(connection),
+        }
+    }
+
+    /// creates a client from a connection that has completed its TLS handshake
+    pub fn from_tls_stream(connection: TlsStream) -> Client {
+        Client {
+            connection: Stream::Tls(connection),
+        }
     }
 
     /// serialize and send a Request structure over the connection
     pub fn send_request(&mut self, msg: &Request) -> Result<()> {
         let serialized: Vec<u8> = bincode::serialize(&msg)?;
+        // the daemon would drop the connection without saying why
+        if serialized.len() > MAX_REQUEST_SIZE {
+            anyhow::bail!(
+                "request of {} bytes is larger than the limit of {}",
+                serialized.len(),
+                MAX_REQUEST_SIZE
+            );
+        }
         send_reqres(&mut self.connection, &serialized)
     }
 
@@ -67,9 +294,9 @@ impl Client {
         send_reqres(&mut self.connection, &serialized)
     }
 
-    /// block and wait for a Request structure, then deserialize and return it
+    /// block and wait for a Request structure of limited size, then deserialize and return it
     pub fn receive_request(&mut self) -> Result<Request> {
-        let received = read_reqres(&mut self.connection)?;
+        let received = read_limited(&mut self.connection, MAX_REQUEST_SIZE)?;
         Ok(bincode::deserialize(&received)?)
     }
 
@@ -78,18 +305,37 @@ impl Client {
         let received = read_reqres(&mut self.connection)?;
         Ok(bincode::deserialize(&received)?)
     }
+
+    /// serialize and send a StreamFrame structure over the connection
+    pub fn send_frame(&mut self, frame: &StreamFrame) -> Result<()> {
+        let serialized: Vec<u8> = bincode::serialize(&frame)?;
+        send_reqres(&mut self.connection, &serialized)
+    }
+
+    /// block and wait for a StreamFrame structure, then deserialize and return it
+    pub fn receive_frame(&mut self) -> Result<StreamFrame> {
+        let received = read_reqres(&mut self.connection)?;
+        Ok(bincode::deserialize(&received)?)
+    }
+
+    /// like `receive_frame`, for the daemon reading the frames a client sends,
+    /// which are limited in size like requests
+    pub fn receive_client_frame(&mut self) -> Result<StreamFrame> {
+        let received = read_limited(&mut self.connection, MAX_REQUEST_SIZE)?;
+        Ok(bincode::deserialize(&received)?)
+    }
 }
 
 /// helper function in this module for sending a request/response
 ///
 /// # Arguments
-/// * `stream` - the TcpStream to send the bytes over
+/// * `stream` - the connection to send the bytes over
 /// * `msg` - a Vec of bytes to send
-fn send_reqres(stream: &mut TcpStream, msg: &[u8]) -> Result<()> {
+fn send_reqres(stream: &mut impl Write, msg: &[u8]) -> Result<()> {
     let size: Vec<u8> = bincode::serialize(&msg.len())?;
 
     stream.write_all(&size)?;
-    stream.write_all(&msg)?;
+    stream.write_all(msg)?;
 
     Ok(())
 }
@@ -97,15 +343,48 @@ fn send_reqres(stream: &mut TcpStream, msg: &[u8]) -> Result<()> {
 /// helper function in this module for blocking and receiving a request/response
 ///
 /// # Arguments
-/// * `stream` - the TcpStream to read over
-fn read_reqres(stream: &mut TcpStream) -> Result<Vec<u8>> {
+/// * `stream` - the connection to read over
+fn read_reqres(stream: &mut impl Read) -> Result<Vec<u8>> {
+    read_limited(stream, usize::MAX)
+}
+
+/// like `read_reqres`, but refuses messages larger than `max` bytes without reading them
+///
+/// # Arguments
+/// * `stream` - the connection to read over
+/// * `max` - the largest message size accepted
+fn read_limited(stream: &mut impl Read, max: usize) -> Result<Vec<u8>> {
     let mut size_buffer: [u8; 8] = [0; 8];
     stream.read_exact(&mut size_buffer)?;
     let size: usize = bincode::deserialize(&size_buffer)?;
+    if size > max {
+        anyhow::bail!(
+            "message of {} bytes is larger than the limit of {}",
+            size,
+            max
+        );
+    }
 
-    let mut buffer: Vec<u8> = Vec::new();
-    buffer.resize(size, 0);
+    let mut buffer: Vec<u8> = vec![0; size];
     stream.read_exact(buffer.as_mut_slice())?;
 
     Ok(buffer)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn oversized_messages_are_refused_unread() {
+        let mut message = Vec::new();
+        send_reqres(&mut message, b"ok").unwrap();
+        assert_eq!(read_limited(&mut Cursor::new(&message), 2).unwrap(), b"ok");
+
+        // only the size is sent, so reading the message itself would fail differently
+        let size = bincode::serialize(&(MAX_REQUEST_SIZE + 1)).unwrap();
+        let err = read_limited(&mut Cursor::new(size), MAX_REQUEST_SIZE).unwrap_err();
+        assert!(err.to_string().contains("larger than the limit"));
+    }
+}
@@ -1,8 +1,13 @@
+use crate::config::Retention;
 use anyhow::Result;
+use chrono::Local;
 use log::debug;
 use std::collections::HashMap;
-use std::fs::{create_dir_all, File, OpenOptions};
+use std::ffi::OsString;
+use std::fs::{self, create_dir_all, File, OpenOptions};
+use std::io;
 use std::path::{Path, PathBuf};
+use std::time::SystemTime;
 
 /// trait that describes any command
 /// that requires the loghandler to be able
@@ -13,6 +18,34 @@ pub trait LogName {
     fn log_name(&self) -> PathBuf;
 }
 
+/// appends the current local time to a log name so that
+/// repeated runs of the same command get their own log file
+///
+/// path separators in the name are replaced, so that commands like
+/// `/usr/bin/env` can't place their log outside of the log directory
+/// # Arguments
+/// * `filename` - the base name of the log file
+pub fn timestamped(filename: OsString) -> PathBuf {
+    let mut filename: OsString = filename
+        .to_string_lossy()
+        .chars()
+        .map(|c| if std::path::is_separator(c) { '-' } else { c })
+        .collect::<String>()
+        .into();
+    let timestamp = format!("_{}", Local::now());
+    let timestamp: String = timestamp
+        .chars()
+        .map(|c| match c {
+            ' ' => '-',
+            ':' => '-',
+            _ => c,
+        })
+        .collect();
+    filename.push(timestamp);
+
+    PathBuf::from(filename)
+}
+
 /// structure that represents a logfile
 /// currently only stores a path, but might be expanded later
 /// to include formatted writing utilities
@@ -23,7 +56,6 @@ pub struct LogFile {
 impl LogFile {
     pub fn open(&self) -> Result<File> {
         let f = OpenOptions::new()
-            .write(true)
             .create(true)
             .append(true)
             .open(&self.path)?;
@@ -60,7 +92,12 @@ impl LogHandler {
 
     /// returns the root path of the log handler
     pub fn log_directory(&self) -> &Path {
-        &self.directory.as_path()
+        self.directory.as_path()
+    }
+
+    /// moves the root path of the log handler, for logs created from now on
+    pub fn set_log_directory(&mut self, p: &Path) {
+        self.directory = PathBuf::from(p);
     }
 
     /// returns a LogFile structure that the callee can use
@@ -80,4 +117,60 @@ impl LogHandler {
         self.logs.insert(log_name.clone(), LogFile { path });
         Ok(self.logs.get(&log_name).unwrap())
     }
+
+    /// deletes old log files according to a retention policy
+    ///
+    /// returns the number of deleted files
+    /// # Arguments
+    /// * `retention` - which logs to delete
+    /// * `keep` - logs that must not be deleted, e.g. those of running processes
+    pub fn prune(&mut self, retention: &Retention, keep: &[PathBuf]) -> Result<usize> {
+        let entries = match fs::read_dir(&self.directory) {
+            Ok(entries) => entries,
+            // nothing has been logged yet
+            Err(_) => return Ok(0),
+        };
+
+        let mut logs: Vec<(SystemTime, PathBuf)> = Vec::new();
+        for entry in entries {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == "slog") && !keep.contains(&path) {
+                match fs::metadata(&path) {
+                    Ok(metadata) => logs.push((metadata.modified()?, path)),
+                    // deleted since the directory was read
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                    Err(e) => return Err(e.into()),
+                }
+            }
+        }
+        // newest first, so that anything past `max_files` is the oldest
+        logs.sort_by_key(|log| std::cmp::Reverse(log.0));
+
+        let now = SystemTime::now();
+        let max_age = retention.max_age();
+        // logs of running processes count towards the limit too
+        let allowed = retention
+            .max_files
+            .map(|max| max.saturating_sub(keep.len()));
+
+        let mut deleted = 0;
+        for (i, (modified, path)) in logs.iter().enumerate() {
+            let too_old = max_age.is_some_and(|max_age| {
+                now.duration_since(*modified).is_ok_and(|age| age > max_age)
+            });
+            let too_many = allowed.is_some_and(|allowed| i >= allowed);
+
+            if too_old || too_many {
+                debug!("deleting old log {:?}", path);
+                match fs::remove_file(path) {
+                    Ok(()) => deleted += 1,
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                    Err(e) => return Err(e.into()),
+                }
+                self.logs.retain(|_, log| &log.path != path);
+            }
+        }
+
+        Ok(deleted)
+    }
 }
@@ -0,0 +1,429 @@
+use crate::audit::Outcome;
+use crate::commands::CommandContext;
+use crate::events::EventKind;
+use crate::processing::{ProcessWaitStatus, SibylProcess};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::fs;
+use std::time::Duration;
+
+/// upper bounds of the request latency histogram, in seconds
+const LATENCY_BUCKETS: [f64; 9] = [0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 10.0];
+
+/// the metrics reported for the running processes, by definition name or program.
+/// processes that have exited are left out, so that every process ever
+/// started doesn't stay around as a series of its own, except that a definition
+/// whose processes have all exited keeps reporting `sibyl_process_up` as 0
+const PROCESS_FAMILIES: [Family; 4] = [
+    Family {
+        name: "sibyl_process_up",
+        kind: "gauge",
+        help: "how many processes with the name are running",
+        value: |s| Some(s.running as f64),
+    },
+    Family {
+        name: "sibyl_process_cpu_seconds_total",
+        kind: "counter",
+        help: "user and system CPU time of the running processes",
+        value: |s| s.cpu_seconds,
+    },
+    Family {
+        name: "sibyl_process_resident_memory_bytes",
+        kind: "gauge",
+        help: "resident set size of the running processes",
+        value: |s| s.rss_bytes.map(|rss| rss as f64),
+    },
+    Family {
+        name: "sibyl_process_log_bytes",
+        kind: "gauge",
+        help: "size of the running processes' log files",
+        value: |s| s.log_bytes.map(|bytes| bytes as f64),
+    },
+];
+
+/// a metric with one sample per process name
+struct Family {
+    name: &'static str,
+    kind: &'static str,
+    help: &'static str,
+    value: fn(&ProcessSample) -> Option<f64>,
+}
+
+/// what the daemon counts about the requests it serves
+#[derive(Default)]
+pub struct Metrics {
+    /// requests by action type and outcome
+    requests: BTreeMap<(String, Outcome), u64>,
+    /// request latency by action type
+    latency: BTreeMap<String, Histogram>,
+    /// how many times the supervisor or `sibyl restart` started a process again, by name
+    restarts: BTreeMap<String, u64>,
+    /// the exit code of the latest process with a name to exit with one
+    exit_codes: BTreeMap<String, i32>,
+}
+
+#[derive(Default)]
+struct Histogram {
+    /// how many observations fell into each of LATENCY_BUCKETS, not cumulative
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Metrics {
+    /// counts a request once it has been handled
+    /// # Arguments
+    /// * `action` - the action's type name, e.g. `CmdOnce`
+    /// * `outcome` - what became of the request
+    /// * `elapsed` - how long handling it took
+    pub fn record_request(&mut self, action: &str, outcome: Outcome, elapsed: Duration) {
+        *self
+            .requests
+            .entry((action.to_string(), outcome))
+            .or_insert(0) += 1;
+
+        let seconds = elapsed.as_secs_f64();
+        let histogram = self.latency.entry(action.to_string()).or_default();
+        if let Some(i) = LATENCY_BUCKETS.iter().position(|&le| seconds <= le) {
+            histogram.buckets[i] += 1;
+        }
+        histogram.count += 1;
+        histogram.sum += seconds;
+    }
+
+    /// counts what an event says about a process, since the process itself may be gone
+    /// by the time metrics are scraped
+    pub fn record_event(&mut self, event: &EventKind) {
+        match event {
+            EventKind::Restarted { name, .. } => {
+                *self.restarts.entry(name.clone()).or_insert(0) += 1;
+            }
+            EventKind::Exited {
+                name,
+                code: Some(code),
+                ..
+            } => {
+                self.exit_codes.insert(name.clone(), *code);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// renders the daemon's metrics in the Prometheus text format
+pub fn render(ctx: &mut CommandContext) -> String {
+    let mut out = String::new();
+    let pending = ctx.prochandler.pending().len();
+    let processes = ctx.prochandler.all_processes_mut();
+
+    let mut running = 0;
+    let mut samples: BTreeMap<String, ProcessSample> = BTreeMap::new();
+    for proc in processes.iter_mut() {
+        if let ProcessWaitStatus::Running(_) = proc.wait_status() {
+            running += 1;
+            let name = proc.display_name().unwrap_or_default();
+            samples.entry(name).or_default().add(proc);
+        } else if let Some(def) = &proc.definition {
+            samples.entry(def.name.clone()).or_default();
+        }
+    }
+
+    header(
+        &mut out,
+        "sibyl_processes",
+        "gauge",
+        "processes the daemon manages, by state",
+    );
+    let exited = processes.len() - running;
+    for (state, count) in [
+        ("running", running),
+        ("exited", exited),
+        ("pending", pending),
+    ] {
+        sample(
+            &mut out,
+            "sibyl_processes",
+            &[("state", state)],
+            count as f64,
+        );
+    }
+
+    for family in &PROCESS_FAMILIES {
+        header(&mut out, family.name, family.kind, family.help);
+        for (name, s) in &samples {
+            if let Some(value) = (family.value)(s) {
+                sample(&mut out, family.name, &[("name", name)], value);
+            }
+        }
+    }
+
+    let metrics = &ctx.metrics;
+    header(
+        &mut out,
+        "sibyl_process_restarts_total",
+        "counter",
+        "how many times processes with the name were restarted",
+    );
+    for (name, count) in &metrics.restarts {
+        sample(
+            &mut out,
+            "sibyl_process_restarts_total",
+            &[("name", name)],
+            *count as f64,
+        );
+    }
+    header(
+        &mut out,
+        "sibyl_process_last_exit_code",
+        "gauge",
+        "exit code of the latest process with the name to exit",
+    );
+    for (name, code) in &metrics.exit_codes {
+        sample(
+            &mut out,
+            "sibyl_process_last_exit_code",
+            &[("name", name)],
+            f64::from(*code),
+        );
+    }
+
+    header(
+        &mut out,
+        "sibyl_requests_total",
+        "counter",
+        "requests served, by action type and outcome",
+    );
+    for ((action, outcome), count) in &metrics.requests {
+        let outcome = outcome.to_string();
+        let labels = [("action", action.as_str()), ("outcome", outcome.as_str())];
+        sample(&mut out, "sibyl_requests_total", &labels, *count as f64);
+    }
+    header(
+        &mut out,
+        "sibyl_request_duration_seconds",
+        "histogram",
+        "time taken to handle a request, by action type",
+    );
+    for (action, histogram) in &metrics.latency {
+        let mut cumulative = 0;
+        for (le, count) in LATENCY_BUCKETS.iter().zip(histogram.buckets) {
+            cumulative += count;
+            let le = le.to_string();
+            let labels = [("action", action.as_str()), ("le", le.as_str())];
+            sample(
+                &mut out,
+                "sibyl_request_duration_seconds_bucket",
+                &labels,
+                cumulative as f64,
+            );
+        }
+        let labels = [("action", action.as_str()), ("le", "+Inf")];
+        sample(
+            &mut out,
+            "sibyl_request_duration_seconds_bucket",
+            &labels,
+            histogram.count as f64,
+        );
+        let labels = [("action", action.as_str())];
+        sample(
+            &mut out,
+            "sibyl_request_duration_seconds_sum",
+            &labels,
+            histogram.sum,
+        );
+        sample(
+            &mut out,
+            "sibyl_request_duration_seconds_count",
+            &labels,
+            histogram.count as f64,
+        );
+    }
+
+    out
+}
+
+/// the values of the running processes with one name, collected once per scrape
+#[derive(Default)]
+struct ProcessSample {
+    running: u32,
+    cpu_seconds: Option<f64>,
+    rss_bytes: Option<u64>,
+    log_bytes: Option<u64>,
+}
+
+impl ProcessSample {
+    fn add(&mut self, proc: &SibylProcess) {
+        let (cpu_seconds, rss_bytes) = os_usage(proc.child.id());
+        let log_bytes = fs::metadata(&proc.log_file).ok().map(|meta| meta.len());
+
+        self.running += 1;
+        self.cpu_seconds = sum(self.cpu_seconds, cpu_seconds);
+        self.rss_bytes = sum(self.rss_bytes, rss_bytes);
+        self.log_bytes = sum(self.log_bytes, log_bytes);
+    }
+}
+
+/// adds up the values that are known, None if neither is
+fn sum<T: std::ops::Add<Output = T>>(a: Option<T>, b: Option<T>) -> Option<T> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a + b),
+        (a, b) => a.or(b),
+    }
+}
+
+/// reads the CPU time and resident memory of a process from /proc
+#[cfg(target_os = "linux")]
+pub fn os_usage(pid: u32) -> (Option<f64>, Option<u64>) {
+    // safety: sysconf has no preconditions
+    let (ticks, page_size) = unsafe {
+        (
+            libc::sysconf(libc::_SC_CLK_TCK),
+            libc::sysconf(libc::_SC_PAGESIZE),
+        )
+    };
+
+    let cpu = fs::read_to_string(format!("/proc/{}/stat", pid))
+        .ok()
+        .and_then(|stat| {
+            // the command name may contain spaces, so fields are counted from after it
+            let fields: Vec<&str> = stat[stat.rfind(')')? + 1..].split_whitespace().collect();
+            let utime: u64 = fields.get(11)?.parse().ok()?;
+            let stime: u64 = fields.get(12)?.parse().ok()?;
+            Some((utime + stime) as f64 / ticks as f64)
+        });
+    let rss = fs::read_to_string(format!("/proc/{}/statm", pid))
+        .ok()
+        .and_then(|statm| {
+            let pages: u64 = statm.split_whitespace().nth(1)?.parse().ok()?;
+            Some(pages * page_size as u64)
+        });
+
+    (cpu, rss)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn os_usage(_pid: u32) -> (Option<f64>, Option<u64>) {
+    (None, None)
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn sample(out: &mut String, name: &str, labels: &[(&str, &str)], value: f64) {
+    let labels: Vec<String> = labels
+        .iter()
+        .map(|(key, value)| format!("{}=\"{}\"", key, escape(value)))
+        .collect();
+    let _ = writeln!(out, "{}{{{}}} {}", name, labels.join(","), value);
+}
+
+/// escapes a label value the way the text format requires
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::definitions::ProcessDefinition;
+    use crate::processing::LaunchOptions;
+    use crate::util::TempDir;
+    use std::process::Command;
+    use std::time::Instant;
+
+    fn spawn(ctx: &mut CommandContext, program: &str, arg: &str, def: Option<&str>) {
+        let definition = def.map(|name| {
+            toml::from_str::<ProcessDefinition>(&format!(
+                "name = \"{}\"\ncmd = [\"{}\", \"{}\"]",
+                name, program, arg
+            ))
+            .unwrap()
+        });
+        let log_path = ctx.loghandler.log_directory().join(format!("{}.log", arg));
+        let mut cmd = Command::new(program);
+        cmd.arg(arg);
+        ctx.prochandler
+            .create_process(
+                program.as_ref(),
+                &[arg.into()],
+                &log_path,
+                cmd,
+                definition,
+                &LaunchOptions::default(),
+            )
+            .unwrap();
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn exited_definitions_are_reported_down() {
+        let dir = TempDir::new("metrics-render");
+        let mut ctx = CommandContext::for_tests(dir.path());
+        spawn(&mut ctx, "sleep", "30", Some("web"));
+        spawn(&mut ctx, "sleep", "0", Some("job"));
+        spawn(&mut ctx, "true", "once", None);
+
+        // wait for both short-lived processes to exit
+        let started = Instant::now();
+        while ctx.prochandler.running_count() > 1 {
+            assert!(started.elapsed() < Duration::from_secs(5));
+            std::thread::sleep(Duration::from_millis(20));
+        }
+
+        let out = render(&mut ctx);
+        assert!(out.contains("sibyl_processes{state=\"running\"} 1\n"));
+        assert!(out.contains("sibyl_processes{state=\"exited\"} 2\n"));
+        assert!(out.contains("sibyl_process_up{name=\"web\"} 1\n"));
+        assert!(out.contains("sibyl_process_up{name=\"job\"} 0\n"));
+        // processes without a definition disappear once they exit
+        assert!(!out.contains("name=\"true\""));
+        // exited definitions have nothing else to report
+        assert!(!out.contains("sibyl_process_resident_memory_bytes{name=\"job\"}"));
+
+        for proc in ctx.prochandler.all_processes_mut() {
+            let _ = proc.child.kill();
+        }
+    }
+
+    #[test]
+    fn events_are_counted_by_name() {
+        let mut metrics = Metrics::default();
+        let restarted = |restarts| EventKind::Restarted {
+            name: String::from("web"),
+            previous_spid: 1,
+            spid: 2,
+            restarts,
+        };
+        let exited = |code| EventKind::Exited {
+            spid: 1,
+            name: String::from("web"),
+            code,
+            signal: None,
+            timed_out: false,
+            stopped: false,
+        };
+        metrics.record_event(&restarted(1));
+        metrics.record_event(&restarted(2));
+        metrics.record_event(&exited(Some(3)));
+        // an exit by signal keeps the latest exit code
+        metrics.record_event(&exited(None));
+        metrics.record_event(&EventKind::LogsPruned { deleted: 1 });
+
+        assert_eq!(metrics.restarts.get("web"), Some(&2));
+        assert_eq!(metrics.restarts.len(), 1);
+        assert_eq!(metrics.exit_codes.get("web"), Some(&3));
+
+        let dir = TempDir::new("metrics-events");
+        let mut ctx = CommandContext::for_tests(dir.path());
+        ctx.metrics = metrics;
+        let out = render(&mut ctx);
+        assert!(out.contains("# TYPE sibyl_process_restarts_total counter\n"));
+        assert!(out.contains("sibyl_process_restarts_total{name=\"web\"} 2\n"));
+        assert!(out.contains("sibyl_process_last_exit_code{name=\"web\"} 3\n"));
+    }
+}
@@ -1,25 +1,349 @@
-use anyhow::Result;
+use crate::definitions::ProcessDefinition;
+use crate::state::SavedProcess;
+use anyhow::{anyhow, Result};
 use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::ffi::{OsStr, OsString};
 use std::fmt;
+use std::fs::File;
+use std::io::{self, Write};
+#[cfg(unix)]
+use std::os::unix::io::{AsRawFd, OwnedFd};
 use std::path::{Path, PathBuf};
-use std::process::{Child, Command};
+use std::process::{Child, Command, Stdio};
+use std::sync::mpsc::{self, Sender};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
 
 pub type SibylPID = u32;
 
+/// how often a process' healthcheck command is run
+const HEALTH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// how long a timed out process gets to exit after SIGTERM before it is killed
+const STOP_GRACE: Duration = Duration::from_secs(5);
+
+/// options that change how a process is spawned and supervised
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct LaunchOptions {
+    /// how long the process may run before it is sent SIGTERM
+    pub timeout: Option<Duration>,
+    /// keep a pipe to the process' stdin instead of inheriting the daemon's
+    pub stdin: bool,
+    /// run the process on a pty allocated by the daemon
+    pub pty: bool,
+}
+
+/// handle to the stdin pipe of a process
+///
+/// writes are queued and performed by a dedicated thread, so that a
+/// process which stops reading its stdin can't block the daemon
+#[derive(Clone)]
+pub struct StdinPipe {
+    sender: Sender<Vec<u8>>,
+    /// a duplicate of the descriptor the writer thread writes to, if known
+    fd: Option<Arc<File>>,
+}
+
+impl StdinPipe {
+    /// starts the writer thread for a stdin pipe or pty master
+    pub fn new<W: Write + Send + 'static>(stdin: W) -> StdinPipe {
+        StdinPipe {
+            sender: Self::spawn_writer(stdin),
+            fd: None,
+        }
+    }
+
+    /// like `new`, but keeps a duplicate of the descriptor so it can be handed over during upgrades
+    ///
+    /// the writer thread closes its descriptor once the process stops reading, after which
+    /// the number could be reused by anything the daemon opens. the duplicate stays open
+    /// for as long as the pipe does, so `raw_fd` always refers to the process' stdin
+    #[cfg(unix)]
+    pub fn with_fd(stdin: File) -> io::Result<StdinPipe> {
+        let fd = Arc::new(stdin.try_clone()?);
+        Ok(StdinPipe {
+            sender: Self::spawn_writer(stdin),
+            fd: Some(fd),
+        })
+    }
+
+    /// the file descriptor of the pipe, if it was created with `with_fd`
+    #[cfg(unix)]
+    pub fn raw_fd(&self) -> Option<i32> {
+        self.fd.as_ref().map(|fd| fd.as_raw_fd())
+    }
+
+    fn spawn_writer<W: Write + Send + 'static>(mut stdin: W) -> Sender<Vec<u8>> {
+        let (sender, receiver) = mpsc::channel::<Vec<u8>>();
+        thread::spawn(move || {
+            for bytes in receiver {
+                if stdin.write_all(&bytes).and_then(|_| stdin.flush()).is_err() {
+                    break;
+                }
+            }
+        });
+
+        sender
+    }
+
+    /// queues bytes to be written to the process' stdin
+    ///
+    /// fails once the process has closed its end of the pipe
+    pub fn write(&self, bytes: Vec<u8>) -> Result<()> {
+        self.sender
+            .send(bytes)
+            .map_err(|_| anyhow!("the process has closed its stdin"))
+    }
+}
+
+/// the os process behind a SibylProcess
+pub enum ChildHandle {
+    /// spawned by this daemon, which can collect its exit status
+    Spawned(Child),
+    /// re-adopted from a previous daemon, so it can only be watched by its pid
+    Adopted { pid: u32, exited: bool },
+    /// spawned by this daemon before it was upgraded in place, so it is
+    /// still our child, but only known by its pid
+    Inherited { pid: u32, code: Option<Option<i32>> },
+}
+
+impl ChildHandle {
+    /// the os pid of the process
+    pub fn id(&self) -> u32 {
+        match self {
+            ChildHandle::Spawned(child) => child.id(),
+            ChildHandle::Adopted { pid, .. } | ChildHandle::Inherited { pid, .. } => *pid,
+        }
+    }
+
+    /// checks whether the process has exited without blocking
+    ///
+    /// returns its exit code if it has exited, which is always None
+    /// for adopted processes since only their parent gets to see it
+    pub fn try_wait(&mut self) -> io::Result<Option<Option<i32>>> {
+        match self {
+            ChildHandle::Spawned(child) => Ok(child.try_wait()?.map(|status| status.code())),
+            ChildHandle::Adopted { pid, exited } => {
+                if !*exited && !is_alive(*pid) {
+                    *exited = true;
+                }
+                Ok(if *exited { Some(None) } else { None })
+            }
+            ChildHandle::Inherited { pid, code } => {
+                if code.is_none() {
+                    *code = reap(*pid)?;
+                }
+                Ok(*code)
+            }
+        }
+    }
+
+    /// the signal that killed the process, only known for processes spawned by this daemon
+    pub fn exit_signal(&mut self) -> Option<i32> {
+        #[cfg(unix)]
+        if let ChildHandle::Spawned(child) = self {
+            use std::os::unix::process::ExitStatusExt;
+
+            return child.try_wait().ok()??.signal();
+        }
+        None
+    }
+
+    /// kills the process outright
+    pub fn kill(&mut self) -> Result<()> {
+        match self {
+            ChildHandle::Spawned(child) => Ok(child.kill()?),
+            ChildHandle::Adopted { pid, .. } | ChildHandle::Inherited { pid, .. } => {
+                send_signal(*pid, SIGKILL)
+            }
+        }
+    }
+}
+
 /// bundles a command and a child, along with any other information that needs to be kept track-of
 pub struct SibylProcess {
     pub cmdline: OsString,
-    pub command: Command,
-    pub child: Child,
+    /// the command the process was spawned from, None for adopted processes
+    pub command: Option<Command>,
+    /// the options the process was launched with, for starting it again
+    pub options: LaunchOptions,
+    pub child: ChildHandle,
     pub started: DateTime<Local>,
     pub pid: SibylPID,
     pub log_file: PathBuf,
+    /// pipe to the process' stdin, if it was launched with one
+    pub stdin: Option<StdinPipe>,
+    /// master end of the process' pty, if it was launched on one
+    pub pty: Option<File>,
+    pub definition: Option<ProcessDefinition>,
+    pub health: HealthStatus,
+    /// how many times the definition had been restarted when this instance was started
+    pub restarts: u32,
+    /// set once the process has been stopped on purpose, so it isn't restarted
+    pub stopped: bool,
+    /// set once the supervisor has started a new instance in place of this one
+    pub replaced: bool,
+    /// when the supervisor first saw the process exited
+    pub exit_seen: Option<Instant>,
+    health_probe: Option<Child>,
+    last_probe: Option<Instant>,
+    deadline: Option<Instant>,
+    timed_out_at: Option<Instant>,
+}
+
+impl SibylProcess {
+    /// the name of the definition this process was started from, if any
+    pub fn name(&self) -> Option<&str> {
+        self.definition.as_ref().map(|def| def.name.as_str())
+    }
+
+    /// the name of the definition, or else the file name of the program, for
+    /// telling processes apart in the audit log and metrics
+    pub fn display_name(&self) -> Option<String> {
+        if let Some(name) = self.name() {
+            return Some(name.to_string());
+        }
+        let program = Path::new(self.command.as_ref()?.get_program());
+        Some(program.file_name()?.to_string_lossy().into_owned())
+    }
+
+    /// polls the child without blocking and reports its wait status
+    pub fn wait_status(&mut self) -> ProcessWaitStatus {
+        match self.child.try_wait() {
+            Ok(Some(code)) if self.timed_out_at.is_some() => ProcessWaitStatus::TimedOut(code),
+            Ok(Some(code)) => ProcessWaitStatus::Exited(code),
+            Ok(None) => ProcessWaitStatus::Running(self.child.id()),
+            Err(_) => ProcessWaitStatus::Unknown,
+        }
+    }
+
+    /// terminates the process once its timeout has run out
+    ///
+    /// the process is first sent SIGTERM, then killed if it
+    /// is still running `STOP_GRACE` later
+    pub fn enforce_timeout(&mut self) -> Result<()> {
+        let deadline = match self.deadline {
+            Some(deadline) => deadline,
+            None => return Ok(()),
+        };
+        if self.child.try_wait()?.is_some() {
+            self.deadline = None;
+            return Ok(());
+        }
+
+        match self.timed_out_at {
+            None if Instant::now() >= deadline => {
+                terminate(&mut self.child)?;
+                self.timed_out_at = Some(Instant::now());
+            }
+            Some(at) if at.elapsed() >= STOP_GRACE => {
+                self.child.kill()?;
+                self.deadline = None;
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    /// advances the healthcheck of this process by one step
+    ///
+    /// collects the result of a running probe, or starts a new one
+    /// once `HEALTH_INTERVAL` has passed since the last one
+    pub fn probe_health(&mut self) {
+        let check = match self
+            .definition
+            .as_ref()
+            .and_then(|def| def.healthcheck.as_ref())
+        {
+            Some(check) => check.clone(),
+            None => return,
+        };
+
+        if !matches!(self.wait_status(), ProcessWaitStatus::Running(_)) {
+            self.health = HealthStatus::Unhealthy;
+            return;
+        }
+
+        if let Some(probe) = &mut self.health_probe {
+            match probe.try_wait() {
+                Ok(Some(status)) => {
+                    self.health = if status.success() {
+                        HealthStatus::Healthy
+                    } else {
+                        HealthStatus::Unhealthy
+                    };
+                    self.health_probe = None;
+                }
+                Ok(None) => {}
+                Err(_) => self.health_probe = None,
+            }
+            return;
+        }
+
+        let due = match self.last_probe {
+            Some(last) => last.elapsed() >= HEALTH_INTERVAL,
+            None => true,
+        };
+        if due {
+            self.last_probe = Some(Instant::now());
+            self.health_probe = Command::new(&check[0])
+                .args(&check[1..])
+                .stdin(Stdio::null())
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .spawn()
+                .ok();
+        }
+    }
+
+    /// describes the process so that a later daemon can adopt it
+    pub fn to_saved(&self) -> SavedProcess {
+        let os_pid = self.child.id();
+        SavedProcess {
+            spid: self.pid,
+            os_pid,
+            start_time: start_time(os_pid),
+            cmdline: self.cmdline.to_string_lossy().into_owned(),
+            started: self.started,
+            log_file: self.log_file.clone(),
+            restarts: self.restarts,
+            timeout_left: self
+                .deadline
+                .filter(|_| self.timed_out_at.is_none())
+                .map(|deadline| deadline.saturating_duration_since(Instant::now())),
+            stdin_fd: None,
+            pty_fd: None,
+            definition: self.definition.clone(),
+        }
+    }
+
+    /// whether this process counts as healthy for `depends_on`
+    ///
+    /// processes without a healthcheck are healthy as long as they are running
+    pub fn is_healthy(&mut self) -> bool {
+        let has_check = self
+            .definition
+            .as_ref()
+            .is_some_and(|def| def.healthcheck.is_some());
+
+        if has_check {
+            self.health == HealthStatus::Healthy
+        } else {
+            matches!(self.wait_status(), ProcessWaitStatus::Running(_))
+        }
+    }
 }
 
+#[derive(Clone, Copy)]
 pub enum ProcessWaitStatus {
     Running(u32),
     Exited(Option<i32>),
+    /// the process was terminated because its timeout ran out
+    TimedOut(Option<i32>),
     Unknown,
 }
 
@@ -29,27 +353,106 @@ impl fmt::Display for ProcessWaitStatus {
             ProcessWaitStatus::Running(p) => write!(f, "running (pid {})", p),
             ProcessWaitStatus::Exited(Some(p)) => write!(f, "exited (exit code {})", p),
             ProcessWaitStatus::Exited(None) => write!(f, "exited (no exit code)"),
+            ProcessWaitStatus::TimedOut(Some(p)) => write!(f, "timed out (exit code {})", p),
+            ProcessWaitStatus::TimedOut(None) => write!(f, "timed out (killed by signal)"),
             ProcessWaitStatus::Unknown => write!(f, "unknown"),
         }
     }
 }
 
+/// how the supervisor treats a definition whose processes keep failing
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+pub enum CrashState {
+    /// restarted as its restart policy says
+    #[default]
+    Stable,
+    /// crash-looping, with restarts delayed longer after every failure
+    Backoff {
+        /// failures since the crash loop was detected
+        failures: u32,
+    },
+    /// no longer restarted until `sibyl reset`
+    Quarantined { failures: u32 },
+}
+
+impl fmt::Display for CrashState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self {
+            CrashState::Stable => write!(f, "stable"),
+            CrashState::Backoff { failures } => write!(
+                f,
+                "crash-looping, restarts backing off ({} failures since)",
+                failures
+            ),
+            CrashState::Quarantined { failures } => write!(
+                f,
+                "quarantined after {} failures while crash-looping, `sibyl reset` restarts it",
+                failures
+            ),
+        }
+    }
+}
+
+/// the recent failures of a definition's processes
+#[derive(Clone, Debug, Default)]
+pub struct CrashLoop {
+    /// when its processes failed, within the crash loop window
+    pub recent: Vec<Instant>,
+    pub state: CrashState,
+}
+
+/// result of the most recent healthcheck of a process
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum HealthStatus {
+    Unknown,
+    Healthy,
+    Unhealthy,
+}
+
+impl fmt::Display for HealthStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self {
+            HealthStatus::Unknown => write!(f, "unknown"),
+            HealthStatus::Healthy => write!(f, "healthy"),
+            HealthStatus::Unhealthy => write!(f, "unhealthy"),
+        }
+    }
+}
+
 pub struct ProcessStatus {
+    pub name: Option<String>,
     pub cmdline: OsString,
     pub started: DateTime<Local>,
     pub internal_pid: SibylPID,
     pub os_pid: u32,
     pub status: ProcessWaitStatus,
+    pub health: Option<HealthStatus>,
+    pub restarts: u32,
+    /// set for the latest process of a crash-looping definition
+    pub crash_state: Option<CrashState>,
     pub log_path: PathBuf,
 }
 
 impl fmt::Display for ProcessStatus {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         writeln!(f, "process status for ({})", self.internal_pid)?;
+        if let Some(name) = &self.name {
+            writeln!(f, "  name         : {}", name)?;
+        }
         writeln!(f, "  command line : {}", self.cmdline.to_str().unwrap())?;
         writeln!(f, "  started at   : {}", self.started)?;
         writeln!(f, "  OS PID       : {}", self.os_pid)?;
         writeln!(f, "  wait status  : {}", self.status)?;
+        if let Some(health) = &self.health {
+            writeln!(f, "  health       : {}", health)?;
+        }
+        if self.restarts > 0 {
+            writeln!(f, "  restarts     : {}", self.restarts)?;
+        }
+        if let Some(state) = &self.crash_state {
+            writeln!(f, "  crash loop   : {}", state)?;
+        }
         writeln!(f, "  log file     : {}", self.log_path.display())
     }
 }
@@ -59,6 +462,9 @@ impl fmt::Display for ProcessStatus {
 pub struct ProcessHandler {
     count: SibylPID,
     processes: Vec<SibylProcess>,
+    pending: Vec<ProcessDefinition>,
+    /// failures by definition name
+    crash_loops: HashMap<String, CrashLoop>,
 }
 
 impl ProcessHandler {
@@ -67,74 +473,319 @@ impl ProcessHandler {
         ProcessHandler {
             count: 0,
             processes: Vec::new(),
+            pending: Vec::new(),
+            crash_loops: HashMap::new(),
         }
     }
 
     /// create a process running under the process handler
     /// # Arguments
-    /// * `cmd` - a Command structure to spawn from
+    /// * `program` - the program being run
+    /// * `args` - the arguments passed to the program
+    /// * `log_path` - the log file the process writes to
+    /// * `command` - a Command structure to spawn from
+    /// * `definition` - the definition the process was started from, if any
+    /// * `options` - the options the process was launched with
     pub fn create_process(
         &mut self,
         program: &OsStr,
         args: &[OsString],
         log_path: &Path,
         mut command: Command,
+        definition: Option<ProcessDefinition>,
+        options: &LaunchOptions,
     ) -> Result<SibylPID> {
         // build our own (owned) version of the command-line string
         // when Command::get_program and Command::get_args are stable, we won't have to do this
         let mut cmdline = OsString::from(program);
         for arg in args {
-            cmdline.push(&arg.clone());
+            cmdline.push(arg);
         }
 
-        let child = command.spawn()?;
+        let mut child = command.spawn()?;
+        #[cfg(unix)]
+        let stdin = child
+            .stdin
+            .take()
+            .map(|stdin| StdinPipe::with_fd(File::from(OwnedFd::from(stdin))))
+            .transpose()?;
+        #[cfg(not(unix))]
+        let stdin = child.stdin.take().map(StdinPipe::new);
+        // the command still holds its copies of the child's stdio, which would
+        // keep pipes and ptys open after the child has exited
+        command
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null());
         self.count += 1;
         let proc = SibylProcess {
             cmdline,
-            command,
-            child,
+            command: Some(command),
+            options: options.clone(),
+            child: ChildHandle::Spawned(child),
             started: Local::now(),
             pid: self.count,
             log_file: PathBuf::from(log_path),
+            stdin,
+            pty: None,
+            definition,
+            health: HealthStatus::Unknown,
+            restarts: 0,
+            stopped: false,
+            replaced: false,
+            exit_seen: None,
+            health_probe: None,
+            last_probe: None,
+            // a deadline too far off to represent is never reached anyway
+            deadline: options
+                .timeout
+                .and_then(|timeout| Instant::now().checked_add(timeout)),
+            timed_out_at: None,
         };
         self.processes.push(proc);
 
         Ok(self.count)
     }
 
+    /// takes over a process left running by a previous daemon
+    ///
+    /// returns false if the process is gone, or its pid now belongs to a different process
+    /// # Arguments
+    /// * `saved` - the process as the previous daemon saved it
+    /// * `inherited` - whether the process is still our child, i.e. the daemon was upgraded in place
+    pub fn adopt(&mut self, saved: SavedProcess, inherited: bool) -> bool {
+        // our own children may have exited during the upgrade, which `wait_status` reports
+        if !inherited && !is_alive(saved.os_pid) {
+            return false;
+        }
+        if saved.start_time.is_some()
+            && start_time(saved.os_pid).is_some_and(|start| Some(start) != saved.start_time)
+        {
+            return false;
+        }
+
+        let child = if inherited {
+            ChildHandle::Inherited {
+                pid: saved.os_pid,
+                code: None,
+            }
+        } else {
+            ChildHandle::Adopted {
+                pid: saved.os_pid,
+                exited: false,
+            }
+        };
+
+        // keep handing out sibyl pids after the adopted ones
+        self.count = self.count.max(saved.spid);
+        self.processes.push(SibylProcess {
+            cmdline: OsString::from(saved.cmdline),
+            command: None,
+            options: LaunchOptions::default(),
+            child,
+            started: saved.started,
+            pid: saved.spid,
+            log_file: saved.log_file,
+            stdin: None,
+            pty: None,
+            definition: saved.definition,
+            health: HealthStatus::Unknown,
+            restarts: saved.restarts,
+            stopped: false,
+            replaced: false,
+            exit_seen: None,
+            health_probe: None,
+            last_probe: None,
+            deadline: saved
+                .timeout_left
+                .and_then(|left| Instant::now().checked_add(left)),
+            timed_out_at: None,
+        });
+        self.processes.sort_by_key(|proc| proc.pid);
+
+        true
+    }
+
     pub fn get_process_by_pid(&self, pid: SibylPID) -> Option<&SibylProcess> {
         self.processes.iter().find(|&proc| proc.pid == pid)
     }
 
+    pub fn get_process_by_pid_mut(&mut self, pid: SibylPID) -> Option<&mut SibylProcess> {
+        self.processes.iter_mut().find(|proc| proc.pid == pid)
+    }
+
+    /// returns the most recently started process created from the definition `name`
+    pub fn get_process_by_name(&mut self, name: &str) -> Option<&mut SibylProcess> {
+        self.processes
+            .iter_mut()
+            .rev()
+            .find(|proc| proc.name() == Some(name))
+    }
+
     pub fn get_process_status(&mut self, pid: SibylPID) -> Option<ProcessStatus> {
+        let crash_state = self.crash_state_of(pid);
         let proc = self.processes.iter_mut().find(|proc| proc.pid == pid);
         if let Some(proc) = proc {
-            let cmdline = proc.cmdline.clone();
-            let started = proc.started;
-            let internal_pid = pid;
-            let os_pid = proc.child.id();
-            let status = match proc.child.try_wait() {
-                Ok(Some(status)) => ProcessWaitStatus::Exited(status.code()),
-                Ok(None) => ProcessWaitStatus::Running(os_pid),
-                Err(_) => ProcessWaitStatus::Unknown,
-            };
-            let log_path = proc.log_file.clone();
+            let status = proc.wait_status();
+            let health = proc
+                .definition
+                .as_ref()
+                .and_then(|def| def.healthcheck.as_ref())
+                .map(|_| proc.health);
 
             Some(ProcessStatus {
-                cmdline,
-                started,
-                internal_pid,
-                os_pid,
+                name: proc.name().map(String::from),
+                cmdline: proc.cmdline.clone(),
+                started: proc.started,
+                internal_pid: pid,
+                os_pid: proc.child.id(),
                 status,
-                log_path,
+                health,
+                restarts: proc.restarts,
+                crash_state,
+                log_path: proc.log_file.clone(),
             })
         } else {
             None
         }
     }
 
+    /// asks a process to exit with SIGTERM, and keeps the supervisor from restarting it
+    ///
+    /// doesn't wait for the process to exit, see `commands::wait_for_stop`.
+    /// returns false if no process with the given pid exists or it has already exited
+    pub fn terminate_process(&mut self, pid: SibylPID) -> Result<bool> {
+        let proc = match self.processes.iter_mut().find(|proc| proc.pid == pid) {
+            Some(proc) => proc,
+            None => return Ok(false),
+        };
+
+        proc.stopped = true;
+        if proc.child.try_wait()?.is_some() {
+            return Ok(false);
+        }
+        terminate(&mut proc.child)?;
+        Ok(true)
+    }
+
+    /// whether a process has exited, which a process that doesn't exist counts as
+    pub fn has_exited(&mut self, pid: SibylPID) -> Result<bool> {
+        match self.processes.iter_mut().find(|proc| proc.pid == pid) {
+            Some(proc) => Ok(proc.child.try_wait()?.is_some()),
+            None => Ok(true),
+        }
+    }
+
+    /// kills a process that didn't exit after being asked to
+    pub fn kill_process(&mut self, pid: SibylPID) -> Result<()> {
+        if let Some(proc) = self.processes.iter_mut().find(|proc| proc.pid == pid) {
+            if proc.child.try_wait()?.is_none() {
+                proc.child.kill()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// sends a signal to a running process
+    ///
+    /// returns false if no running process with the given pid exists
+    pub fn signal_process(&mut self, pid: SibylPID, signal: i32) -> Result<bool> {
+        let proc = match self.processes.iter_mut().find(|proc| proc.pid == pid) {
+            Some(proc) => proc,
+            None => return Ok(false),
+        };
+        if proc.child.try_wait()?.is_some() {
+            return Ok(false);
+        }
+
+        send_signal(proc.child.id(), signal)?;
+        Ok(true)
+    }
+
+    /// the number of processes that have not exited yet
+    pub fn running_count(&mut self) -> usize {
+        self.processes
+            .iter_mut()
+            .map(|proc| proc.wait_status())
+            .filter(|status| matches!(status, ProcessWaitStatus::Running(_)))
+            .count()
+    }
+
     pub fn all_processes(&self) -> &[SibylProcess] {
-        &self.processes.as_slice()
+        self.processes.as_slice()
+    }
+
+    pub fn all_processes_mut(&mut self) -> &mut [SibylProcess] {
+        self.processes.as_mut_slice()
+    }
+
+    /// queue definitions to be started once their dependencies are satisfied
+    pub fn queue_definitions(&mut self, defs: impl IntoIterator<Item = ProcessDefinition>) {
+        self.pending.extend(defs);
+    }
+
+    /// definitions that are waiting on their dependencies
+    pub fn pending(&self) -> &[ProcessDefinition] {
+        self.pending.as_slice()
+    }
+
+    /// replaces a definition by name, both in the pending queue and in the latest
+    /// process started from it, so that it is used the next time the process starts
+    ///
+    /// returns false if no definition with that name is known
+    pub fn update_definition(&mut self, def: ProcessDefinition) -> bool {
+        let mut found = false;
+        for pending in self.pending.iter_mut().filter(|d| d.name == def.name) {
+            *pending = def.clone();
+            found = true;
+        }
+        if let Some(proc) = self.get_process_by_name(&def.name) {
+            proc.definition = Some(def);
+            found = true;
+        }
+        found
+    }
+
+    /// the crash loop state of a process' definition, only for the
+    /// latest process of a definition that is crash-looping
+    fn crash_state_of(&self, pid: SibylPID) -> Option<CrashState> {
+        let name = self.processes.iter().find(|p| p.pid == pid)?.name()?;
+        let latest = self
+            .processes
+            .iter()
+            .rev()
+            .find(|p| p.name() == Some(name))?;
+        if latest.pid != pid {
+            return None;
+        }
+        self.crash_loops
+            .get(name)
+            .map(|crash| crash.state)
+            .filter(|state| *state != CrashState::Stable)
+    }
+
+    /// the recent failures of a definition's processes, if any have failed
+    pub fn crash_loop(&self, name: &str) -> Option<&CrashLoop> {
+        self.crash_loops.get(name)
+    }
+
+    pub fn crash_loop_mut(&mut self, name: &str) -> &mut CrashLoop {
+        self.crash_loops.entry(name.to_string()).or_default()
+    }
+
+    /// forgets the failures of a definition's processes, returning what was known about them
+    pub fn reset_crash_loop(&mut self, name: &str) -> Option<CrashLoop> {
+        self.crash_loops.remove(name)
+    }
+
+    /// removes and returns every pending definition that matches `pred`
+    pub fn take_pending(
+        &mut self,
+        mut pred: impl FnMut(&ProcessDefinition) -> bool,
+    ) -> Vec<ProcessDefinition> {
+        let (taken, kept) = self.pending.drain(..).partition(|def| pred(def));
+        self.pending = kept;
+        taken
     }
 }
 
@@ -143,3 +794,105 @@ impl Default for ProcessHandler {
         Self::new()
     }
 }
+
+#[cfg(unix)]
+const SIGKILL: i32 = libc::SIGKILL;
+#[cfg(not(unix))]
+const SIGKILL: i32 = 9;
+
+/// sends a unix signal to a process
+#[cfg(unix)]
+fn send_signal(pid: u32, signal: i32) -> Result<()> {
+    // safety: kill(2) has no memory-safety requirements
+    if unsafe { libc::kill(pid as libc::pid_t, signal) } != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn send_signal(_pid: u32, _signal: i32) -> Result<()> {
+    anyhow::bail!("signals are only supported on unix")
+}
+
+/// politely asks a child to exit
+///
+/// on unix this sends SIGTERM, elsewhere the child is killed outright
+#[cfg(unix)]
+fn terminate(child: &mut ChildHandle) -> Result<()> {
+    send_signal(child.id(), libc::SIGTERM)
+}
+
+#[cfg(not(unix))]
+fn terminate(child: &mut ChildHandle) -> Result<()> {
+    child.kill()
+}
+
+/// collects the exit status of one of our children without blocking
+///
+/// returns None while it is running, and Some(None) if it was killed by a signal
+#[cfg(unix)]
+fn reap(pid: u32) -> io::Result<Option<Option<i32>>> {
+    let mut status = 0;
+    // safety: waitpid only writes to the status we pass in
+    match unsafe { libc::waitpid(pid as libc::pid_t, &mut status, libc::WNOHANG) } {
+        0 => Ok(None),
+        -1 => {
+            let err = io::Error::last_os_error();
+            // someone else already reaped it, so the exit code is lost
+            if err.raw_os_error() == Some(libc::ECHILD) {
+                Ok(Some(None))
+            } else {
+                Err(err)
+            }
+        }
+        _ if libc::WIFEXITED(status) => Ok(Some(Some(libc::WEXITSTATUS(status)))),
+        _ => Ok(Some(None)),
+    }
+}
+
+#[cfg(not(unix))]
+fn reap(_pid: u32) -> io::Result<Option<Option<i32>>> {
+    Ok(Some(None))
+}
+
+/// whether a process that isn't our child is still running
+///
+/// zombies count as exited, since they are only waiting for their parent to reap them
+#[cfg(target_os = "linux")]
+fn is_alive(pid: u32) -> bool {
+    match std::fs::read_to_string(format!("/proc/{}/stat", pid)) {
+        // the state is the first field after the parenthesized command name
+        Ok(stat) => stat
+            .rsplit_once(')')
+            .and_then(|(_, rest)| rest.split_whitespace().next())
+            .is_some_and(|state| state != "Z" && state != "X"),
+        Err(_) => false,
+    }
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+fn is_alive(pid: u32) -> bool {
+    // safety: kill(2) with signal 0 only checks whether the process exists
+    unsafe { libc::kill(pid as libc::pid_t, 0) == 0 }
+}
+
+#[cfg(not(unix))]
+fn is_alive(_pid: u32) -> bool {
+    false
+}
+
+/// when a process started, in clock ticks since boot, used to tell
+/// whether a pid still belongs to the same process
+#[cfg(target_os = "linux")]
+fn start_time(pid: u32) -> Option<u64> {
+    let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    // starttime is the 22nd field, i.e. the 20th after the command name
+    let (_, rest) = stat.rsplit_once(')')?;
+    rest.split_whitespace().nth(19)?.parse().ok()
+}
+
+#[cfg(not(target_os = "linux"))]
+fn start_time(_pid: u32) -> Option<u64> {
+    None
+}
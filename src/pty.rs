@@ -0,0 +1,175 @@
+use crate::daemon::set_cloexec;
+use anyhow::Result;
+use log::warn;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::os::unix::process::CommandExt;
+use std::process::{Command, Stdio};
+use std::thread;
+
+/// a pseudo-terminal pair allocated by the daemon
+///
+/// the child gets the slave end as its controlling terminal,
+/// while the daemon keeps the master end to read output and write input
+pub struct Pty {
+    master: File,
+    slave: File,
+}
+
+impl Pty {
+    /// allocates a new pty with a default window size of 24x80
+    pub fn open() -> Result<Pty> {
+        let mut master: libc::c_int = -1;
+        let mut slave: libc::c_int = -1;
+        let size = libc::winsize {
+            ws_row: 24,
+            ws_col: 80,
+            ws_xpixel: 0,
+            ws_ypixel: 0,
+        };
+
+        // safety: openpty only writes to the two fds we pass in, and reads `size`
+        let res = unsafe {
+            libc::openpty(
+                &mut master,
+                &mut slave,
+                std::ptr::null_mut(),
+                std::ptr::null(),
+                &size,
+            )
+        };
+        if res != 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+
+        // safety: openpty succeeded, so both fds are open and owned by nobody else
+        let pty = unsafe {
+            Pty {
+                master: File::from_raw_fd(master),
+                slave: File::from_raw_fd(slave),
+            }
+        };
+        // openpty doesn't set FD_CLOEXEC, which would leak both ends into
+        // every process the daemon spawns from now on
+        set_cloexec(pty.master.as_raw_fd(), true)?;
+        set_cloexec(pty.slave.as_raw_fd(), true)?;
+
+        Ok(pty)
+    }
+
+    /// makes `cmd` run with the slave end as its stdio and controlling terminal
+    pub fn attach_to(&self, cmd: &mut Command) -> Result<()> {
+        cmd.stdin(Stdio::from(self.slave.try_clone()?))
+            .stdout(Stdio::from(self.slave.try_clone()?))
+            .stderr(Stdio::from(self.slave.try_clone()?));
+
+        // safety: setsid and ioctl are async-signal-safe
+        unsafe {
+            cmd.pre_exec(|| {
+                if libc::setsid() == -1 {
+                    return Err(io::Error::last_os_error());
+                }
+                // stdin is the slave end at this point
+                if libc::ioctl(0, libc::TIOCSCTTY as _, 0) == -1 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+
+        Ok(())
+    }
+
+    /// closes the daemon's copy of the slave end and returns the master end
+    ///
+    /// must be called once the child has been spawned, otherwise reading
+    /// the master never reports the end of the child's output
+    pub fn into_master(self) -> File {
+        self.master
+    }
+}
+
+/// changes the window size of the terminal behind a pty master
+pub fn set_window_size(master: &File, rows: u16, cols: u16) -> Result<()> {
+    let size = libc::winsize {
+        ws_row: rows,
+        ws_col: cols,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    };
+
+    // safety: TIOCSWINSZ only reads the winsize we pass in
+    if unsafe { libc::ioctl(master.as_raw_fd(), libc::TIOCSWINSZ, &size) } == -1 {
+        return Err(io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+/// returns the window size of the terminal on `fd`, if it is one
+pub fn window_size(fd: libc::c_int) -> Option<(u16, u16)> {
+    let mut size = libc::winsize {
+        ws_row: 0,
+        ws_col: 0,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    };
+
+    // safety: TIOCGWINSZ only writes to the winsize we pass in
+    if unsafe { libc::ioctl(fd, libc::TIOCGWINSZ, &mut size) } == -1 {
+        return None;
+    }
+    Some((size.ws_row, size.ws_col))
+}
+
+/// copies everything the child writes to the pty into its log file
+///
+/// the copy ends when all slave ends are closed, i.e. once the child has exited
+pub fn copy_to_log(mut master: File, mut log: File) {
+    thread::spawn(move || {
+        let mut buffer = [0; 8192];
+        loop {
+            match master.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(read) => {
+                    if let Err(e) = log.write_all(&buffer[..read]) {
+                        warn!("failed to write pty output to log: {}", e);
+                        break;
+                    }
+                }
+                // linux reports EIO on the master once the slave is gone
+                Err(e) if e.raw_os_error() == Some(libc::EIO) => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => {
+                    warn!("failed to read from pty: {}", e);
+                    break;
+                }
+            }
+        }
+    });
+}
+
+/// puts the terminal on stdin into raw mode, returning the previous settings
+pub fn enter_raw_mode() -> Option<libc::termios> {
+    // safety: tcgetattr/tcsetattr only access the termios we pass in
+    unsafe {
+        let mut original: libc::termios = std::mem::zeroed();
+        if libc::tcgetattr(0, &mut original) == -1 {
+            return None;
+        }
+        let mut raw = original;
+        libc::cfmakeraw(&mut raw);
+        if libc::tcsetattr(0, libc::TCSANOW, &raw) == -1 {
+            return None;
+        }
+        Some(original)
+    }
+}
+
+/// restores terminal settings saved by `enter_raw_mode`
+pub fn restore_mode(original: &libc::termios) {
+    // safety: tcsetattr only reads the termios we pass in
+    unsafe {
+        libc::tcsetattr(0, libc::TCSANOW, original);
+    }
+}
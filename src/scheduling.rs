@@ -0,0 +1,252 @@
+use crate::processing::{ProcessWaitStatus, SibylPID};
+use crate::util::format_duration;
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use std::ffi::OsString;
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+pub type JobID = u32;
+
+/// describes when a scheduled job runs
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum Trigger {
+    /// a cron expression, either in the classic 5-field form or with a leading seconds field
+    Cron(String),
+    /// a fixed interval, counted from when the job was registered
+    Interval(Duration),
+}
+
+impl Trigger {
+    /// returns the first time this trigger fires after `from`
+    pub fn next_after(&self, from: DateTime<Local>) -> Result<Option<DateTime<Local>>> {
+        match self {
+            Trigger::Cron(expr) => Ok(parse_cron(expr)?.after(&from).next()),
+            Trigger::Interval(interval) => {
+                let interval = chrono::Duration::from_std(*interval)?;
+                Ok(from.checked_add_signed(interval))
+            }
+        }
+    }
+}
+
+impl fmt::Display for Trigger {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self {
+            Trigger::Cron(expr) => write!(f, "cron '{}'", expr),
+            Trigger::Interval(interval) => write!(f, "every {}", format_duration(*interval)),
+        }
+    }
+}
+
+/// parses a cron expression, accepting the 5-field form used by crontab
+/// by running it at second 0 of each matching minute
+fn parse_cron(expr: &str) -> Result<cron::Schedule> {
+    let expr = if expr.split_whitespace().count() == 5 {
+        format!("0 {}", expr)
+    } else {
+        expr.to_string()
+    };
+    cron::Schedule::from_str(&expr).map_err(|e| anyhow!("invalid cron expression: {}", e))
+}
+
+/// what to do when a job comes due while a previous run is still going
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum OverlapPolicy {
+    /// drop the new run
+    Skip,
+    /// start the new run as soon as the previous one exits
+    Queue,
+    /// start the new run alongside the previous one
+    Allow,
+}
+
+impl FromStr for OverlapPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "skip" => Ok(OverlapPolicy::Skip),
+            "queue" => Ok(OverlapPolicy::Queue),
+            "allow" => Ok(OverlapPolicy::Allow),
+            _ => Err(anyhow!("unknown overlap policy '{}'", s)),
+        }
+    }
+}
+
+impl fmt::Display for OverlapPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self {
+            OverlapPolicy::Skip => write!(f, "skip"),
+            OverlapPolicy::Queue => write!(f, "queue"),
+            OverlapPolicy::Allow => write!(f, "allow"),
+        }
+    }
+}
+
+/// a job registered with the schedule handler, along with its run history
+pub struct ScheduledJob {
+    pub id: JobID,
+    pub program: OsString,
+    pub args: Vec<OsString>,
+    pub trigger: Trigger,
+    pub overlap: OverlapPolicy,
+    pub next_run: Option<DateTime<Local>>,
+    pub last_run: Option<DateTime<Local>>,
+    pub last_exit: Option<ProcessWaitStatus>,
+    /// sibyl pids of the runs that have not been seen exiting yet
+    pub running: Vec<SibylPID>,
+    /// whether a run was held back by `OverlapPolicy::Queue`
+    pub queued: bool,
+}
+
+impl ScheduledJob {
+    /// the command line of the job, for display purposes
+    pub fn cmdline(&self) -> String {
+        let mut cmdline = self.program.to_string_lossy().into_owned();
+        for arg in &self.args {
+            cmdline.push(' ');
+            cmdline.push_str(&arg.to_string_lossy());
+        }
+        cmdline
+    }
+}
+
+/// state structure for the schedule handler
+/// keeps track of periodic jobs, which the supervisor starts when they come due
+pub struct ScheduleHandler {
+    count: JobID,
+    jobs: Vec<ScheduledJob>,
+}
+
+impl ScheduleHandler {
+    /// creates a new schedule handler
+    pub fn new() -> ScheduleHandler {
+        ScheduleHandler {
+            count: 0,
+            jobs: Vec::new(),
+        }
+    }
+
+    /// registers a new job and computes its first run time
+    /// # Arguments
+    /// * `program` - the program to run
+    /// * `args` - the arguments passed to the program
+    /// * `trigger` - when the job runs
+    /// * `overlap` - what to do if a run is still going when the next one is due
+    pub fn add_job(
+        &mut self,
+        program: OsString,
+        args: Vec<OsString>,
+        trigger: Trigger,
+        overlap: OverlapPolicy,
+    ) -> Result<JobID> {
+        let next_run = trigger.next_after(Local::now())?;
+
+        self.count += 1;
+        self.jobs.push(ScheduledJob {
+            id: self.count,
+            program,
+            args,
+            trigger,
+            overlap,
+            next_run,
+            last_run: None,
+            last_exit: None,
+            running: Vec::new(),
+            queued: false,
+        });
+
+        Ok(self.count)
+    }
+
+    /// removes a job, returning false if it did not exist
+    ///
+    /// runs that are already going are left alone
+    pub fn remove_job(&mut self, id: JobID) -> bool {
+        let len = self.jobs.len();
+        self.jobs.retain(|job| job.id != id);
+        self.jobs.len() != len
+    }
+
+    /// puts back a job carried over from a previous daemon, keeping its id
+    pub fn restore_job(&mut self, job: ScheduledJob) {
+        self.count = self.count.max(job.id);
+        self.jobs.push(job);
+    }
+
+    pub fn all_jobs(&self) -> &[ScheduledJob] {
+        self.jobs.as_slice()
+    }
+
+    pub fn all_jobs_mut(&mut self) -> &mut [ScheduledJob] {
+        self.jobs.as_mut_slice()
+    }
+}
+
+impl Default for ScheduleHandler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn at(h: u32, m: u32, s: u32) -> DateTime<Local> {
+        Local.ymd(2024, 5, 1).and_hms(h, m, s)
+    }
+
+    #[test]
+    fn cron_with_five_fields_fires_at_second_zero() {
+        let trigger = Trigger::Cron("*/15 * * * *".to_string());
+        assert_eq!(
+            trigger.next_after(at(12, 7, 30)).unwrap(),
+            Some(at(12, 15, 0))
+        );
+        assert_eq!(
+            trigger.next_after(at(12, 15, 0)).unwrap(),
+            Some(at(12, 30, 0))
+        );
+    }
+
+    #[test]
+    fn cron_with_seconds_field() {
+        let trigger = Trigger::Cron("30 0 13 * * *".to_string());
+        assert_eq!(
+            trigger.next_after(at(12, 7, 30)).unwrap(),
+            Some(at(13, 0, 30))
+        );
+    }
+
+    #[test]
+    fn invalid_cron_is_an_error() {
+        assert!(Trigger::Cron("not cron".to_string())
+            .next_after(at(12, 0, 0))
+            .is_err());
+        assert!(Trigger::Cron("61 * * * *".to_string())
+            .next_after(at(12, 0, 0))
+            .is_err());
+    }
+
+    #[test]
+    fn interval_fires_after_the_interval() {
+        let trigger = Trigger::Interval(Duration::from_secs(90));
+        assert_eq!(
+            trigger.next_after(at(12, 0, 0)).unwrap(),
+            Some(at(12, 1, 30))
+        );
+    }
+
+    #[test]
+    fn interval_too_long_never_fires() {
+        // within what chrono can represent as a duration, but not as a date
+        let trigger = Trigger::Interval(Duration::from_secs(1 << 50));
+        assert_eq!(trigger.next_after(at(12, 0, 0)).unwrap(), None);
+    }
+}
@@ -0,0 +1,336 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Local, NaiveDateTime, TimeZone, Utc};
+use flate2::read::MultiGzDecoder;
+use regex::Regex;
+use std::collections::{HashMap, VecDeque};
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Read};
+use std::path::{Path, PathBuf};
+
+/// the most of a line that is searched and shown, so that a log
+/// without newlines can't make a search read it all into memory
+const MAX_LINE: usize = 64 * 1024;
+
+/// one generation of a process' log in the log directory
+///
+/// besides the `.slog` files sibyld writes, this covers the generations
+/// left behind by rotating them, e.g. `web_<time>.slog.1`, `.slog.2.gz` or `.slog.gz`
+pub struct LogSource {
+    pub path: PathBuf,
+    /// the name of the process the log belongs to
+    pub process: String,
+    /// when the log was created, from the time in its name
+    pub created: Option<DateTime<Utc>>,
+    pub modified: DateTime<Utc>,
+    /// 0 for the log itself, 1 and up for older rotated generations
+    pub generation: u32,
+    pub compressed: bool,
+}
+
+impl LogSource {
+    /// whether anything was written to the log between `since` and `until`
+    ///
+    /// log lines carry no time of their own, so this is the closest a search can get
+    pub fn overlaps(&self, since: Option<DateTime<Utc>>, until: Option<DateTime<Utc>>) -> bool {
+        let created = self.created.unwrap_or(self.modified);
+        since.is_none_or(|since| self.modified >= since)
+            && until.is_none_or(|until| created <= until)
+    }
+
+    fn open(&self) -> Result<Box<dyn BufRead>> {
+        let file = File::open(&self.path)?;
+        Ok(if self.compressed {
+            Box::new(BufReader::new(MultiGzDecoder::new(file)))
+        } else {
+            Box::new(BufReader::new(file))
+        })
+    }
+}
+
+/// lists the logs in a directory, grouped by log and with each log's generations oldest first
+/// # Arguments
+/// * `directory` - the log directory
+/// * `known` - the names of the processes the daemon knows, by their log's path. the logs
+///   of other processes are named after their file, e.g. `web` for `web_<time>.slog`
+pub fn find_logs(directory: &Path, known: &HashMap<PathBuf, String>) -> Result<Vec<LogSource>> {
+    let entries = match fs::read_dir(directory) {
+        Ok(entries) => entries,
+        // nothing has been logged yet
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => {
+            return Err(e)
+                .with_context(|| format!("failed to read log directory {}", directory.display()))
+        }
+    };
+
+    let mut logs = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        let file_name = entry.file_name().to_string_lossy().into_owned();
+        let (rest, compressed) = match file_name.strip_suffix(".gz") {
+            Some(rest) => (rest, true),
+            None => (file_name.as_str(), false),
+        };
+        let (rest, generation) = match rest.rsplit_once('.') {
+            Some((rest, n)) if !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()) => {
+                (rest, n.parse().unwrap_or(u32::MAX))
+            }
+            _ => (rest, 0),
+        };
+        let stem = match rest.strip_suffix(".slog") {
+            Some(stem) => stem,
+            None => continue,
+        };
+        let metadata = entry.metadata()?;
+        if !metadata.is_file() {
+            continue;
+        }
+
+        let (name, created) = parse_log_name(stem);
+        let process = known.get(&directory.join(rest)).cloned().unwrap_or(name);
+        logs.push(LogSource {
+            path: entry.path(),
+            process,
+            created,
+            modified: metadata.modified()?.into(),
+            generation,
+            compressed,
+        });
+    }
+    logs.sort_by(|a, b| {
+        (&a.process, a.created, std::cmp::Reverse(a.generation)).cmp(&(
+            &b.process,
+            b.created,
+            std::cmp::Reverse(b.generation),
+        ))
+    });
+    Ok(logs)
+}
+
+/// splits a log's name into what it was named after and the time it was created,
+/// which `timestamped` appended as e.g. `_2024-05-01-12-00-00`
+fn parse_log_name(stem: &str) -> (String, Option<DateTime<Utc>>) {
+    let created = stem.rsplit_once('_').and_then(|(name, time)| {
+        let time = NaiveDateTime::parse_from_str(time, "%Y-%m-%d-%H-%M-%S").ok()?;
+        let time = Local.from_local_datetime(&time).earliest()?;
+        Some((name, time.with_timezone(&Utc)))
+    });
+    match created {
+        Some((name, time)) => (name.to_string(), Some(time)),
+        None => (stem.to_string(), None),
+    }
+}
+
+/// searches a log for lines matching a pattern, grep-style
+///
+/// every matching line is passed to `out` as `<process> <file>:<line>: <text>`, and the
+/// lines around it as `<process> <file>-<line>- <text>`, with `--` between groups of
+/// lines that aren't adjacent. lines longer than MAX_LINE are cut short.
+/// returns the number of matching lines
+/// # Arguments
+/// * `log` - the log to search
+/// * `regex` - the pattern lines must match
+/// * `context` - how many lines to show before and after each match
+/// * `out` - receives the lines to show, without a newline
+pub fn search_log(
+    log: &LogSource,
+    regex: &Regex,
+    context: usize,
+    out: &mut impl FnMut(&str) -> Result<()>,
+) -> Result<usize> {
+    let mut reader = log.open()?;
+    let file_name = log
+        .path
+        .file_name()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .into_owned();
+
+    let mut matches = 0;
+    // lines that may precede a match, and how many lines after the last match are left to show
+    let mut before: VecDeque<(usize, String)> = VecDeque::new();
+    let mut after = 0;
+    let mut last_shown: Option<usize> = None;
+    let mut show = |number: usize, line: &str, separator: char| -> Result<()> {
+        // like grep, separators only come with context
+        if context > 0 && last_shown.is_some_and(|last| number > last + 1) {
+            out("--")?;
+        }
+        last_shown = Some(number);
+        out(&format!(
+            "{} {}{}{}{} {}",
+            log.process, file_name, separator, number, separator, line
+        ))
+    };
+
+    let mut buffer = Vec::new();
+    let mut number = 0;
+    loop {
+        buffer.clear();
+        let read = (&mut reader)
+            .take(MAX_LINE as u64)
+            .read_until(b'\n', &mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        if !buffer.ends_with(b"\n") {
+            skip_line(&mut reader)?;
+        }
+        number += 1;
+        // logs are whatever processes wrote, which needn't be UTF-8
+        let line = String::from_utf8_lossy(&buffer);
+        let line = line.trim_end_matches(['\n', '\r']);
+
+        if regex.is_match(line) {
+            matches += 1;
+            for (number, line) in before.drain(..) {
+                show(number, &line, '-')?;
+            }
+            show(number, line, ':')?;
+            after = context;
+        } else if after > 0 {
+            after -= 1;
+            show(number, line, '-')?;
+        } else if context > 0 {
+            before.push_back((number, line.to_string()));
+            if before.len() > context {
+                before.pop_front();
+            }
+        }
+    }
+    Ok(matches)
+}
+
+/// skips the rest of the current line, without holding on to any of it
+fn skip_line(reader: &mut impl BufRead) -> Result<()> {
+    loop {
+        let available = reader.fill_buf()?;
+        if available.is_empty() {
+            return Ok(());
+        }
+        match available.iter().position(|&b| b == b'\n') {
+            Some(end) => {
+                reader.consume(end + 1);
+                return Ok(());
+            }
+            None => {
+                let len = available.len();
+                reader.consume(len);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::TempDir;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::io::Write;
+
+    fn source(path: PathBuf, compressed: bool) -> LogSource {
+        LogSource {
+            path,
+            process: String::from("web"),
+            created: None,
+            modified: Utc::now(),
+            generation: 0,
+            compressed,
+        }
+    }
+
+    /// the lines `search_log` shows for a pattern
+    fn search(log: &LogSource, pattern: &str, context: usize) -> (usize, Vec<String>) {
+        let mut lines = Vec::new();
+        let regex = Regex::new(pattern).unwrap();
+        let found = search_log(log, &regex, context, &mut |line: &str| {
+            lines.push(line.to_string());
+            Ok(())
+        })
+        .unwrap();
+        (found, lines)
+    }
+
+    fn write_log(dir: &TempDir, lines: &[&str]) -> LogSource {
+        let path = dir.path().join("web.slog");
+        fs::write(&path, lines.join("\n") + "\n").unwrap();
+        source(path, false)
+    }
+
+    #[test]
+    fn matches_without_context() {
+        let dir = TempDir::new("search-plain");
+        let log = write_log(&dir, &["one", "two", "three", "twenty"]);
+        let (found, lines) = search(&log, "tw", 0);
+        assert_eq!(found, 2);
+        assert_eq!(lines, ["web web.slog:2: two", "web web.slog:4: twenty"]);
+    }
+
+    #[test]
+    fn context_is_separated_between_groups() {
+        let dir = TempDir::new("search-context");
+        let lines: Vec<String> = (1..=10).map(|i| format!("line {}", i)).collect();
+        let lines: Vec<&str> = lines.iter().map(String::as_str).collect();
+        let log = write_log(&dir, &lines);
+
+        let (found, shown) = search(&log, "^line (3|9)$", 1);
+        assert_eq!(found, 2);
+        assert_eq!(
+            shown,
+            [
+                "web web.slog-2- line 2",
+                "web web.slog:3: line 3",
+                "web web.slog-4- line 4",
+                "--",
+                "web web.slog-8- line 8",
+                "web web.slog:9: line 9",
+                "web web.slog-10- line 10",
+            ]
+        );
+    }
+
+    #[test]
+    fn overlapping_context_is_shown_once() {
+        let dir = TempDir::new("search-overlap");
+        let log = write_log(&dir, &["a", "match", "b", "match", "c", "d"]);
+        let (found, shown) = search(&log, "match", 2);
+        assert_eq!(found, 2);
+        assert_eq!(
+            shown,
+            [
+                "web web.slog-1- a",
+                "web web.slog:2: match",
+                "web web.slog-3- b",
+                "web web.slog:4: match",
+                "web web.slog-5- c",
+                "web web.slog-6- d",
+            ]
+        );
+    }
+
+    #[test]
+    fn long_lines_are_cut_short() {
+        let dir = TempDir::new("search-long");
+        let long = "x".repeat(MAX_LINE * 3);
+        let log = write_log(&dir, &[&long, "match"]);
+
+        let (found, shown) = search(&log, "x|match", 0);
+        assert_eq!(found, 2);
+        assert_eq!(shown[0].len(), "web web.slog:1: ".len() + MAX_LINE);
+        assert_eq!(shown[1], "web web.slog:2: match");
+    }
+
+    #[test]
+    fn compressed_logs_are_searched() {
+        let dir = TempDir::new("search-gz");
+        let path = dir.path().join("web.slog.1.gz");
+        let mut encoder = GzEncoder::new(File::create(&path).unwrap(), Compression::default());
+        encoder.write_all(b"one\ntwo\n").unwrap();
+        encoder.finish().unwrap();
+
+        let (found, shown) = search(&source(path, true), "two", 0);
+        assert_eq!(found, 1);
+        assert_eq!(shown, ["web web.slog.1.gz:2: two"]);
+    }
+}
@@ -0,0 +1,178 @@
+use crate::definitions::ProcessDefinition;
+use crate::processing::SibylPID;
+use crate::scheduling::{JobID, OverlapPolicy, ScheduledJob, Trigger};
+use anyhow::{Context, Result};
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// a process left running by a daemon that detached from it
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SavedProcess {
+    pub spid: SibylPID,
+    pub os_pid: u32,
+    /// when the os process started, to tell if its pid has been reused since
+    pub start_time: Option<u64>,
+    pub cmdline: String,
+    pub started: DateTime<Local>,
+    pub log_file: PathBuf,
+    pub restarts: u32,
+    /// how much of the process' timeout was left when it was saved
+    pub timeout_left: Option<Duration>,
+    /// the write end of the process' stdin pipe, only handed over during upgrades
+    #[serde(default)]
+    pub stdin_fd: Option<i32>,
+    /// the master end of the process' pty, only handed over during upgrades
+    #[serde(default)]
+    pub pty_fd: Option<i32>,
+    pub definition: Option<ProcessDefinition>,
+}
+
+/// a scheduled job, carried over to the next daemon
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SavedJob {
+    pub id: JobID,
+    pub program: String,
+    pub args: Vec<String>,
+    // toml can't hold a `Trigger`, so it is saved as whichever of these is set
+    pub cron: Option<String>,
+    pub interval: Option<Duration>,
+    pub overlap: OverlapPolicy,
+    pub next_run: Option<DateTime<Local>>,
+    pub last_run: Option<DateTime<Local>>,
+    pub running: Vec<SibylPID>,
+    pub queued: bool,
+}
+
+impl From<&ScheduledJob> for SavedJob {
+    fn from(job: &ScheduledJob) -> Self {
+        let (cron, interval) = match &job.trigger {
+            Trigger::Cron(expr) => (Some(expr.clone()), None),
+            Trigger::Interval(interval) => (None, Some(*interval)),
+        };
+        SavedJob {
+            id: job.id,
+            program: job.program.to_string_lossy().into_owned(),
+            args: job
+                .args
+                .iter()
+                .map(|arg| arg.to_string_lossy().into_owned())
+                .collect(),
+            cron,
+            interval,
+            overlap: job.overlap,
+            next_run: job.next_run,
+            last_run: job.last_run,
+            running: job.running.clone(),
+            queued: job.queued,
+        }
+    }
+}
+
+impl From<SavedJob> for ScheduledJob {
+    fn from(job: SavedJob) -> Self {
+        let trigger = match (job.cron, job.interval) {
+            (Some(expr), _) => Trigger::Cron(expr),
+            (None, interval) => Trigger::Interval(interval.unwrap_or_default()),
+        };
+        ScheduledJob {
+            id: job.id,
+            program: OsString::from(job.program),
+            args: job.args.into_iter().map(OsString::from).collect(),
+            trigger,
+            overlap: job.overlap,
+            next_run: job.next_run,
+            last_run: job.last_run,
+            last_exit: None,
+            running: job.running,
+            queued: job.queued,
+        }
+    }
+}
+
+/// a definition's hook that was still running when the daemon was upgraded
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SavedHook {
+    pub os_pid: u32,
+    /// e.g. `on_failure hook of 'web'`
+    pub what: String,
+    /// how much of the hook's timeout was left when it was saved
+    pub timeout_left: Duration,
+    /// whether it had already been killed for timing out
+    pub killed: bool,
+}
+
+/// file descriptors of the daemon itself that are kept open across an upgrade
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct DaemonFds {
+    pub listeners: Vec<i32>,
+    #[serde(default)]
+    pub tls_listeners: Vec<i32>,
+    #[serde(default)]
+    pub http_listeners: Vec<i32>,
+    pub pid_file: Option<i32>,
+}
+
+/// everything a new daemon needs to take over from one that shut down
+/// with the detach policy, or that is being upgraded
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct SavedState {
+    #[serde(default, rename = "process")]
+    pub processes: Vec<SavedProcess>,
+    #[serde(default)]
+    pub pending: Vec<ProcessDefinition>,
+    #[serde(default, rename = "job")]
+    pub jobs: Vec<SavedJob>,
+    /// only set during upgrades
+    #[serde(default, rename = "hook")]
+    pub hooks: Vec<SavedHook>,
+    /// only set during upgrades
+    #[serde(default)]
+    pub fds: DaemonFds,
+}
+
+impl SavedState {
+    /// writes the state to a file, replacing it atomically
+    pub fn save(&self, path: &Path) -> Result<()> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)
+                .with_context(|| format!("failed to create directory {}", dir.display()))?;
+        }
+
+        // going through a Value puts nested tables after plain values, which toml requires
+        let contents = toml::Value::try_from(self)
+            .and_then(|value| toml::to_string(&value))
+            .context("failed to serialize daemon state")?;
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, contents)
+            .with_context(|| format!("failed to write daemon state to {}", tmp.display()))?;
+        fs::rename(&tmp, path)
+            .with_context(|| format!("failed to write daemon state to {}", path.display()))?;
+
+        Ok(())
+    }
+
+    /// reads and removes a state file, so that its processes are only adopted once
+    ///
+    /// returns None if there is no state file
+    pub fn take(path: &Path) -> Result<Option<SavedState>> {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => {
+                return Err(e)
+                    .with_context(|| format!("failed to read daemon state {}", path.display()))
+            }
+        };
+        let state = toml::from_str(&contents)
+            .with_context(|| format!("failed to parse daemon state {}", path.display()))?;
+        fs::remove_file(path)
+            .with_context(|| format!("failed to remove daemon state {}", path.display()))?;
+
+        Ok(Some(state))
+    }
+}
@@ -0,0 +1,537 @@
+use crate::commands::{stop_for_shutdown, CmdOnce, CommandContext, SharedContext};
+use crate::config::ShutdownPolicy;
+use crate::definitions::{DependencyCondition, ProcessDefinition};
+use crate::events::EventKind;
+use crate::processing::{CrashState, LaunchOptions, ProcessWaitStatus};
+use crate::scheduling::OverlapPolicy;
+use chrono::Local;
+use log::{error, info, warn};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// how often the supervisor wakes up to look after processes
+const TICK_INTERVAL: Duration = Duration::from_millis(250);
+
+/// how often old logs are deleted according to the retention settings
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// how long an exited process waits before it is restarted
+const RESTART_DELAY: Duration = Duration::from_secs(1);
+
+/// set by `request_reload`, and cleared once the supervisor has reloaded the config
+static RELOAD_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// set by `request_shutdown`, and cleared once the supervisor has tried to shut down
+static SHUTDOWN_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// the result of checking a single dependency of a pending definition
+enum Readiness {
+    Ready,
+    Waiting,
+    Failed(String),
+}
+
+/// spawns the supervisor thread, which periodically locks the context and calls `tick`
+pub fn spawn(ctx: Arc<Mutex<CommandContext>>) -> JoinHandle<()> {
+    thread::spawn(move || {
+        let mut last_prune: Option<Instant> = None;
+        loop {
+            thread::sleep(TICK_INTERVAL);
+            if SHUTDOWN_REQUESTED.swap(false, Ordering::SeqCst) {
+                shutdown(&ctx);
+            }
+            let mut ctx = match ctx.lock() {
+                Ok(ctx) => ctx,
+                Err(_) => {
+                    error!("command context was poisoned, stopping supervisor");
+                    return;
+                }
+            };
+
+            tick(&mut ctx);
+            if last_prune.is_none_or(|last| last.elapsed() >= PRUNE_INTERVAL) {
+                prune_logs(&mut ctx);
+                last_prune = Some(Instant::now());
+            }
+        }
+    })
+}
+
+/// asks the supervisor to reload the config on its next tick
+///
+/// only stores to an atomic, so it is safe to call from a signal handler
+pub fn request_reload() {
+    RELOAD_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+/// asks the supervisor to shut the daemon down on its next tick, using the configured policy
+///
+/// only stores to an atomic, so it is safe to call from a signal handler
+pub fn request_shutdown() {
+    SHUTDOWN_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+/// performs one round of background work on the managed processes
+pub fn tick(ctx: &mut CommandContext) {
+    if RELOAD_REQUESTED.swap(false, Ordering::SeqCst) {
+        reload(ctx);
+    }
+    let mut events = Vec::new();
+    for proc in ctx.prochandler.all_processes_mut() {
+        if let Err(e) = proc.enforce_timeout() {
+            error!("failed to enforce timeout of spid {}: {}", proc.pid, e);
+        }
+        let health = proc.health;
+        proc.probe_health();
+        if proc.health != health {
+            let name = proc.display_name().unwrap_or_default();
+            events.push(EventKind::HealthChanged {
+                spid: proc.pid,
+                name,
+                from: health,
+                to: proc.health,
+            });
+        }
+    }
+    for event in events {
+        ctx.emit(event);
+    }
+    report_exits(ctx);
+    recover_crash_loops(ctx);
+    ctx.hooks.tick();
+    restart_exited(ctx);
+    start_ready(ctx);
+    run_schedules(ctx);
+}
+
+/// shuts down with the configured policy and exits the daemon,
+/// unless stopping the processes fails
+fn shutdown(ctx: &SharedContext) {
+    let policy = ctx.lock().unwrap().config.shutdown;
+    info!("shutting down, {} policy", policy);
+    let prepared = ctx.lock().unwrap().shutdown(policy);
+    let result = prepared.and_then(|msg| {
+        for line in msg.lines().skip(1) {
+            info!("{}", line);
+        }
+        if policy == ShutdownPolicy::Stop {
+            stop_for_shutdown(ctx, |line| info!("{}", line))?;
+        }
+        Ok(())
+    });
+    match result {
+        Ok(()) => {
+            ctx.lock().unwrap().hooks.kill_all();
+            std::process::exit(0)
+        }
+        Err(e) => error!("failed to shut down, sibyld keeps running: {:#}", e),
+    }
+}
+
+/// reloads the config, logging what changed
+fn reload(ctx: &mut CommandContext) {
+    match ctx.reload_config() {
+        Ok(msg) => {
+            for line in msg.lines() {
+                match line.trim().strip_prefix("warning: ") {
+                    Some(warning) => warn!("{}", warning),
+                    None => info!("{}", line.trim()),
+                }
+            }
+        }
+        Err(e) => error!("failed to reload config, keeping the old one: {:#}", e),
+    }
+}
+
+/// starts every pending definition whose dependencies are satisfied
+///
+/// definitions whose dependencies can never be satisfied are dropped
+fn start_ready(ctx: &mut CommandContext) {
+    let pending: Vec<ProcessDefinition> = ctx.prochandler.pending().to_vec();
+    let mut ready = Vec::new();
+    let mut failed = Vec::new();
+
+    for def in &pending {
+        match readiness(ctx, def, &pending) {
+            Readiness::Ready => ready.push(def.name.clone()),
+            Readiness::Waiting => {}
+            Readiness::Failed(reason) => {
+                error!("not starting '{}': {}", def.name, reason);
+                failed.push(def.name.clone());
+            }
+        }
+    }
+
+    ctx.prochandler
+        .take_pending(|def| failed.contains(&def.name));
+    for name in ready {
+        // the rest stay pending until a slot frees up
+        if ctx.at_capacity() {
+            break;
+        }
+        let def = ctx
+            .prochandler
+            .take_pending(|def| def.name == name)
+            .remove(0);
+        let options = def.launch_options();
+        match ctx.launch(
+            &def,
+            &def.program(),
+            &def.args(),
+            Some(def.clone()),
+            &options,
+        ) {
+            Ok(pid) => info!("started '{}' with sibyl pid {}", def.name, pid),
+            Err(e) => error!("failed to start '{}': {}", def.name, e),
+        }
+    }
+}
+
+/// notes the processes that exited since the last tick, and tells subscribers about them
+fn report_exits(ctx: &mut CommandContext) {
+    let mut events = Vec::new();
+    for proc in ctx.prochandler.all_processes_mut() {
+        if proc.exit_seen.is_some() {
+            continue;
+        }
+        let (code, timed_out) = match proc.wait_status() {
+            ProcessWaitStatus::Exited(code) => (code, false),
+            ProcessWaitStatus::TimedOut(code) => (code, true),
+            ProcessWaitStatus::Running(_) | ProcessWaitStatus::Unknown => continue,
+        };
+        proc.exit_seen = Some(Instant::now());
+        events.push(EventKind::Exited {
+            spid: proc.pid,
+            name: proc.display_name().unwrap_or_default(),
+            code,
+            signal: code.map_or_else(|| proc.child.exit_signal(), |_| None),
+            timed_out,
+            stopped: proc.stopped,
+        });
+    }
+
+    let mut looping = Vec::new();
+    for event in &events {
+        let spid = match *event {
+            EventKind::Exited { spid, .. } if event.is_failure() => spid,
+            _ => continue,
+        };
+        let name = match ctx.prochandler.get_process_by_pid(spid) {
+            Some(proc) => match proc.name() {
+                Some(name) => name.to_string(),
+                None => continue,
+            },
+            None => continue,
+        };
+        looping.extend(record_failure(ctx, &name));
+    }
+    for event in events.into_iter().chain(looping) {
+        ctx.emit(event);
+    }
+}
+
+/// counts a failure towards a definition's crash loop, moving it into backoff
+/// or quarantine once it crosses the configured thresholds
+///
+/// returns the event announcing the change, if there was one
+fn record_failure(ctx: &mut CommandContext, name: &str) -> Option<EventKind> {
+    let config = &ctx.config.crash_loop;
+    let window = config.window_duration;
+    let crash = ctx.prochandler.crash_loop_mut(name);
+    let now = Instant::now();
+    crash
+        .recent
+        .retain(|failed| now.duration_since(*failed) <= window);
+    crash.recent.push(now);
+
+    match crash.state {
+        CrashState::Stable if crash.recent.len() >= config.failures => {
+            warn!(
+                "'{}' failed {} times within {}, backing off its restarts",
+                name,
+                crash.recent.len(),
+                config.window
+            );
+            crash.state = CrashState::Backoff { failures: 0 };
+            Some(EventKind::CrashLooping {
+                name: name.to_string(),
+                failures: crash.recent.len(),
+                window_secs: window.as_secs(),
+            })
+        }
+        CrashState::Stable => None,
+        CrashState::Backoff { failures } if failures + 1 >= config.quarantine_after => {
+            error!(
+                "'{}' is still crash-looping, no longer restarting it until `sibyl reset {}`",
+                name, name
+            );
+            crash.state = CrashState::Quarantined {
+                failures: failures + 1,
+            };
+            Some(EventKind::Quarantined {
+                name: name.to_string(),
+                failures: failures + 1,
+            })
+        }
+        CrashState::Backoff { failures } => {
+            crash.state = CrashState::Backoff {
+                failures: failures + 1,
+            };
+            None
+        }
+        CrashState::Quarantined { .. } => None,
+    }
+}
+
+/// takes definitions out of backoff once their latest process has
+/// stayed up for a whole crash loop window
+fn recover_crash_loops(ctx: &mut CommandContext) {
+    let window = ctx.config.crash_loop.window_duration;
+    let mut recovered = Vec::new();
+    for proc in ctx.prochandler.all_processes_mut() {
+        let name = match proc.name() {
+            Some(name) => name.to_string(),
+            None => continue,
+        };
+        let up_for = (Local::now() - proc.started).to_std().unwrap_or_default();
+        if up_for >= window && matches!(proc.wait_status(), ProcessWaitStatus::Running(_)) {
+            recovered.push(name);
+        }
+    }
+    for name in recovered {
+        let backing_off = ctx
+            .prochandler
+            .crash_loop(&name)
+            .is_some_and(|crash| matches!(crash.state, CrashState::Backoff { .. }));
+        if backing_off {
+            info!("'{}' is no longer crash-looping", name);
+            ctx.prochandler.reset_crash_loop(&name);
+        }
+    }
+}
+
+/// starts a new instance of every definition-backed process that
+/// has exited and whose restart policy asks for it
+///
+/// processes stopped on purpose (e.g. by `sibyl down`) are left alone
+fn restart_exited(ctx: &mut CommandContext) {
+    let default = ctx.config.restart;
+    let mut delays = HashMap::new();
+    for proc in ctx.prochandler.all_processes() {
+        if let Some(name) = proc.name() {
+            let delay = match ctx.prochandler.crash_loop(name).map(|crash| crash.state) {
+                Some(CrashState::Backoff { failures }) => {
+                    Some(ctx.config.crash_loop.backoff(failures))
+                }
+                // left exited until `sibyl reset`
+                Some(CrashState::Quarantined { .. }) => None,
+                Some(CrashState::Stable) | None => Some(RESTART_DELAY),
+            };
+            delays.insert(name.to_string(), delay);
+        }
+    }
+    let mut to_restart = Vec::new();
+
+    for proc in ctx.prochandler.all_processes_mut() {
+        if proc.replaced || proc.stopped {
+            continue;
+        }
+        let (policy, delay) = match &proc.definition {
+            Some(def) => match delays[&def.name] {
+                Some(delay) => (def.restart.unwrap_or(default), delay),
+                None => continue,
+            },
+            None => continue,
+        };
+        let failed = match proc.wait_status() {
+            ProcessWaitStatus::Running(_) => continue,
+            ProcessWaitStatus::Exited(Some(0)) => false,
+            _ => true,
+        };
+        if !policy.should_restart(failed) {
+            continue;
+        }
+
+        let seen = *proc.exit_seen.get_or_insert_with(Instant::now);
+        if seen.elapsed() >= delay {
+            to_restart.push(proc.pid);
+        }
+    }
+
+    for old in to_restart {
+        if ctx.at_capacity() {
+            return;
+        }
+        let proc = ctx.prochandler.get_process_by_pid_mut(old).unwrap();
+        let def = proc.definition.clone().unwrap();
+        let restarts = proc.restarts + 1;
+
+        match ctx.launch(
+            &def,
+            &def.program(),
+            &def.args(),
+            Some(def.clone()),
+            &def.launch_options(),
+        ) {
+            Ok(pid) => {
+                info!("restarted '{}' with sibyl pid {}", def.name, pid);
+                ctx.prochandler
+                    .get_process_by_pid_mut(pid)
+                    .unwrap()
+                    .restarts = restarts;
+                ctx.emit(EventKind::Restarted {
+                    name: def.name.clone(),
+                    previous_spid: old,
+                    spid: pid,
+                    restarts,
+                });
+            }
+            Err(e) => error!("failed to restart '{}': {}", def.name, e),
+        }
+        // a failed restart is not retried, so that a broken
+        // definition doesn't flood the log every tick
+        ctx.prochandler
+            .get_process_by_pid_mut(old)
+            .unwrap()
+            .replaced = true;
+    }
+}
+
+/// deletes old logs according to the retention settings, sparing those of running processes
+fn prune_logs(ctx: &mut CommandContext) {
+    let retention = &ctx.config.retention;
+    if retention.max_age.is_none() && retention.max_files.is_none() {
+        return;
+    }
+
+    let keep: Vec<PathBuf> = ctx
+        .prochandler
+        .all_processes_mut()
+        .iter_mut()
+        .filter_map(|proc| match proc.wait_status() {
+            ProcessWaitStatus::Running(_) => Some(proc.log_file.clone()),
+            _ => None,
+        })
+        .collect();
+
+    match ctx.loghandler.prune(&ctx.config.retention, &keep) {
+        Ok(0) => {}
+        Ok(deleted) => {
+            info!("deleted {} old log files", deleted);
+            ctx.emit(EventKind::LogsPruned { deleted });
+        }
+        Err(e) => error!("failed to delete old logs: {}", e),
+    }
+}
+
+/// checks whether all dependencies of `def` have reached their required condition
+fn readiness(
+    ctx: &mut CommandContext,
+    def: &ProcessDefinition,
+    pending: &[ProcessDefinition],
+) -> Readiness {
+    for dep in &def.depends_on {
+        let proc = match ctx.prochandler.get_process_by_name(&dep.name) {
+            Some(proc) => proc,
+            None if pending.iter().any(|p| p.name == dep.name) => return Readiness::Waiting,
+            None => return Readiness::Failed(format!("unknown dependency '{}'", dep.name)),
+        };
+
+        let satisfied = match dep.condition {
+            DependencyCondition::Started => true,
+            DependencyCondition::Healthy => proc.is_healthy(),
+            DependencyCondition::ExitedSuccessfully => match proc.wait_status() {
+                ProcessWaitStatus::Exited(Some(0)) => true,
+                ProcessWaitStatus::Running(_) => false,
+                status => {
+                    return Readiness::Failed(format!("dependency '{}' {}", dep.name, status))
+                }
+            },
+        };
+
+        if !satisfied {
+            return Readiness::Waiting;
+        }
+    }
+
+    Readiness::Ready
+}
+
+/// records finished runs of scheduled jobs and starts the jobs that have come due
+fn run_schedules(ctx: &mut CommandContext) {
+    let now = Local::now();
+    let mut to_start = Vec::new();
+
+    for job in ctx.schedhandler.all_jobs_mut() {
+        let mut still_running = Vec::new();
+        for &pid in &job.running {
+            match ctx.prochandler.get_process_status(pid) {
+                Some(status) => match status.status {
+                    ProcessWaitStatus::Running(_) => still_running.push(pid),
+                    status => job.last_exit = Some(status),
+                },
+                None => job.last_exit = Some(ProcessWaitStatus::Unknown),
+            }
+        }
+        job.running = still_running;
+
+        let due = job.next_run.is_some_and(|next| next <= now);
+        if due {
+            job.next_run = match job.trigger.next_after(now) {
+                Ok(next) => next,
+                Err(e) => {
+                    error!("failed to compute next run of job {}: {}", job.id, e);
+                    None
+                }
+            };
+
+            if job.running.is_empty() || job.overlap == OverlapPolicy::Allow {
+                to_start.push(job.id);
+            } else if job.overlap == OverlapPolicy::Queue {
+                job.queued = true;
+            } else {
+                info!(
+                    "skipping run of job {}, previous run is still going",
+                    job.id
+                );
+            }
+        } else if job.queued && job.running.is_empty() {
+            job.queued = false;
+            to_start.push(job.id);
+        }
+    }
+
+    for id in to_start {
+        let job = ctx
+            .schedhandler
+            .all_jobs()
+            .iter()
+            .find(|job| job.id == id)
+            .unwrap();
+        // scheduled runs are launched exactly like `sibyl once` would launch them
+        let once = CmdOnce {
+            program: job.program.clone(),
+            args: job.args.clone(),
+            options: LaunchOptions::default(),
+        };
+
+        match ctx.launch(&once, &once.program, &once.args, None, &once.options) {
+            Ok(pid) => {
+                info!("started run of job {} with sibyl pid {}", id, pid);
+                let job = ctx
+                    .schedhandler
+                    .all_jobs_mut()
+                    .iter_mut()
+                    .find(|job| job.id == id)
+                    .unwrap();
+                job.last_run = Some(now);
+                job.running.push(pid);
+            }
+            Err(e) => error!("failed to start run of job {}: {}", id, e),
+        }
+    }
+}
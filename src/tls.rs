@@ -0,0 +1,432 @@
+use crate::config::TlsConfig;
+use anyhow::{bail, Context, Result};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{
+    ClientConfig, ClientConnection, Connection, RootCertStore, ServerConfig, ServerConnection,
+};
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::{self, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// a TLS connection over TCP
+///
+/// unlike rustls' own streams this one can be cloned, so that one thread can
+/// read while another writes, the same way a plain TcpStream is used
+pub struct TlsStream {
+    conn: Arc<Mutex<Connection>>,
+    tcp: TcpStream,
+}
+
+impl TlsStream {
+    /// performs the client side of the handshake
+    /// # Arguments
+    /// * `tcp` - the connection to the daemon
+    /// * `config` - built with `client_config`
+    /// * `addr` - the address that was connected to, whose host the server certificate must be valid for
+    pub fn connect(tcp: TcpStream, config: Arc<ClientConfig>, addr: &str) -> Result<TlsStream> {
+        // the port is all that comes after the last colon, and ipv6 hosts are in brackets
+        let host = addr.rsplit_once(':').map_or(addr, |(host, _)| host);
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        let name = ServerName::try_from(host.to_string())
+            .with_context(|| format!("invalid server name '{}'", host))?;
+
+        let conn = ClientConnection::new(config, name).context("failed to set up TLS")?;
+        TlsStream::handshake(Connection::Client(conn), tcp)
+    }
+
+    /// performs the server side of the handshake, checking the client's certificate if the config asks for one
+    pub fn accept(tcp: TcpStream, config: Arc<ServerConfig>) -> Result<TlsStream> {
+        let conn = ServerConnection::new(config).context("failed to set up TLS")?;
+        TlsStream::handshake(Connection::Server(conn), tcp)
+    }
+
+    /// completes the handshake up front, so that reads and writes never have to wait on each other
+    fn handshake(mut conn: Connection, mut tcp: TcpStream) -> Result<TlsStream> {
+        while conn.is_handshaking() {
+            conn.complete_io(&mut tcp).context("TLS handshake failed")?;
+        }
+        Ok(TlsStream {
+            conn: Arc::new(Mutex::new(conn)),
+            tcp,
+        })
+    }
+
+    /// creates a second handle to the same connection
+    pub fn try_clone(&self) -> io::Result<TlsStream> {
+        Ok(TlsStream {
+            conn: Arc::clone(&self.conn),
+            tcp: self.tcp.try_clone()?,
+        })
+    }
+
+    /// the underlying TCP connection
+    pub fn get_ref(&self) -> &TcpStream {
+        &self.tcp
+    }
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut received = [0; 16 * 1024];
+        loop {
+            {
+                let mut conn = self.conn.lock().unwrap();
+                match conn.reader().read(buf) {
+                    Ok(n) => return Ok(n),
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                    Err(e) => return Err(e),
+                }
+            }
+
+            // the lock isn't held while waiting for data, so that writers can carry on
+            let n = self.tcp.read(&mut received)?;
+            if n == 0 {
+                return Ok(0);
+            }
+
+            let mut conn = self.conn.lock().unwrap();
+            let mut received = &received[..n];
+            while !received.is_empty() {
+                conn.read_tls(&mut received)?;
+                conn.process_new_packets().map_err(io::Error::other)?;
+            }
+            while conn.wants_write() {
+                conn.write_tls(&mut &self.tcp)?;
+            }
+        }
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut conn = self.conn.lock().unwrap();
+        conn.writer().write_all(buf)?;
+        while conn.wants_write() {
+            conn.write_tls(&mut &self.tcp)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// builds the daemon's TLS settings from its config
+pub fn server_config(tls: &TlsConfig) -> Result<Arc<ServerConfig>> {
+    let builder = ServerConfig::builder();
+    let builder = match &tls.client_ca {
+        Some(ca) => {
+            let verifier = WebPkiClientVerifier::builder(Arc::new(load_roots(ca)?))
+                .build()
+                .context("failed to set up client certificate verification")?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let config = builder
+        .with_single_cert(load_certs(&tls.cert)?, load_key(&tls.key)?)
+        .context("invalid TLS certificate or key")?;
+
+    Ok(Arc::new(config))
+}
+
+/// what the client needs to connect to a daemon over TLS
+#[derive(Debug, Clone)]
+pub struct TlsOptions {
+    /// the CA that signed the daemon's certificate, or the certificate itself if it is self-signed
+    pub ca: PathBuf,
+    /// the client's certificate, for daemons that require one
+    pub cert: Option<PathBuf>,
+    /// the private key of `cert`
+    pub key: Option<PathBuf>,
+}
+
+/// builds the client's TLS settings
+pub fn client_config(options: &TlsOptions) -> Result<Arc<ClientConfig>> {
+    let builder = ClientConfig::builder().with_root_certificates(load_roots(&options.ca)?);
+    let config = match (&options.cert, &options.key) {
+        (Some(cert), Some(key)) => builder
+            .with_client_auth_cert(load_certs(cert)?, load_key(key)?)
+            .context("invalid TLS client certificate or key")?,
+        (None, None) => builder.with_no_client_auth(),
+        _ => bail!("a TLS client certificate and its key must be given together"),
+    };
+
+    Ok(Arc::new(config))
+}
+
+/// reads every certificate in a PEM file
+fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let file = File::open(path)
+        .with_context(|| format!("failed to open certificate {}", path.display()))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .collect::<io::Result<Vec<_>>>()
+        .with_context(|| format!("failed to read certificate {}", path.display()))?;
+    if certs.is_empty() {
+        bail!("no certificates found in {}", path.display());
+    }
+    Ok(certs)
+}
+
+/// reads the first private key in a PEM file
+fn load_key(path: &Path) -> Result<PrivateKeyDer<'static>> {
+    let file =
+        File::open(path).with_context(|| format!("failed to open key {}", path.display()))?;
+    rustls_pemfile::private_key(&mut BufReader::new(file))
+        .with_context(|| format!("failed to read key {}", path.display()))?
+        .with_context(|| format!("no private key found in {}", path.display()))
+}
+
+/// reads the CA certificates that peers' certificates are checked against
+fn load_roots(path: &Path) -> Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(path)? {
+        roots
+            .add(cert)
+            .with_context(|| format!("invalid CA certificate in {}", path.display()))?;
+    }
+    Ok(roots)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::CmdPing;
+    use crate::util::TempDir;
+    use crate::{Client, Request, Response};
+    use chrono::Utc;
+    use rcgen::{
+        BasicConstraints, Certificate, CertificateParams, ExtendedKeyUsagePurpose, IsCa, KeyPair,
+    };
+    use std::net::TcpListener;
+    use std::thread;
+
+    /// a certificate authority, with its certificates written to a temporary directory
+    struct Ca {
+        dir: TempDir,
+        cert: Certificate,
+        key: KeyPair,
+    }
+
+    impl Ca {
+        fn new(name: &str) -> Ca {
+            let mut params = CertificateParams::new(Vec::new()).unwrap();
+            params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+            let key = KeyPair::generate().unwrap();
+            let cert = params.self_signed(&key).unwrap();
+            let dir = TempDir::new(name);
+            std::fs::write(dir.path().join("ca.pem"), cert.pem()).unwrap();
+            Ca { dir, cert, key }
+        }
+
+        fn path(&self) -> PathBuf {
+            self.dir.path().join("ca.pem")
+        }
+
+        /// signs a certificate for `names`, returning the paths of it and its key
+        fn issue(&self, names: &[&str], usage: ExtendedKeyUsagePurpose) -> (PathBuf, PathBuf) {
+            let names: Vec<String> = names.iter().map(|name| name.to_string()).collect();
+            let file = names[0].replace('.', "_");
+            let mut params = CertificateParams::new(names).unwrap();
+            params.extended_key_usages = vec![usage];
+            let key = KeyPair::generate().unwrap();
+            let cert = params.signed_by(&key, &self.cert, &self.key).unwrap();
+
+            let cert_path = self.dir.path().join(format!("{}.pem", file));
+            let key_path = self.dir.path().join(format!("{}.key", file));
+            std::fs::write(&cert_path, cert.pem()).unwrap();
+            std::fs::write(&key_path, key.serialize_pem()).unwrap();
+            (cert_path, key_path)
+        }
+
+        /// the settings of a daemon at localhost whose certificate this CA signed
+        fn server(&self, client_ca: Option<PathBuf>) -> Arc<ServerConfig> {
+            let (cert, key) = self.issue(
+                &["localhost", "127.0.0.1"],
+                ExtendedKeyUsagePurpose::ServerAuth,
+            );
+            server_config(&TlsConfig {
+                listen: Vec::new(),
+                cert,
+                key,
+                client_ca,
+            })
+            .unwrap()
+        }
+
+        /// the settings of a client trusting this CA, presenting `cert` if given
+        fn client(&self, cert: Option<(PathBuf, PathBuf)>) -> Arc<ClientConfig> {
+            let (cert, key) = cert.unzip();
+            client_config(&TlsOptions {
+                ca: self.path(),
+                cert,
+                key,
+            })
+            .unwrap()
+        }
+    }
+
+    /// connects a client to a server over TLS on a local port
+    ///
+    /// returns the result of both sides of the handshake
+    /// # Arguments
+    /// * `host` - the name the client connects to, which the server certificate must be valid for
+    fn connect(
+        server: Arc<ServerConfig>,
+        client: Arc<ClientConfig>,
+        host: &str,
+    ) -> (Result<TlsStream>, Result<TlsStream>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let accepting = thread::spawn(move || {
+            let (tcp, _) = listener.accept().unwrap();
+            TlsStream::accept(tcp, server)
+        });
+
+        let tcp = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        // a client that fails its handshake drops the connection, which ends the server's
+        let client = TlsStream::connect(tcp, client, &format!("{}:{}", host, port));
+        (accepting.join().unwrap(), client)
+    }
+
+    #[test]
+    fn request_and_response_round_trip() {
+        let ca = Ca::new("tls-round-trip");
+        let (server, client) = connect(ca.server(None), ca.client(None), "localhost");
+        let mut server = Client::from_tls_stream(server.unwrap());
+        let mut client = Client::from_tls_stream(client.unwrap());
+
+        client
+            .send_request(&Request {
+                command: Box::new(CmdPing),
+                time: Utc::now(),
+            })
+            .unwrap();
+        server.receive_request().unwrap();
+        server
+            .send_response(&Response {
+                msg: "pong!".to_string(),
+                spid: Some(7),
+                error: false,
+            })
+            .unwrap();
+        let res = client.receive_response().unwrap();
+        assert_eq!(res.msg, "pong!");
+        assert_eq!(res.spid, Some(7));
+    }
+
+    #[test]
+    fn ip_addresses_are_verified_too() {
+        let ca = Ca::new("tls-ip");
+        let (server, client) = connect(ca.server(None), ca.client(None), "127.0.0.1");
+        assert!(server.is_ok());
+        assert!(client.is_ok());
+    }
+
+    #[test]
+    fn hostname_mismatch_is_refused() {
+        let ca = Ca::new("tls-hostname");
+        let (_, client) = connect(ca.server(None), ca.client(None), "sibyl.example.com");
+        let err = client
+            .err()
+            .expect("connected to a server with the wrong name");
+        assert!(
+            format!("{:#}", err).contains("not valid for name"),
+            "{:#}",
+            err
+        );
+    }
+
+    #[test]
+    fn untrusted_server_is_refused() {
+        let ca = Ca::new("tls-untrusted-server");
+        let other = Ca::new("tls-untrusted-server-other");
+        let (_, client) = connect(ca.server(None), other.client(None), "localhost");
+        assert!(client.is_err());
+    }
+
+    #[test]
+    fn client_certificate_signed_by_client_ca_is_accepted() {
+        let ca = Ca::new("tls-client-cert");
+        let cert = ca.issue(&["laptop"], ExtendedKeyUsagePurpose::ClientAuth);
+        let (server, client) = connect(
+            ca.server(Some(ca.path())),
+            ca.client(Some(cert)),
+            "localhost",
+        );
+        assert!(server.is_ok());
+        assert!(client.is_ok());
+    }
+
+    #[test]
+    fn client_certificate_from_another_ca_is_refused() {
+        let ca = Ca::new("tls-client-cert-other");
+        let other = Ca::new("tls-client-cert-other-ca");
+        let cert = other.issue(&["laptop"], ExtendedKeyUsagePurpose::ClientAuth);
+        let (server, client) = connect(
+            ca.server(Some(ca.path())),
+            ca.client(Some(cert)),
+            "localhost",
+        );
+        assert!(server.is_err());
+
+        // with TLS 1.3 the client finishes its handshake first, and only learns of the refusal
+        // once it reads. either way, nothing gets through
+        if let Ok(mut client) = client {
+            assert!(client.read(&mut [0; 1]).is_err());
+        }
+    }
+
+    #[test]
+    fn missing_client_certificate_is_refused() {
+        let ca = Ca::new("tls-no-client-cert");
+        let (server, _) = connect(ca.server(Some(ca.path())), ca.client(None), "localhost");
+        assert!(server.is_err());
+    }
+
+    #[test]
+    fn clones_read_and_write_concurrently() {
+        const MESSAGES: usize = 200;
+
+        let ca = Ca::new("tls-concurrent");
+        let (server, client) = connect(ca.server(None), ca.client(None), "localhost");
+        let mut server = server.unwrap();
+        let client = client.unwrap();
+
+        // the server echoes every line back
+        let echo = thread::spawn(move || {
+            let mut reader = BufReader::new(server.try_clone().unwrap());
+            let mut line = String::new();
+            for _ in 0..MESSAGES {
+                line.clear();
+                io::BufRead::read_line(&mut reader, &mut line).unwrap();
+                server.write_all(line.as_bytes()).unwrap();
+            }
+        });
+
+        // one clone writes while another is blocked reading the echoes
+        let mut writer = client.try_clone().unwrap();
+        let writing = thread::spawn(move || {
+            for i in 0..MESSAGES {
+                writer
+                    .write_all(format!("message {}\n", i).as_bytes())
+                    .unwrap();
+            }
+        });
+        let mut reader = BufReader::new(client);
+        let mut line = String::new();
+        for i in 0..MESSAGES {
+            line.clear();
+            io::BufRead::read_line(&mut reader, &mut line).unwrap();
+            assert_eq!(line, format!("message {}\n", i));
+        }
+
+        writing.join().unwrap();
+        echo.join().unwrap();
+    }
+}
@@ -0,0 +1,883 @@
+use crate::commands::CommandContext;
+use crate::metrics::os_usage;
+use crate::processing::{CrashState, ProcessWaitStatus, SibylPID};
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+
+/// one row of `sibyl top`, as the daemon sends it
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ProcessSummary {
+    pub spid: SibylPID,
+    /// the definition name, or else the program
+    pub name: String,
+    pub cmdline: String,
+    /// `running`, `exited`, `timed out` or `unknown`, or `backoff` and
+    /// `quarantined` for the latest process of a crash-looping definition
+    pub state: String,
+    pub exit_code: Option<i32>,
+    pub health: Option<String>,
+    pub started: DateTime<Local>,
+    pub restarts: u32,
+    /// user and system CPU time, only known for running processes
+    pub cpu_seconds: Option<f64>,
+    pub rss_bytes: Option<u64>,
+}
+
+impl ProcessSummary {
+    /// whether the process ended badly or isn't being restarted
+    fn failed(&self) -> bool {
+        match self.state.as_str() {
+            "exited" => self.exit_code != Some(0),
+            "timed out" | "quarantined" => true,
+            _ => false,
+        }
+    }
+}
+
+/// describes every process the daemon manages
+pub fn snapshot(ctx: &mut CommandContext) -> Vec<ProcessSummary> {
+    let spids: Vec<SibylPID> = ctx
+        .prochandler
+        .all_processes()
+        .iter()
+        .map(|proc| proc.pid)
+        .collect();
+
+    let mut processes = Vec::new();
+    for spid in spids {
+        let status = match ctx.prochandler.get_process_status(spid) {
+            Some(status) => status,
+            None => continue,
+        };
+        let proc = ctx.prochandler.get_process_by_pid(spid).unwrap();
+        let (state, exit_code) = match status.status {
+            ProcessWaitStatus::Running(_) => ("running", None),
+            ProcessWaitStatus::Exited(code) => ("exited", code),
+            ProcessWaitStatus::TimedOut(code) => ("timed out", code),
+            ProcessWaitStatus::Unknown => ("unknown", None),
+        };
+        let state = match status.crash_state {
+            Some(CrashState::Backoff { .. }) => "backoff",
+            Some(CrashState::Quarantined { .. }) => "quarantined",
+            Some(CrashState::Stable) | None => state,
+        };
+        let (cpu_seconds, rss_bytes) = match status.status {
+            ProcessWaitStatus::Running(_) => os_usage(status.os_pid),
+            _ => (None, None),
+        };
+
+        processes.push(ProcessSummary {
+            spid,
+            name: proc.display_name().unwrap_or_default(),
+            cmdline: status.cmdline.to_string_lossy().into_owned(),
+            state: state.to_string(),
+            exit_code,
+            health: status.health.map(|health| health.to_string()),
+            started: status.started,
+            restarts: status.restarts,
+            cpu_seconds,
+            rss_bytes,
+        });
+    }
+    processes
+}
+
+#[cfg(unix)]
+pub use self::tui::run;
+
+#[cfg(not(unix))]
+pub fn run(
+    _client: crate::Client,
+    _connect: impl Fn() -> anyhow::Result<crate::Client> + Send + Sync + 'static,
+) -> anyhow::Result<()> {
+    anyhow::bail!("sibyl top is only supported on unix")
+}
+
+/// the full-screen dashboard, drawn with plain ANSI escape sequences
+#[cfg(unix)]
+mod tui {
+    use super::ProcessSummary;
+    use crate::commands::{Action, CmdLog, CmdRestart, CmdSignal, CmdStop};
+    use crate::processing::SibylPID;
+    use crate::util::parse_signal;
+    use crate::{pty, Client, Request, StreamFrame};
+    use anyhow::{bail, Context, Result};
+    use chrono::{Local, Utc};
+    use std::collections::{HashMap, VecDeque};
+    use std::io::{self, Read, Write};
+    use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    /// how many lines of a log the log pane keeps
+    const LOG_LINES: usize = 1000;
+
+    /// how often the screen is redrawn when nothing happens, to notice the terminal being resized
+    const REDRAW_INTERVAL: Duration = Duration::from_millis(500);
+
+    const HELP: &str =
+        "up/down select  s stop  r restart  k signal  l log  o sort  i invert  / filter  q quit";
+
+    type Connect = Arc<dyn Fn() -> Result<Client> + Send + Sync>;
+
+    /// what the dashboard reacts to
+    enum Input {
+        Snapshot(Vec<ProcessSummary>),
+        Keys(Vec<u8>),
+        /// output for the log pane, tagged with the generation of the pane it is for
+        Log(u64, Vec<u8>),
+        /// the outcome of an action, for the status line
+        Message(String),
+        /// the daemon stopped sending snapshots
+        Disconnected,
+    }
+
+    /// a key press, decoded from the terminal's input
+    #[derive(Debug, PartialEq)]
+    enum Key {
+        Char(char),
+        Up,
+        Down,
+        PageUp,
+        PageDown,
+        Home,
+        End,
+        Enter,
+        Backspace,
+        Escape,
+        CtrlC,
+        Other,
+    }
+
+    /// the columns the process table can be sorted by
+    #[derive(Clone, Copy, PartialEq)]
+    enum SortKey {
+        Spid,
+        Name,
+        State,
+        Uptime,
+        Cpu,
+        Memory,
+        Restarts,
+    }
+
+    impl SortKey {
+        fn next(self) -> SortKey {
+            match self {
+                SortKey::Spid => SortKey::Name,
+                SortKey::Name => SortKey::State,
+                SortKey::State => SortKey::Uptime,
+                SortKey::Uptime => SortKey::Cpu,
+                SortKey::Cpu => SortKey::Memory,
+                SortKey::Memory => SortKey::Restarts,
+                SortKey::Restarts => SortKey::Spid,
+            }
+        }
+
+        fn label(self) -> &'static str {
+            match self {
+                SortKey::Spid => "spid",
+                SortKey::Name => "name",
+                SortKey::State => "state",
+                SortKey::Uptime => "uptime",
+                SortKey::Cpu => "cpu",
+                SortKey::Memory => "memory",
+                SortKey::Restarts => "restarts",
+            }
+        }
+    }
+
+    /// what typed keys go to
+    enum Mode {
+        Normal,
+        /// editing the filter, which applies as it is typed
+        Filter,
+        /// typing the signal to send to the selected process
+        Signal(String),
+    }
+
+    /// the live tail of the selected process' log
+    struct LogPane {
+        spid: SibylPID,
+        name: String,
+        generation: u64,
+        lines: VecDeque<String>,
+        /// the last line, until its newline arrives
+        partial: String,
+        /// a handle to the streaming connection, for closing it along with the pane
+        connection: Client,
+    }
+
+    impl LogPane {
+        fn push(&mut self, bytes: &[u8]) {
+            for c in String::from_utf8_lossy(bytes).chars() {
+                match c {
+                    '\n' => {
+                        self.lines.push_back(std::mem::take(&mut self.partial));
+                        if self.lines.len() > LOG_LINES {
+                            self.lines.pop_front();
+                        }
+                    }
+                    '\t' => self.partial.push_str("    "),
+                    // escape sequences and carriage returns would garble the screen
+                    c if c.is_control() => {}
+                    c => self.partial.push(c),
+                }
+            }
+        }
+    }
+
+    struct Top {
+        processes: Vec<ProcessSummary>,
+        /// CPU usage in percent, from the difference between the last two snapshots
+        cpu: HashMap<SibylPID, f64>,
+        last_cpu: HashMap<SibylPID, (f64, Instant)>,
+        selected: Option<SibylPID>,
+        sort: SortKey,
+        reverse: bool,
+        filter: String,
+        mode: Mode,
+        message: String,
+        log: Option<LogPane>,
+        generation: u64,
+        /// the number of table rows on screen, for paging
+        page: usize,
+        connect: Connect,
+        sender: Sender<Input>,
+    }
+
+    /// shows a live, full-screen view of the daemon's processes until the user quits
+    /// # Arguments
+    /// * `client` - the connection a `top` request was sent over
+    /// * `connect` - opens and authenticates a new connection, for actions and log tails
+    pub fn run(
+        client: Client,
+        connect: impl Fn() -> Result<Client> + Send + Sync + 'static,
+    ) -> Result<()> {
+        let original = match pty::enter_raw_mode() {
+            Some(original) => original,
+            None => bail!("sibyl top needs a terminal"),
+        };
+        // the alternate screen keeps the dashboard out of the terminal's scrollback
+        print!("\x1b[?1049h\x1b[?25l");
+
+        let result = dashboard(client, Arc::new(connect));
+
+        print!("\x1b[?25h\x1b[?1049l");
+        let _ = io::stdout().flush();
+        pty::restore_mode(&original);
+        result
+    }
+
+    fn dashboard(mut client: Client, connect: Connect) -> Result<()> {
+        let (sender, receiver) = mpsc::channel();
+
+        let snapshots = sender.clone();
+        thread::spawn(move || {
+            loop {
+                let line = match client.receive_frame() {
+                    Ok(StreamFrame::Output(line)) => line,
+                    Ok(StreamFrame::Heartbeat) => continue,
+                    _ => break,
+                };
+                if let Ok(processes) = serde_json::from_slice(&line) {
+                    if snapshots.send(Input::Snapshot(processes)).is_err() {
+                        return;
+                    }
+                }
+            }
+            let _ = snapshots.send(Input::Disconnected);
+        });
+
+        let keys = sender.clone();
+        thread::spawn(move || {
+            let mut stdin = io::stdin();
+            let mut buffer = [0; 64];
+            while let Ok(read) = stdin.read(&mut buffer) {
+                if read == 0 || keys.send(Input::Keys(buffer[..read].to_vec())).is_err() {
+                    return;
+                }
+            }
+        });
+
+        let mut top = Top {
+            processes: Vec::new(),
+            cpu: HashMap::new(),
+            last_cpu: HashMap::new(),
+            selected: None,
+            sort: SortKey::Spid,
+            reverse: false,
+            filter: String::new(),
+            mode: Mode::Normal,
+            message: String::new(),
+            log: None,
+            generation: 0,
+            page: 1,
+            connect,
+            sender,
+        };
+
+        loop {
+            top.draw()?;
+            let input = match receiver.recv_timeout(REDRAW_INTERVAL) {
+                Ok(input) => input,
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => return Ok(()),
+            };
+            match input {
+                Input::Snapshot(processes) => top.update(processes),
+                Input::Keys(bytes) => {
+                    for key in decode_keys(&bytes) {
+                        if !top.handle_key(key) {
+                            top.close_log();
+                            return Ok(());
+                        }
+                    }
+                }
+                Input::Log(generation, bytes) => {
+                    if let Some(pane) = top.log.as_mut().filter(|p| p.generation == generation) {
+                        pane.push(&bytes);
+                    }
+                }
+                Input::Message(msg) => top.message = msg,
+                Input::Disconnected => bail!("lost connection to sibyld"),
+            }
+        }
+    }
+
+    impl Top {
+        /// takes in a new snapshot, working out CPU usage from the previous one
+        fn update(&mut self, processes: Vec<ProcessSummary>) {
+            let now = Instant::now();
+            self.cpu.clear();
+            let mut last_cpu = HashMap::new();
+            for proc in &processes {
+                if let Some(cpu) = proc.cpu_seconds {
+                    if let Some((before, then)) = self.last_cpu.get(&proc.spid) {
+                        let elapsed = now.duration_since(*then).as_secs_f64();
+                        if elapsed > 0.0 {
+                            self.cpu
+                                .insert(proc.spid, (cpu - before).max(0.0) / elapsed * 100.0);
+                        }
+                    }
+                    last_cpu.insert(proc.spid, (cpu, now));
+                }
+            }
+            self.last_cpu = last_cpu;
+            self.processes = processes;
+        }
+
+        /// the processes that pass the filter, in display order
+        fn visible(&self) -> Vec<&ProcessSummary> {
+            let filter = self.filter.to_lowercase();
+            let mut visible: Vec<&ProcessSummary> = self
+                .processes
+                .iter()
+                .filter(|proc| {
+                    filter.is_empty()
+                        || proc.name.to_lowercase().contains(&filter)
+                        || proc.cmdline.to_lowercase().contains(&filter)
+                })
+                .collect();
+
+            let cpu = |proc: &ProcessSummary| self.cpu.get(&proc.spid).copied().unwrap_or(-1.0);
+            visible.sort_by(|a, b| {
+                let order = match self.sort {
+                    SortKey::Spid => a.spid.cmp(&b.spid),
+                    SortKey::Name => a.name.cmp(&b.name),
+                    SortKey::State => a.state.cmp(&b.state),
+                    // the longest running first, like the other numeric columns
+                    SortKey::Uptime => b.started.cmp(&a.started).reverse(),
+                    SortKey::Cpu => cpu(b).total_cmp(&cpu(a)),
+                    SortKey::Memory => b.rss_bytes.cmp(&a.rss_bytes),
+                    SortKey::Restarts => b.restarts.cmp(&a.restarts),
+                };
+                let order = order.then(a.spid.cmp(&b.spid));
+                if self.reverse {
+                    order.reverse()
+                } else {
+                    order
+                }
+            });
+            visible
+        }
+
+        /// the selected process, falling back to the first visible one
+        fn selection(&self) -> Option<&ProcessSummary> {
+            let visible = self.visible();
+            visible
+                .iter()
+                .find(|proc| Some(proc.spid) == self.selected)
+                .or(visible.first())
+                .copied()
+        }
+
+        /// moves the selection by `offset` rows, stopping at either end
+        fn select(&mut self, offset: isize) {
+            let visible: Vec<SibylPID> = self.visible().iter().map(|proc| proc.spid).collect();
+            if visible.is_empty() {
+                return;
+            }
+            let current = self
+                .selection()
+                .and_then(|proc| visible.iter().position(|spid| *spid == proc.spid))
+                .unwrap_or(0);
+            let index = (current as isize + offset).clamp(0, visible.len() as isize - 1);
+            self.selected = Some(visible[index as usize]);
+        }
+
+        /// reacts to a key, returning false once the user wants to quit
+        fn handle_key(&mut self, key: Key) -> bool {
+            match &mut self.mode {
+                Mode::Filter => {
+                    match key {
+                        Key::Char(c) => self.filter.push(c),
+                        Key::Backspace => {
+                            self.filter.pop();
+                        }
+                        Key::Escape => {
+                            self.filter.clear();
+                            self.mode = Mode::Normal;
+                        }
+                        Key::Enter => self.mode = Mode::Normal,
+                        Key::CtrlC => return false,
+                        _ => {}
+                    }
+                    return true;
+                }
+                Mode::Signal(signal) => {
+                    match key {
+                        Key::Char(c) => signal.push(c),
+                        Key::Backspace => {
+                            signal.pop();
+                        }
+                        Key::Escape => self.mode = Mode::Normal,
+                        Key::Enter => {
+                            let signal = std::mem::take(signal);
+                            self.mode = Mode::Normal;
+                            self.send_signal(&signal);
+                        }
+                        Key::CtrlC => return false,
+                        _ => {}
+                    }
+                    return true;
+                }
+                Mode::Normal => {}
+            }
+
+            match key {
+                Key::Char('q') | Key::CtrlC => return false,
+                Key::Up => self.select(-1),
+                Key::Down => self.select(1),
+                Key::PageUp => self.select(-(self.page as isize)),
+                Key::PageDown => self.select(self.page as isize),
+                Key::Home => self.select(isize::MIN / 2),
+                Key::End => self.select(isize::MAX / 2),
+                Key::Char('s') => {
+                    if let Some(pid) = self.selection().map(|proc| proc.spid) {
+                        self.message = format!("stopping SPID {}...", pid);
+                        self.act(Box::new(CmdStop { pid }));
+                    }
+                }
+                Key::Char('r') => {
+                    if let Some(pid) = self.selection().map(|proc| proc.spid) {
+                        self.message = format!("restarting SPID {}...", pid);
+                        self.act(Box::new(CmdRestart { pid }));
+                    }
+                }
+                Key::Char('k') if self.selection().is_some() => {
+                    self.mode = Mode::Signal(String::new())
+                }
+                Key::Char('l') | Key::Enter => self.toggle_log(),
+                Key::Char('o') => self.sort = self.sort.next(),
+                Key::Char('i') => self.reverse = !self.reverse,
+                Key::Char('/') => self.mode = Mode::Filter,
+                Key::Escape => {
+                    self.close_log();
+                    self.message.clear();
+                }
+                _ => {}
+            }
+            true
+        }
+
+        fn send_signal(&mut self, signal: &str) {
+            let pid = match self.selection() {
+                Some(proc) => proc.spid,
+                None => return,
+            };
+            match parse_signal(signal) {
+                Ok(signal) => {
+                    self.message = format!("signalling SPID {}...", pid);
+                    self.act(Box::new(CmdSignal { pid, signal }));
+                }
+                Err(e) => self.message = format!("{:#}", e),
+            }
+        }
+
+        /// sends an action over a connection of its own, reporting the outcome in the status line
+        ///
+        /// only the wait for the response happens in the background, actions aren't `Send`
+        fn act(&mut self, command: Box<dyn Action>) {
+            let req = Request {
+                command,
+                time: Utc::now(),
+            };
+            let mut client = match (self.connect)().and_then(|mut client| {
+                client.send_request(&req)?;
+                Ok(client)
+            }) {
+                Ok(client) => client,
+                Err(e) => {
+                    self.message = format!("{:#}", e);
+                    return;
+                }
+            };
+            let sender = self.sender.clone();
+            thread::spawn(move || {
+                let mut msg = match client.receive_response() {
+                    Ok(res) => res.msg,
+                    Err(e) => format!("{:#}", e),
+                };
+                // stopping and restarting report once the process has exited,
+                // and the connection closes after any other action
+                while let Ok(frame) = client.receive_frame() {
+                    match frame {
+                        StreamFrame::Output(bytes) => {
+                            msg.push_str(&String::from_utf8_lossy(&bytes))
+                        }
+                        StreamFrame::End(_) => break,
+                        _ => {}
+                    }
+                }
+                let _ = sender.send(Input::Message(msg.trim().to_string()));
+            });
+        }
+
+        /// opens the log pane on the selected process, or closes it
+        fn toggle_log(&mut self) {
+            let selected = self.selection().map(|proc| (proc.spid, proc.name.clone()));
+            let showing = self.log.as_ref().map(|pane| pane.spid);
+            self.close_log();
+            let (spid, name) = match selected {
+                Some(selected) if Some(selected.0) != showing => selected,
+                _ => return,
+            };
+
+            let req = Request {
+                command: Box::new(CmdLog {
+                    pid: spid,
+                    follow: true,
+                }),
+                time: Utc::now(),
+            };
+            let mut client = match (self.connect)().and_then(|mut client| {
+                client.send_request(&req)?;
+                let res = client.receive_response()?;
+                if res.spid.is_none() {
+                    bail!("{}", res.msg);
+                }
+                Ok(client)
+            }) {
+                Ok(client) => client,
+                Err(e) => {
+                    self.message = format!("{:#}", e);
+                    return;
+                }
+            };
+            let connection = match client.try_clone() {
+                Ok(connection) => connection,
+                Err(e) => {
+                    self.message = format!("{:#}", e);
+                    return;
+                }
+            };
+
+            self.generation += 1;
+            let generation = self.generation;
+            let sender = self.sender.clone();
+            thread::spawn(move || loop {
+                let bytes = match client.receive_frame() {
+                    Ok(StreamFrame::Output(bytes)) => bytes,
+                    Ok(StreamFrame::End(_)) => b"[process exited]\n".to_vec(),
+                    _ => return,
+                };
+                if sender.send(Input::Log(generation, bytes)).is_err() {
+                    return;
+                }
+            });
+            self.log = Some(LogPane {
+                spid,
+                name,
+                generation,
+                lines: VecDeque::new(),
+                partial: String::new(),
+                connection,
+            });
+        }
+
+        fn close_log(&mut self) {
+            if let Some(pane) = self.log.take() {
+                // wakes up the thread reading the stream, which then exits
+                let _ = pane.connection.shutdown();
+            }
+        }
+
+        fn draw(&mut self) -> Result<()> {
+            let (rows, cols) = pty::window_size(1).unwrap_or((24, 80));
+            let (rows, cols) = (rows as usize, cols as usize);
+            let mut lines: Vec<String> = Vec::with_capacity(rows);
+
+            let running = self
+                .processes
+                .iter()
+                .filter(|proc| proc.state == "running")
+                .count();
+            let mut header = format!(
+                "sibyl top - {} processes, {} running   sort: {}{}",
+                self.processes.len(),
+                running,
+                self.sort.label(),
+                if self.reverse { " (inverted)" } else { "" }
+            );
+            if !self.filter.is_empty() {
+                header.push_str(&format!("   filter: {}", self.filter));
+            }
+            lines.push(fit(&header, cols));
+            lines.push(format!(
+                "\x1b[7m{}\x1b[0m",
+                pad(
+                    &format!(
+                        "{:>5} {:<16} {:<11} {:>9} {:>6} {:>8} {:>8}  {}",
+                        "SPID", "NAME", "STATE", "UPTIME", "CPU%", "MEM", "RESTARTS", "COMMAND"
+                    ),
+                    cols
+                )
+            ));
+
+            // the table, status line and header take the screen the log pane leaves
+            let available = rows.saturating_sub(3);
+            let table = match self.log {
+                Some(_) => available / 2,
+                None => available,
+            };
+            self.page = table.max(1);
+
+            let selected = self.selection().map(|proc| proc.spid);
+            let visible = self.visible();
+            let index = visible
+                .iter()
+                .position(|proc| Some(proc.spid) == selected)
+                .unwrap_or(0);
+            let skip = (index + 1).saturating_sub(table);
+            for proc in visible.iter().skip(skip).take(table) {
+                let row = fit(&self.row(proc), cols);
+                lines.push(if Some(proc.spid) == selected {
+                    format!("\x1b[7m{}\x1b[0m", pad(&row, cols))
+                } else if proc.failed() {
+                    format!("\x1b[31m{}\x1b[0m", row)
+                } else if proc.state == "backoff" {
+                    format!("\x1b[33m{}\x1b[0m", row)
+                } else {
+                    row
+                });
+            }
+            while lines.len() < table + 2 {
+                lines.push(String::new());
+            }
+
+            if let Some(pane) = &self.log {
+                let title = format!("-- log of SPID {} ({}) ", pane.spid, printable(&pane.name));
+                lines.push(fit(&format!("{:-<width$}", title, width = cols), cols));
+                let height = available.saturating_sub(table).saturating_sub(1);
+                let partial = Some(&pane.partial).filter(|partial| !partial.is_empty());
+                let tail: Vec<&String> = pane.lines.iter().chain(partial).collect();
+                for line in tail.iter().skip(tail.len().saturating_sub(height)) {
+                    lines.push(fit(line, cols));
+                }
+            }
+            while lines.len() < rows.saturating_sub(1) {
+                lines.push(String::new());
+            }
+
+            let status = match &self.mode {
+                Mode::Filter => format!("filter: {}", self.filter),
+                Mode::Signal(signal) => format!("signal to send (e.g. HUP or 9): {}", signal),
+                Mode::Normal if !self.message.is_empty() => {
+                    self.message.lines().collect::<Vec<_>>().join(" ")
+                }
+                Mode::Normal => String::from(HELP),
+            };
+            lines.push(fit(&status, cols));
+
+            let mut screen = String::from("\x1b[H");
+            screen.push_str(&lines.join("\x1b[K\r\n"));
+            screen.push_str("\x1b[K\x1b[J");
+            let mut stdout = io::stdout();
+            stdout.write_all(screen.as_bytes())?;
+            stdout.flush().context("failed to draw the dashboard")
+        }
+
+        fn row(&self, proc: &ProcessSummary) -> String {
+            let uptime = match proc.state.as_str() {
+                "running" | "backoff" => format_uptime(Local::now() - proc.started),
+                _ => String::from("-"),
+            };
+            let state = match (proc.state.as_str(), proc.exit_code) {
+                ("exited", Some(code)) => format!("exited({})", code),
+                (state, _) => state.to_string(),
+            };
+            let cpu = match self.cpu.get(&proc.spid) {
+                Some(cpu) => format!("{:.1}", cpu),
+                None => String::from("-"),
+            };
+            let memory = match proc.rss_bytes {
+                Some(bytes) => format_bytes(bytes),
+                None => String::from("-"),
+            };
+            format!(
+                "{:>5} {:<16} {:<11} {:>9} {:>6} {:>8} {:>8}  {}",
+                proc.spid,
+                fit(&printable(&proc.name), 16),
+                state,
+                uptime,
+                cpu,
+                memory,
+                proc.restarts,
+                printable(&proc.cmdline)
+            )
+        }
+    }
+
+    /// splits terminal input into key presses
+    fn decode_keys(bytes: &[u8]) -> Vec<Key> {
+        let mut keys = Vec::new();
+        let mut i = 0;
+        while i < bytes.len() {
+            let byte = bytes[i];
+            i += 1;
+            let key = match byte {
+                0x1b if i < bytes.len() && matches!(bytes[i], b'[' | b'O') => {
+                    // a CSI or SS3 sequence runs up to its final byte
+                    let start = i + 1;
+                    let end = bytes[start..]
+                        .iter()
+                        .position(|b| (0x40..=0x7e).contains(b))
+                        .map_or(bytes.len(), |pos| start + pos + 1);
+                    let sequence = &bytes[start..end];
+                    i = end;
+                    match sequence {
+                        b"A" => Key::Up,
+                        b"B" => Key::Down,
+                        b"H" | b"1~" => Key::Home,
+                        b"F" | b"4~" => Key::End,
+                        b"5~" => Key::PageUp,
+                        b"6~" => Key::PageDown,
+                        _ => Key::Other,
+                    }
+                }
+                0x1b => Key::Escape,
+                0x03 => Key::CtrlC,
+                b'\r' | b'\n' => Key::Enter,
+                0x7f | 0x08 => Key::Backspace,
+                byte if byte.is_ascii_graphic() || byte == b' ' => Key::Char(byte as char),
+                _ => Key::Other,
+            };
+            keys.push(key);
+        }
+        keys
+    }
+
+    /// leaves out the control characters of text a process chose, such as its command
+    /// line, which could otherwise move the cursor or change the terminal's settings
+    fn printable(text: &str) -> String {
+        text.chars().filter(|c| !c.is_control()).collect()
+    }
+
+    /// cuts a line down to the width of the terminal
+    fn fit(line: &str, width: usize) -> String {
+        line.chars().take(width).collect()
+    }
+
+    /// pads a line to the width of the terminal, so that highlighting covers the whole row
+    fn pad(line: &str, width: usize) -> String {
+        format!("{:<width$}", fit(line, width), width = width)
+    }
+
+    fn format_uptime(uptime: chrono::Duration) -> String {
+        let secs = uptime.num_seconds().max(0);
+        let (days, hours, minutes) = (secs / 86400, secs / 3600 % 24, secs / 60 % 60);
+        if days > 0 {
+            format!("{}d{:02}h", days, hours)
+        } else if hours > 0 {
+            format!("{}h{:02}m", hours, minutes)
+        } else if minutes > 0 {
+            format!("{}m{:02}s", minutes, secs % 60)
+        } else {
+            format!("{}s", secs)
+        }
+    }
+
+    fn format_bytes(bytes: u64) -> String {
+        const UNITS: [&str; 4] = ["K", "M", "G", "T"];
+        let mut value = bytes as f64 / 1024.0;
+        let mut unit = 0;
+        while value >= 1024.0 && unit < UNITS.len() - 1 {
+            value /= 1024.0;
+            unit += 1;
+        }
+        format!("{:.1}{}", value, UNITS[unit])
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn decode_plain_keys() {
+            assert_eq!(
+                decode_keys(b"q /\r\x7f\x03"),
+                [
+                    Key::Char('q'),
+                    Key::Char(' '),
+                    Key::Char('/'),
+                    Key::Enter,
+                    Key::Backspace,
+                    Key::CtrlC,
+                ]
+            );
+        }
+
+        #[test]
+        fn decode_escape_sequences() {
+            assert_eq!(
+                decode_keys(b"\x1b[A\x1b[B\x1bOH\x1b[4~\x1b[5~\x1b[6~"),
+                [
+                    Key::Up,
+                    Key::Down,
+                    Key::Home,
+                    Key::End,
+                    Key::PageUp,
+                    Key::PageDown,
+                ]
+            );
+            // unknown sequences are swallowed whole rather than read as typed characters
+            assert_eq!(decode_keys(b"\x1b[1;5Cx"), [Key::Other, Key::Char('x')]);
+        }
+
+        #[test]
+        fn decode_lone_escape() {
+            assert_eq!(decode_keys(b"\x1b"), [Key::Escape]);
+            assert_eq!(decode_keys(b"\x1bq"), [Key::Escape, Key::Char('q')]);
+            // a sequence cut short by the end of the read
+            assert_eq!(decode_keys(b"\x1b[1"), [Key::Other]);
+        }
+
+        #[test]
+        fn printable_drops_control_characters() {
+            assert_eq!(printable("sh -c \x1b[2Jecho\thi\n"), "sh -c [2Jechohi");
+            assert_eq!(printable("web"), "web");
+        }
+    }
+}
@@ -0,0 +1,196 @@
+use anyhow::{bail, Context, Result};
+use std::time::Duration;
+
+/// the longest timeout a process may be given, far beyond any process' lifetime
+/// but short enough that a deadline that far off can always be represented
+pub const MAX_TIMEOUT: Duration = Duration::from_secs(100 * 365 * 24 * 60 * 60);
+
+/// parses a human-readable duration such as `90s`, `5m`, `2h` or `1d`
+///
+/// a bare number is interpreted as seconds
+/// # Arguments
+/// * `s` - the string to parse
+pub fn parse_duration(s: &str) -> Result<Duration> {
+    let s = s.trim();
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (value, unit) = s.split_at(split);
+    let value: u64 = value
+        .parse()
+        .with_context(|| format!("invalid duration '{}'", s))?;
+
+    let multiplier = match unit {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 60 * 60 * 24,
+        "ms" => return Ok(Duration::from_millis(value)),
+        _ => bail!("invalid duration unit '{}' in '{}'", unit, s),
+    };
+    let seconds = value
+        .checked_mul(multiplier)
+        .with_context(|| format!("duration '{}' is too long", s))?;
+
+    Ok(Duration::from_secs(seconds))
+}
+
+/// parses a timeout the way `parse_duration` does, refusing timeouts longer than MAX_TIMEOUT
+/// # Arguments
+/// * `s` - the string to parse
+pub fn parse_timeout(s: &str) -> Result<Duration> {
+    let timeout = parse_duration(s)?;
+    if timeout > MAX_TIMEOUT {
+        bail!("timeout '{}' is too long, the longest is 36500d", s.trim());
+    }
+    Ok(timeout)
+}
+
+/// formats a duration the same way `parse_duration` reads it
+pub fn format_duration(d: Duration) -> String {
+    let secs = d.as_secs();
+    if d.subsec_millis() != 0 || secs == 0 {
+        format!("{}ms", d.as_millis())
+    } else if secs.is_multiple_of(60 * 60 * 24) {
+        format!("{}d", secs / (60 * 60 * 24))
+    } else if secs.is_multiple_of(60 * 60) {
+        format!("{}h", secs / (60 * 60))
+    } else if secs.is_multiple_of(60) {
+        format!("{}m", secs / 60)
+    } else {
+        format!("{}s", secs)
+    }
+}
+
+/// parses a signal given by number or by name, with or without the `SIG` prefix
+/// # Arguments
+/// * `s` - the string to parse, e.g. `15`, `TERM` or `SIGTERM`
+pub fn parse_signal(s: &str) -> Result<i32> {
+    if let Ok(number) = s.parse() {
+        return Ok(number);
+    }
+
+    let name = s.to_ascii_uppercase();
+    signal_by_name(name.strip_prefix("SIG").unwrap_or(&name))
+        .with_context(|| format!("unknown signal '{}'", s))
+}
+
+#[cfg(unix)]
+fn signal_by_name(name: &str) -> Option<i32> {
+    Some(match name {
+        "HUP" => libc::SIGHUP,
+        "INT" => libc::SIGINT,
+        "QUIT" => libc::SIGQUIT,
+        "KILL" => libc::SIGKILL,
+        "USR1" => libc::SIGUSR1,
+        "USR2" => libc::SIGUSR2,
+        "TERM" => libc::SIGTERM,
+        "CONT" => libc::SIGCONT,
+        "STOP" => libc::SIGSTOP,
+        "WINCH" => libc::SIGWINCH,
+        _ => return None,
+    })
+}
+
+#[cfg(not(unix))]
+fn signal_by_name(_name: &str) -> Option<i32> {
+    None
+}
+
+/// a fresh directory for a test, removed again when it is dropped
+#[cfg(test)]
+pub struct TempDir(std::path::PathBuf);
+
+#[cfg(test)]
+impl TempDir {
+    /// # Arguments
+    /// * `name` - unique among the tests, since they run in parallel
+    pub fn new(name: &str) -> TempDir {
+        let dir = std::env::temp_dir().join(format!("sibyl-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        TempDir(dir)
+    }
+
+    pub fn path(&self) -> &std::path::Path {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_duration_units() {
+        assert_eq!(parse_duration("90").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse_duration("90s").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse_duration("5m").unwrap(), Duration::from_secs(5 * 60));
+        assert_eq!(
+            parse_duration("2h").unwrap(),
+            Duration::from_secs(2 * 60 * 60)
+        );
+        assert_eq!(
+            parse_duration("1d").unwrap(),
+            Duration::from_secs(24 * 60 * 60)
+        );
+        assert_eq!(parse_duration("250ms").unwrap(), Duration::from_millis(250));
+        assert_eq!(parse_duration(" 3s ").unwrap(), Duration::from_secs(3));
+        assert_eq!(parse_duration("0").unwrap(), Duration::ZERO);
+    }
+
+    #[test]
+    fn parse_duration_rejects_garbage() {
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("s").is_err());
+        assert!(parse_duration("-5s").is_err());
+        assert!(parse_duration("1.5h").is_err());
+        assert!(parse_duration("5 m").is_err());
+        assert!(parse_duration("5w").is_err());
+    }
+
+    #[test]
+    fn parse_duration_rejects_overflow() {
+        assert!(parse_duration(&format!("{}d", u64::MAX / 1000)).is_err());
+        assert!(parse_duration("99999999999999999999s").is_err());
+    }
+
+    #[test]
+    fn parse_timeout_rejects_huge_timeouts() {
+        assert_eq!(parse_timeout("36500d").unwrap(), MAX_TIMEOUT);
+        assert!(parse_timeout("36501d").is_err());
+        assert!(parse_timeout("18446744073709551615s").is_err());
+        assert!(parse_timeout("18446744073709551615ms").is_err());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn parse_signal_names_and_numbers() {
+        assert_eq!(parse_signal("15").unwrap(), libc::SIGTERM);
+        assert_eq!(parse_signal("TERM").unwrap(), libc::SIGTERM);
+        assert_eq!(parse_signal("SIGTERM").unwrap(), libc::SIGTERM);
+        assert_eq!(parse_signal("int").unwrap(), libc::SIGINT);
+        assert_eq!(parse_signal("sigint").unwrap(), libc::SIGINT);
+        assert_eq!(parse_signal("SigHup").unwrap(), libc::SIGHUP);
+    }
+
+    #[test]
+    fn parse_signal_rejects_unknown_names() {
+        assert!(parse_signal("").is_err());
+        assert!(parse_signal("SIG").is_err());
+        assert!(parse_signal("BOGUS").is_err());
+        assert!(parse_signal("SIGSIGTERM").is_err());
+    }
+
+    #[test]
+    fn format_duration_round_trips() {
+        for s in ["90s", "5m", "2h", "1d", "250ms", "0ms"] {
+            assert_eq!(format_duration(parse_duration(s).unwrap()), s);
+        }
+    }
+}
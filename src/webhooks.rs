@@ -0,0 +1,357 @@
+use crate::config::WebhookConfig;
+use crate::events::Event;
+use chrono::{DateTime, Utc};
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// the longest a delivery waits between two attempts
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+/// how long a single attempt may take before it counts as failed
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// what a webhook can be alerted about
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum AlertKind {
+    /// a process exited unsuccessfully without having been stopped
+    Failed,
+    /// a definition's processes keep failing soon after they are started
+    CrashLoop,
+    /// a running process' healthcheck started failing
+    Unhealthy,
+}
+
+impl AlertKind {
+    /// every kind of alert, which webhooks are sent unless they say otherwise
+    pub fn all() -> Vec<AlertKind> {
+        vec![
+            AlertKind::Failed,
+            AlertKind::CrashLoop,
+            AlertKind::Unhealthy,
+        ]
+    }
+}
+
+impl fmt::Display for AlertKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self {
+            AlertKind::Failed => write!(f, "failed"),
+            AlertKind::CrashLoop => write!(f, "crash_loop"),
+            AlertKind::Unhealthy => write!(f, "unhealthy"),
+        }
+    }
+}
+
+/// the JSON body POSTed to a webhook
+///
+/// alerts of the same kind about the same process that arrive within the webhook's
+/// `group_window` are sent together, so a flapping process sends one request, not dozens
+#[derive(Serialize, Debug)]
+struct Payload {
+    alert: AlertKind,
+    process: String,
+    /// how many events were grouped into this request
+    count: usize,
+    first: DateTime<Utc>,
+    last: DateTime<Utc>,
+    events: Vec<Event>,
+}
+
+/// an alert on its way to one webhook
+struct Alert {
+    webhook: WebhookConfig,
+    kind: AlertKind,
+    process: String,
+    event: Event,
+}
+
+/// alerts collected for one webhook, kind and process until the group window closes
+struct Group {
+    webhook: WebhookConfig,
+    events: Vec<Event>,
+    closes: Instant,
+}
+
+/// sends alerts to the configured webhooks from a thread of its own,
+/// so that slow or unreachable endpoints never hold up the daemon
+#[derive(Default)]
+pub struct Notifier {
+    sender: Option<Sender<Alert>>,
+}
+
+impl Notifier {
+    /// queues an alert for every webhook that wants alerts of its kind
+    /// # Arguments
+    /// * `webhooks` - the configured webhooks
+    /// * `kind` - what the alert is about
+    /// * `event` - the event that raised it
+    pub fn notify(&mut self, webhooks: &[WebhookConfig], kind: AlertKind, event: &Event) {
+        let process = event.kind.process_name().unwrap_or_default().to_string();
+        for webhook in webhooks.iter().filter(|w| w.alerts.contains(&kind)) {
+            let alert = Alert {
+                webhook: webhook.clone(),
+                kind,
+                process: process.clone(),
+                event: event.clone(),
+            };
+            if self.sender().send(alert).is_err() {
+                error!("the webhook thread has gone away, dropping the alert");
+                self.sender = None;
+            }
+        }
+    }
+
+    /// the channel to the webhook thread, which is started the first time it's needed
+    fn sender(&mut self) -> &Sender<Alert> {
+        self.sender.get_or_insert_with(|| {
+            let (sender, receiver) = mpsc::channel();
+            thread::spawn(move || group_alerts(receiver));
+            sender
+        })
+    }
+}
+
+/// collects the alerts sent through `receiver` into groups, and delivers each group
+/// once its window closes
+fn group_alerts(receiver: Receiver<Alert>) {
+    let mut groups: HashMap<(String, AlertKind, String), Group> = HashMap::new();
+    loop {
+        let wait = groups
+            .values()
+            .map(|group| group.closes.saturating_duration_since(Instant::now()))
+            .min()
+            .unwrap_or(Duration::from_secs(3600));
+        match receiver.recv_timeout(wait) {
+            Ok(alert) => {
+                let Alert {
+                    webhook,
+                    kind,
+                    process,
+                    event,
+                } = alert;
+                let closes = Instant::now() + webhook.group_window_duration;
+                groups
+                    .entry((webhook.url.clone(), kind, process))
+                    .or_insert_with(|| Group {
+                        webhook,
+                        events: Vec::new(),
+                        closes,
+                    })
+                    .events
+                    .push(event);
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return,
+        }
+
+        let now = Instant::now();
+        let closed: Vec<_> = groups
+            .iter()
+            .filter(|(_, group)| group.closes <= now)
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in closed {
+            let Group {
+                webhook, events, ..
+            } = groups.remove(&key).unwrap();
+            let (_, kind, process) = key;
+            let payload = Payload {
+                alert: kind,
+                process,
+                count: events.len(),
+                first: events[0].time,
+                last: events[events.len() - 1].time,
+                events,
+            };
+            // retries sleep, so each delivery gets its own thread
+            thread::spawn(move || deliver(&webhook, &payload));
+        }
+    }
+}
+
+/// POSTs a payload to a webhook, retrying with exponential backoff
+///
+/// client errors other than 429 are not retried, since sending the same body again won't help
+fn deliver(webhook: &WebhookConfig, payload: &Payload) {
+    let body = match serde_json::to_string(payload) {
+        Ok(body) => body,
+        Err(e) => {
+            error!("failed to encode webhook payload: {}", e);
+            return;
+        }
+    };
+    let what = format!(
+        "{} alert about '{}' to {}",
+        payload.alert, payload.process, webhook.url
+    );
+
+    let mut backoff = webhook.backoff_duration;
+    for attempt in 1..=webhook.attempts {
+        let result = ureq::post(&webhook.url)
+            .timeout(REQUEST_TIMEOUT)
+            .set("Content-Type", "application/json")
+            .send_string(&body);
+        let retry = match result {
+            Ok(_) => {
+                info!("sent {}", what);
+                return;
+            }
+            Err(ureq::Error::Status(status, _)) => {
+                warn!("failed to send {}: status {}", what, status);
+                status == 429 || status >= 500
+            }
+            Err(e) => {
+                warn!("failed to send {}: {}", what, e);
+                true
+            }
+        };
+        if !retry || attempt == webhook.attempts {
+            break;
+        }
+        thread::sleep(backoff);
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+    error!("gave up sending {}", what);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::EventKind;
+    use serde_json::Value;
+
+    /// a webhook endpoint that answers requests with the given statuses in turn and
+    /// then goes away, passing on when each request arrived and its body
+    fn stand_in(statuses: Vec<u16>) -> (String, Receiver<(Instant, Value)>) {
+        let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", server.server_addr().to_ip().unwrap());
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            for status in statuses {
+                let mut request = server.recv().unwrap();
+                let arrived = Instant::now();
+                let mut body = String::new();
+                request.as_reader().read_to_string(&mut body).unwrap();
+                let _ = sender.send((arrived, serde_json::from_str(&body).unwrap()));
+                let response = tiny_http::Response::empty(status);
+                request.respond(response).unwrap();
+            }
+        });
+        (url, receiver)
+    }
+
+    fn webhook(url: &str, attempts: u32, backoff_ms: u64) -> WebhookConfig {
+        WebhookConfig {
+            url: url.to_string(),
+            alerts: AlertKind::all(),
+            group_window: String::from("200ms"),
+            attempts,
+            backoff: format!("{}ms", backoff_ms),
+            group_window_duration: Duration::from_millis(200),
+            backoff_duration: Duration::from_millis(backoff_ms),
+        }
+    }
+
+    fn failed(name: &str) -> Event {
+        Event::new(EventKind::Exited {
+            spid: 1,
+            name: name.to_string(),
+            code: Some(1),
+            signal: None,
+            timed_out: false,
+            stopped: false,
+        })
+    }
+
+    fn payload(name: &str) -> Payload {
+        let event = failed(name);
+        Payload {
+            alert: AlertKind::Failed,
+            process: name.to_string(),
+            count: 1,
+            first: event.time,
+            last: event.time,
+            events: vec![event],
+        }
+    }
+
+    #[test]
+    fn alerts_within_the_window_are_grouped() {
+        let (url, requests) = stand_in(vec![200, 200]);
+        let webhooks = [webhook(&url, 1, 1)];
+        let mut notifier = Notifier::default();
+        for name in ["web", "web", "db", "web"] {
+            notifier.notify(&webhooks, AlertKind::Failed, &failed(name));
+        }
+
+        let mut counts = HashMap::new();
+        for _ in 0..2 {
+            let (_, body) = requests.recv_timeout(Duration::from_secs(5)).unwrap();
+            assert_eq!(body["alert"], "failed");
+            assert_eq!(
+                body["count"].as_u64().unwrap() as usize,
+                body["events"].as_array().unwrap().len()
+            );
+            counts.insert(
+                body["process"].as_str().unwrap().to_string(),
+                body["count"].clone(),
+            );
+        }
+        assert_eq!(counts["web"], 3);
+        assert_eq!(counts["db"], 1);
+    }
+
+    #[test]
+    fn alerts_are_only_sent_to_webhooks_that_want_them() {
+        let (url, requests) = stand_in(vec![200]);
+        let mut unhealthy = webhook(&url, 1, 1);
+        unhealthy.alerts = vec![AlertKind::Unhealthy];
+        let mut notifier = Notifier::default();
+        notifier.notify(&[unhealthy], AlertKind::Failed, &failed("web"));
+        assert!(requests.recv_timeout(Duration::from_millis(500)).is_err());
+    }
+
+    #[test]
+    fn server_errors_and_rate_limits_are_retried() {
+        let (url, requests) = stand_in(vec![500, 429, 503, 200]);
+        deliver(&webhook(&url, 5, 1), &payload("web"));
+        assert_eq!(requests.try_iter().count(), 4);
+    }
+
+    #[test]
+    fn client_errors_are_not_retried() {
+        let (url, requests) = stand_in(vec![404, 200]);
+        deliver(&webhook(&url, 5, 1), &payload("web"));
+        assert_eq!(requests.try_iter().count(), 1);
+    }
+
+    #[test]
+    fn delivery_gives_up_after_its_attempts() {
+        let (url, requests) = stand_in(vec![500, 500, 500, 200]);
+        deliver(&webhook(&url, 3, 1), &payload("web"));
+        assert_eq!(requests.try_iter().count(), 3);
+    }
+
+    #[test]
+    fn backoff_doubles_after_every_attempt() {
+        let (url, requests) = stand_in(vec![500, 500, 500, 200]);
+        deliver(&webhook(&url, 4, 50), &payload("web"));
+
+        let arrived: Vec<Instant> = requests.try_iter().map(|(arrived, _)| arrived).collect();
+        assert_eq!(arrived.len(), 4);
+        for (i, pair) in arrived.windows(2).enumerate() {
+            let expected = Duration::from_millis(50 << i);
+            assert!(
+                pair[1] - pair[0] >= expected,
+                "retry {} came too early",
+                i + 1
+            );
+        }
+    }
+}